@@ -0,0 +1,12 @@
+#!/bin/sh
+# Fetches the IP-to-ASN database that build.rs embeds in the app: iptoasn.com's
+# ip2asn-combined.tsv.gz (IPv4 and IPv6 ranges with ASN, country and AS name),
+# which is in the public domain (PDDL v1.0).
+set -eu
+
+cd "$(dirname "$0")/../src-tauri"
+mkdir -p data
+curl -fsSL -o data/ip2asn-combined.tsv.gz.tmp https://iptoasn.com/data/ip2asn-combined.tsv.gz
+gzip -t data/ip2asn-combined.tsv.gz.tmp
+mv data/ip2asn-combined.tsv.gz.tmp data/ip2asn-combined.tsv.gz
+echo "Saved src-tauri/data/ip2asn-combined.tsv.gz"
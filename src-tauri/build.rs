@@ -1,3 +1,20 @@
+use std::path::Path;
+
 fn main() {
+    bundle_ip_database();
     tauri_build::build()
 }
+
+// Embeds data/ip2asn-combined.tsv.gz, fetched by scripts/update-ip-database.sh.
+// Without it an empty file is embedded, so a fresh checkout still builds.
+fn bundle_ip_database() {
+    let source = Path::new("data/ip2asn-combined.tsv.gz");
+    let target = Path::new(&std::env::var("OUT_DIR").unwrap()).join("ip2asn-combined.tsv.gz");
+    println!("cargo:rerun-if-changed={}", source.display());
+    if source.exists() {
+        std::fs::copy(source, &target).expect("copy the IP database");
+    } else {
+        println!("cargo:warning=No IP database in {}, addresses are only tagged from the CDN ranges", source.display());
+        std::fs::write(&target, []).expect("write an empty IP database");
+    }
+}
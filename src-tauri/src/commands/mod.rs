@@ -1,13 +1,35 @@
-use crate::dns::{test_download_speed_with_dns, test_single_dns_server, DNS_SERVERS};
+use crate::dns::{
+    self, apply_dnssec_check, apply_poisoning_check, best_download_server, ensure_https_url, load_block_pages,
+    load_cdn_networks, load_dns_providers, parse_client_subnet, pre_resolve_download_host,
+    batch_domains, preset_names, probe_resolver, rank_dns_servers, rewrite_baseline,
+    test_download_speed_with_dns, test_single_dns_server, trusted_baseline, trusted_signatures,
+    DnsMatrixCell, DnsProvider, DnsProviderResult, DnsRecommendation, DownloadProviderResult,
+    DownloadProgress, DownloadSpeedResult, DownloadTestOptions, LookupStrategy, PreResolved, ServerCoverage, TestResultSet,
+    MAX_SEGMENTS, TRUSTED_DNS_SERVER,
+};
+use crate::download::{download_to_file, file_name_from_url, ExpectedChecksum, FileDownload};
 use crate::docker::{
     docker_config_path, download_docker_config_file, read_docker_registries_file,
     test_docker_registry_download_speed, validate_docker_image_name, DOCKER_CONFIG_URL,
 };
+use crate::export::{ConfigFormat, DnsConfigExport, ExportedConfig};
+use crate::forwarder::{
+    rules_from_results, start_forwarder, ForwardRule, ForwarderHandle, ForwarderInfo,
+    DEFAULT_FORWARDER_PORT,
+};
+use crate::system::{system_dns_address, AppliedDns, DnsBackend, SystemDns};
+use crate::utils::dns_export_dir;
+use futures::stream::{FuturesUnordered, StreamExt};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use tauri::{AppHandle, Emitter};
 use tokio::task::JoinHandle;
 
+mod schedule;
+
+pub use schedule::DownloadSchedule;
+
 async fn spawn_with_cleanup<F, Fut>(
     task_key: String,
     task_fn: F,
@@ -33,12 +55,38 @@ where
     }
 }
 
+// The DNS providers to test, telling the frontend when the user's dns.yml was
+// left out because it couldn't be read
+async fn dns_providers(app_handle: &AppHandle) -> Vec<DnsProvider> {
+    let (providers, config_error) = load_dns_providers().await;
+    if let Some(error) = config_error {
+        if let Err(e) = app_handle.emit("dns-config-error", &error) {
+            eprintln!("Failed to emit DNS config error: {}", e);
+        }
+    }
+    providers
+}
+
 lazy_static::lazy_static! {
     static ref ACTIVE_TASKS: Arc<Mutex<HashMap<String, Vec<JoinHandle<()>>>>> = Arc::new(Mutex::new(HashMap::new()));
+    // Results of the latest DNS and download runs, for ranking
+    static ref LATEST_RESULTS: Mutex<TestResultSet> = Mutex::new(TestResultSet::default());
+    // Kept apart from ACTIVE_TASKS so aborting the tests leaves the forwarder running
+    static ref DNS_FORWARDER: Mutex<Option<ForwarderHandle>> = Mutex::new(None);
+    // File downloads by destination, also apart so aborting the tests leaves them running
+    static ref FILE_DOWNLOADS: Mutex<HashMap<PathBuf, JoinHandle<()>>> = Mutex::new(HashMap::new());
 }
 
 #[tauri::command]
-pub async fn test_dns_servers(domain: String, app_handle: AppHandle) -> Result<(), String> {
+pub async fn test_dns_servers(
+    domain: String,
+    detect_poisoning: Option<bool>,
+    trusted_dns_server: Option<String>,
+    lookup_strategy: Option<LookupStrategy>,
+    rounds: Option<u32>,
+    validate_dnssec: Option<bool>,
+    app_handle: AppHandle,
+) -> Result<(), String> {
     println!("Testing DNS servers for domain: {}", domain);
 
     {
@@ -54,25 +102,87 @@ pub async fn test_dns_servers(domain: String, app_handle: AppHandle) -> Result<(
         return Err("Please enter a valid domain name".to_string());
     }
 
-    let results_count = Arc::new(Mutex::new(1));
+    let host = ensure_https_url(&domain)
+        .and_then(|url| url.host_str().map(String::from))
+        .ok_or_else(|| "Please enter a valid domain name".to_string())?;
+    let trusted_dns_server = trusted_dns_server.unwrap_or_else(|| TRUSTED_DNS_SERVER.to_string());
 
-    for &dns_server in DNS_SERVERS {
+    // In hijack-detection mode every answer is compared against a trusted encrypted resolver
+    let poisoning_baseline = if detect_poisoning.unwrap_or(false) {
+        Some(Arc::new(trusted_baseline(&host, &trusted_dns_server).await))
+    } else {
+        None
+    };
+
+    // The trusted resolver also tells whether missing signatures were stripped
+    let dnssec_baseline = if validate_dnssec.unwrap_or(false) {
+        let trusted_signed = trusted_signatures(&host, &trusted_dns_server).await;
+        Some(Arc::new((host.clone(), trusted_signed)))
+    } else {
+        None
+    };
+
+    let lookup_strategy = lookup_strategy.unwrap_or_default();
+    let rounds = rounds.unwrap_or(1);
+    LATEST_RESULTS.lock().unwrap().dns_results.clear();
+    let block_pages = Arc::new(load_block_pages().await);
+    let providers = dns_providers(&app_handle).await;
+    let total_providers = providers.len();
+    let results_count = Arc::new(Mutex::new(0));
+
+    for provider in providers {
         let domain_clone = domain.clone();
-        let dns_server_string = dns_server.to_string();
+        let poisoning_baseline = poisoning_baseline.clone();
+        let dnssec_baseline = dnssec_baseline.clone();
+        let block_pages = Arc::clone(&block_pages);
+        let task_key = domain.clone() + "-" + &provider.name;
         let app_handle_clone = app_handle.clone();
         let results_count_clone = Arc::clone(&results_count);
-        let task_key = domain.clone() + "-" + dns_server;
 
         spawn_with_cleanup(task_key.clone(), move || async move {
-            let result = test_single_dns_server(domain_clone, dns_server_string, 0).await;
+            // A provider's servers are tested together, each result emitted as soon as it's in
+            let mut tests: FuturesUnordered<_> = provider
+                .entries()
+                .into_iter()
+                .map(|dns_server| {
+                    let domain = domain_clone.clone();
+                    let poisoning_baseline = poisoning_baseline.clone();
+                    let dnssec_baseline = dnssec_baseline.clone();
+                    let block_pages = Arc::clone(&block_pages);
+                    async move {
+                        let mut result =
+                            test_single_dns_server(domain, dns_server, lookup_strategy, rounds, &block_pages, 0).await;
+                        if let Some(trusted_ips) = poisoning_baseline {
+                            apply_poisoning_check(&mut result, &trusted_ips);
+                        }
+                        if let Some(baseline) = dnssec_baseline {
+                            let (host, trusted_signed) = baseline.as_ref();
+                            apply_dnssec_check(&mut result, host, *trusted_signed).await;
+                        }
+                        result
+                    }
+                })
+                .collect();
+
+            let mut results = Vec::new();
+            while let Some(mut result) = tests.next().await {
+                result.provider = Some(provider.name.clone());
+                if let Err(e) = app_handle_clone.emit("dns-test-result", &result) {
+                    eprintln!("Failed to emit DNS test result: {}", e);
+                }
+                LATEST_RESULTS.lock().unwrap().dns_results.push(result.clone());
+                results.push(result);
+            }
 
-            if let Err(e) = app_handle_clone.emit("dns-test-result", &result) {
-                eprintln!("Failed to emit DNS test result: {}", e);
+            let provider_result = DnsProviderResult::new(&provider, results);
+            if let Err(e) = app_handle_clone.emit("dns-provider-result", &provider_result) {
+                eprintln!("Failed to emit DNS provider result: {}", e);
             }
+
             let mut result_count = results_count_clone.lock().unwrap();
             *result_count += 1;
 
-            if *result_count == DNS_SERVERS.len() {
+            if *result_count == total_providers {
                 if let Err(e) = app_handle_clone.emit("dns-test-complete", ()) {
                     eprintln!("Failed to emit completion event: {}", e);
                 }
@@ -82,10 +192,311 @@ pub async fn test_dns_servers(domain: String, app_handle: AppHandle) -> Result<(
     Ok(())
 }
 
+#[tauri::command]
+pub fn list_domain_presets() -> Vec<&'static str> {
+    preset_names()
+}
+
+// Tests a set of domains against every DNS server, either given as a list or by preset name
+#[tauri::command]
+pub async fn test_dns_servers_batch(
+    domains: Option<Vec<String>>,
+    preset: Option<String>,
+    lookup_strategy: Option<LookupStrategy>,
+    app_handle: AppHandle,
+) -> Result<(), String> {
+    {
+        let result = abort_all_tasks().await;
+        if let Err(e) = result {
+            eprintln!("Failed to abort all tasks: {}", e);
+        }
+    }
+
+    let domains = Arc::new(batch_domains(domains.unwrap_or_default(), preset.as_deref())?);
+
+    println!("Batch testing DNS servers for {} domains", domains.len());
+    LATEST_RESULTS.lock().unwrap().dns_results.clear();
+
+    let lookup_strategy = lookup_strategy.unwrap_or_default();
+    let block_pages = Arc::new(load_block_pages().await);
+    let providers = dns_providers(&app_handle).await;
+    let total_providers = providers.len();
+    let results_count = Arc::new(Mutex::new(0));
+
+    for provider in providers {
+        let domains = Arc::clone(&domains);
+        let block_pages = Arc::clone(&block_pages);
+        let task_key = "batch-".to_string() + &provider.name;
+        let app_handle_clone = app_handle.clone();
+        let results_count_clone = Arc::clone(&results_count);
+
+        spawn_with_cleanup(task_key, move || async move {
+            // Every server of the provider works through the domains at the same time
+            let mut servers: FuturesUnordered<_> = provider
+                .entries()
+                .into_iter()
+                .map(|dns_server| {
+                    let domains = Arc::clone(&domains);
+                    let block_pages = Arc::clone(&block_pages);
+                    let provider_name = provider.name.clone();
+                    let app_handle = app_handle_clone.clone();
+                    async move {
+                        let mut tests: FuturesUnordered<_> = domains
+                            .iter()
+                            .map(|domain| {
+                                let dns_server = dns_server.clone();
+                                let block_pages = Arc::clone(&block_pages);
+                                async move {
+                                    let result = test_single_dns_server(
+                                        domain.clone(),
+                                        dns_server,
+                                        lookup_strategy,
+                                        1,
+                                        &block_pages,
+                                        0,
+                                    )
+                                    .await;
+                                    (domain.clone(), result)
+                                }
+                            })
+                            .collect();
+
+                        let mut cells = Vec::new();
+                        while let Some((domain, mut result)) = tests.next().await {
+                            result.provider = Some(provider_name.clone());
+                            LATEST_RESULTS.lock().unwrap().dns_results.push(result.clone());
+                            let cell = DnsMatrixCell {
+                                dns_server: dns_server.clone(),
+                                provider: Some(provider_name.clone()),
+                                domain,
+                                reachable: result.status,
+                                result,
+                            };
+                            if let Err(e) = app_handle.emit("dns-matrix-cell", &cell) {
+                                eprintln!("Failed to emit DNS matrix cell: {}", e);
+                            }
+                            cells.push(cell);
+                        }
+
+                        let coverage = ServerCoverage::new(&dns_server, Some(provider_name), &cells);
+                        if let Err(e) = app_handle.emit("dns-server-coverage", &coverage) {
+                            eprintln!("Failed to emit DNS server coverage: {}", e);
+                        }
+                    }
+                })
+                .collect();
+            while servers.next().await.is_some() {}
+
+            let mut result_count = results_count_clone.lock().unwrap();
+            *result_count += 1;
+
+            if *result_count == total_providers {
+                if let Err(e) = app_handle_clone.emit("dns-batch-complete", ()) {
+                    eprintln!("Failed to emit completion event: {}", e);
+                }
+            }
+        }).await;
+    }
+    Ok(())
+}
+
+// Characterizes each server (TCP fallback, EDNS, AAAA, NXDOMAIN hijacking, open
+// recursion, rewritten answers), by default every server of every provider
+#[tauri::command]
+pub async fn probe_dns_servers(
+    dns_servers: Option<Vec<String>>,
+    trusted_dns_server: Option<String>,
+    app_handle: AppHandle,
+) -> Result<(), String> {
+    {
+        let result = abort_all_tasks().await;
+        if let Err(e) = result {
+            eprintln!("Failed to abort all tasks: {}", e);
+        }
+    }
+
+    // (server, provider) pairs
+    let servers: Vec<(String, Option<String>)> = match dns_servers {
+        Some(servers) => servers
+            .into_iter()
+            .map(|server| server.trim().to_string())
+            .filter(|server| !server.is_empty())
+            .map(|server| (server, None))
+            .collect(),
+        None => dns_providers(&app_handle)
+            .await
+            .iter()
+            .flat_map(|provider| {
+                provider
+                    .entries()
+                    .into_iter()
+                    .map(|server| (server, Some(provider.name.clone())))
+            })
+            .collect(),
+    };
+    if servers.is_empty() {
+        return Err("Please enter at least one DNS server".to_string());
+    }
+
+    let trusted_dns_server = trusted_dns_server.unwrap_or_else(|| TRUSTED_DNS_SERVER.to_string());
+    println!("Probing the behaviour of {} DNS servers", servers.len());
+
+    spawn_with_cleanup("dns-behaviour".to_string(), move || async move {
+        let baseline = rewrite_baseline(&trusted_dns_server).await;
+        let cdn_networks = load_cdn_networks().await;
+
+        let mut probes: FuturesUnordered<_> = servers
+            .into_iter()
+            .map(|(dns_server, provider)| {
+                let baseline = &baseline;
+                let cdn_networks = &cdn_networks;
+                async move {
+                    let mut report = probe_resolver(&dns_server, baseline, cdn_networks).await;
+                    report.provider = provider;
+                    report
+                }
+            })
+            .collect();
+        while let Some(report) = probes.next().await {
+            if let Err(e) = app_handle.emit("dns-behaviour-result", &report) {
+                eprintln!("Failed to emit DNS behaviour result: {}", e);
+            }
+        }
+
+        if let Err(e) = app_handle.emit("dns-behaviour-complete", ()) {
+            eprintln!("Failed to emit completion event: {}", e);
+        }
+    })
+    .await;
+
+    Ok(())
+}
+
+// Ranks the servers from the latest DNS, batch and download tests and recommends a pair to use
+#[tauri::command]
+pub fn recommend_dns_servers() -> Result<DnsRecommendation, String> {
+    let results = LATEST_RESULTS.lock().unwrap().clone();
+    if results.dns_results.is_empty() && results.download_results.is_empty() {
+        return Err("Run a DNS or download test first".to_string());
+    }
+    Ok(rank_dns_servers(&results))
+}
+
+// Writes dnsmasq, CoreDNS, SmartDNS and hosts files routing each tested domain to
+// its best server, by default into the `export` folder of the config directory
+#[tauri::command]
+pub async fn export_dns_config(
+    formats: Option<Vec<ConfigFormat>>,
+    directory: Option<String>,
+    default_server: Option<String>,
+) -> Result<Vec<ExportedConfig>, String> {
+    let latest = LATEST_RESULTS.lock().unwrap().clone();
+    if latest.dns_results.is_empty() {
+        return Err("Run a DNS test first".to_string());
+    }
+    let default_server = default_server.or_else(|| rank_dns_servers(&latest).primary);
+    let export = DnsConfigExport::from_results(&latest.dns_results, default_server);
+    if export.rules.is_empty() {
+        return Err("No tested domain was reachable through any DNS server".to_string());
+    }
+
+    let directory = directory.map(PathBuf::from).unwrap_or_else(dns_export_dir);
+    let formats = formats.unwrap_or_else(|| ConfigFormat::ALL.to_vec());
+    export
+        .write(&directory, &formats)
+        .await
+        .map_err(|e| format!("Failed to export DNS config: {:#}", e))
+}
+
+#[tauri::command]
+pub async fn detect_system_dns_backend() -> Result<DnsBackend, String> {
+    SystemDns::default().detect_backend().await.map_err(|e| e.to_string())
+}
+
+// Points the system at the chosen servers; `backend` overrides the detected one
+#[tauri::command]
+pub async fn apply_system_dns(
+    primary: String,
+    secondary: Option<String>,
+    backend: Option<DnsBackend>,
+) -> Result<AppliedDns, String> {
+    let servers = std::iter::once(&primary)
+        .chain(secondary.as_ref())
+        .map(|entry| system_dns_address(entry))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+
+    let system_dns = SystemDns::default();
+    let backend = match backend {
+        Some(backend) => backend,
+        None => system_dns.detect_backend().await.map_err(|e| e.to_string())?,
+    };
+    system_dns
+        .apply(backend, &servers)
+        .await
+        .map_err(|e| format!("Failed to apply DNS settings: {:#}", e))
+}
+
+#[tauri::command]
+pub async fn revert_system_dns() -> Result<DnsBackend, String> {
+    SystemDns::default()
+        .revert()
+        .await
+        .map_err(|e| format!("Failed to revert DNS settings: {:#}", e))
+}
+
+// Starts the local split-DNS forwarder, replacing a running one. Without explicit
+// rules they are derived from the latest DNS tests, and without a default server
+// the recommended primary is used.
+#[tauri::command]
+pub async fn start_dns_forwarder(
+    port: Option<u16>,
+    rules: Option<Vec<ForwardRule>>,
+    default_server: Option<String>,
+    app_handle: AppHandle,
+) -> Result<ForwarderInfo, String> {
+    let latest = LATEST_RESULTS.lock().unwrap().clone();
+    let rules = rules.unwrap_or_else(|| rules_from_results(&latest.dns_results));
+    let default_server = match default_server.or_else(|| rank_dns_servers(&latest).primary) {
+        Some(server) => server,
+        None => return Err("No default DNS server given and no tested server to use, run a DNS test first".to_string()),
+    };
+
+    // Free the port before binding it again
+    DNS_FORWARDER.lock().unwrap().take();
+
+    let handle = start_forwarder(port.unwrap_or(DEFAULT_FORWARDER_PORT), default_server, rules, move |entry| {
+        let _ = app_handle.emit("dns-forwarder-query", entry);
+    })
+    .await
+    .map_err(|e| format!("Failed to start DNS forwarder: {:#}", e))?;
+
+    let info = handle.info.clone();
+    *DNS_FORWARDER.lock().unwrap() = Some(handle);
+    Ok(info)
+}
+
+#[tauri::command]
+pub fn stop_dns_forwarder() -> Result<(), String> {
+    match DNS_FORWARDER.lock().unwrap().take() {
+        Some(_) => Ok(()),
+        None => Err("DNS forwarder is not running".to_string()),
+    }
+}
+
+#[tauri::command]
+pub fn dns_forwarder_status() -> Option<ForwarderInfo> {
+    DNS_FORWARDER.lock().unwrap().as_ref().map(|handle| handle.info.clone())
+}
+
 #[tauri::command]
 pub async fn test_download_speed_all_dns(
     url: String,
     timeout_seconds: u64,
+    lookup_strategy: Option<LookupStrategy>,
+    compare_ecs: Option<bool>,
+    ecs_subnet: Option<String>,
+    schedule: Option<DownloadSchedule>,
     app_handle: AppHandle,
 ) -> Result<(), String> {
     {
@@ -105,39 +516,123 @@ pub async fn test_download_speed_all_dns(
         return Err("URL must start with http:// or https://".to_string());
     }
 
-    let url_for_storage = url.clone();
-
-    spawn_with_cleanup(url_for_storage.clone(), move || async move {
-        println!("Starting download tests for URL: {}", url);
-
-        for (index, &dns_server) in DNS_SERVERS.iter().enumerate() {
-            println!(
-                "Testing DNS server {} ({}/{}): {}",
-                dns_server,
-                index + 1,
-                DNS_SERVERS.len(),
-                dns_server
-            );
-
-            let url_clone = url.clone();
-            let dns_server_string = dns_server.to_string();
-            let app_handle_clone = app_handle.clone();
+    // Only with compare_ecs: each resolver is asked again with this client subnet
+    let ecs_subnet = match compare_ecs.unwrap_or(false) {
+        true => {
+            let subnet = match ecs_subnet.filter(|subnet| !subnet.trim().is_empty()) {
+                Some(subnet) => subnet,
+                None => dns::public_client_subnet().await.map_err(|e| {
+                    format!("Could not find your public address for the client subnet, enter one instead: {:#}", e)
+                })?,
+            };
+            parse_client_subnet(&subnet).map_err(|e| e.to_string())?;
+            Some(subnet)
+        }
+        false => None,
+    };
 
-            let result =
-                test_download_speed_with_dns(url_clone, dns_server_string, timeout_seconds, 0)
-                    .await;
+    let url_for_storage = url.clone();
+    let lookup_strategy = lookup_strategy.unwrap_or_default();
+    let providers = dns_providers(&app_handle).await;
+    let schedule = schedule.unwrap_or_default();
+    let options = DownloadTestOptions {
+        lookup_strategy,
+        timeout_seconds,
+        cdn_networks: load_cdn_networks().await,
+        segments: schedule.segments.unwrap_or(1).clamp(1, MAX_SEGMENTS),
+    };
+    LATEST_RESULTS.lock().unwrap().download_results.clear();
 
-            println!(
-                "Download test result for {}: success={}, speed={:.3} Mbps",
-                result.dns_server, result.success, result.download_speed_mbps
-            );
+    let fair_mode = schedule.fair_mode;
+    let concurrency = schedule.concurrency();
 
-            if let Err(e) = app_handle_clone.emit("download-test-result", &result) {
-                eprintln!("Failed to emit download test result: {}", e);
-            } else {
-                println!("Successfully emitted result for {}", result.dns_server);
-            }
+    spawn_with_cleanup(url_for_storage.clone(), move || async move {
+        println!(
+            "Starting download tests for URL: {} ({} at a time{})",
+            url,
+            concurrency,
+            if fair_mode { ", fair mode" } else { "" }
+        );
+
+        // (provider index, server) in the order they are tested
+        let jobs: Vec<(usize, String)> = providers
+            .iter()
+            .enumerate()
+            .flat_map(|(index, provider)| provider.entries().into_iter().map(move |dns_server| (index, dns_server)))
+            .collect();
+
+        let mut remaining: Vec<usize> = vec![0; providers.len()];
+        for (index, _) in &jobs {
+            remaining[*index] += 1;
         }
+        let mut provider_results: Vec<Vec<DownloadSpeedResult>> = vec![vec![]; providers.len()];
+
+        let (url, providers, options, ecs_subnet) = (&url, &providers, &options, &ecs_subnet);
+        schedule
+            .run(
+                jobs,
+                |(_, dns_server): &(usize, String)| {
+                    let dns_server = dns_server.clone();
+                    async move { pre_resolve_download_host(url, &dns_server, lookup_strategy, timeout_seconds).await }
+                },
+                |(index, dns_server), pre_resolved: Option<PreResolved>| {
+                    let app_handle = app_handle.clone();
+                    async move {
+                        let mut result = test_download_speed_with_dns(
+                            url.clone(),
+                            dns_server,
+                            options,
+                            0,
+                            pre_resolved,
+                            &move |progress: &DownloadProgress| {
+                                if let Err(e) = app_handle.emit("download-progress", progress) {
+                                    eprintln!("Failed to emit download progress: {}", e);
+                                }
+                            },
+                        )
+                        .await;
+                        result.provider = Some(providers[index].name.clone());
+                        (index, result)
+                    }
+                },
+                // The ECS lookups run after the download has given up its slot
+                |(index, mut result): (usize, DownloadSpeedResult)| async move {
+                    if let Some(subnet) = ecs_subnet {
+                        let steering =
+                            dns::compare_ecs(url, &result.dns_server, subnet, lookup_strategy, &options.cdn_networks);
+                        match steering.await {
+                            Ok(steering) => result.steering = Some(steering),
+                            Err(e) => eprintln!("ECS comparison for {} failed: {:#}", result.dns_server, e),
+                        }
+                    }
+                    (index, result)
+                },
+                |(index, result)| {
+                    println!(
+                        "Download test result for {}: success={}, speed={:.3} Mbps",
+                        result.dns_server, result.success, result.download_speed_mbps
+                    );
+
+                    if let Err(e) = app_handle.emit("download-test-result", &result) {
+                        eprintln!("Failed to emit download test result: {}", e);
+                    } else {
+                        println!("Successfully emitted result for {}", result.dns_server);
+                    }
+                    LATEST_RESULTS.lock().unwrap().download_results.push(result.clone());
+                    provider_results[index].push(result);
+
+                    // A provider is reported once the last of its servers is done
+                    remaining[index] -= 1;
+                    if remaining[index] == 0 {
+                        let results = std::mem::take(&mut provider_results[index]);
+                        let provider_result = DownloadProviderResult::new(&providers[index], results);
+                        if let Err(e) = app_handle.emit("download-provider-result", &provider_result) {
+                            eprintln!("Failed to emit download provider result: {}", e);
+                        }
+                    }
+                },
+            )
+            .await;
 
         println!("All download tests completed");
         if let Err(e) = app_handle.emit("download-test-complete", ()) {
@@ -150,6 +645,98 @@ pub async fn test_download_speed_all_dns(
     Ok(())
 }
 
+// Saves `url` to `destination` through `dns_server`, or the fastest server of the
+// latest download test of that URL. An interrupted download is resumed when run
+// again with the same destination. Returns the file it saves to, which is what
+// cancel_file_download takes.
+#[tauri::command]
+pub async fn download_via_best_dns(
+    url: String,
+    destination: String,
+    dns_server: Option<String>,
+    checksum: Option<String>,
+    lookup_strategy: Option<LookupStrategy>,
+    app_handle: AppHandle,
+) -> Result<String, String> {
+    let url = url.trim().to_string();
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err("URL must start with http:// or https://".to_string());
+    }
+    if destination.trim().is_empty() {
+        return Err("Please choose where to save the file".to_string());
+    }
+    let checksum = match checksum.filter(|checksum| !checksum.trim().is_empty()) {
+        Some(checksum) => Some(ExpectedChecksum::parse(&checksum).map_err(|e| e.to_string())?),
+        None => None,
+    };
+
+    let dns_server = match dns_server {
+        Some(dns_server) => dns_server,
+        None => {
+            let latest = LATEST_RESULTS.lock().unwrap().clone();
+            best_download_server(&latest, &url).ok_or("Run a download or DNS test first, or choose a DNS server")?
+        }
+    };
+
+    // Saving into a folder keeps the file's own name
+    let mut destination = PathBuf::from(destination.trim());
+    if tokio::fs::metadata(&destination).await.is_ok_and(|metadata| metadata.is_dir()) {
+        destination.push(file_name_from_url(&url));
+    }
+    let download = FileDownload {
+        url: url.clone(),
+        destination,
+        dns_server,
+        lookup_strategy: lookup_strategy.unwrap_or_default(),
+        checksum,
+    };
+
+    // Two downloads into one partial file would corrupt it
+    let mut downloads = FILE_DOWNLOADS.lock().unwrap();
+    let key = download.destination.clone();
+    if downloads.contains_key(&key) {
+        return Err(format!("{} is already being downloaded", key.display()));
+    }
+    let handle = tokio::spawn(async move {
+        println!(
+            "Downloading {} to {} via {}",
+            download.url,
+            download.destination.display(),
+            download.dns_server
+        );
+        let progress_handle = app_handle.clone();
+        let result = download_to_file(&download, &move |progress: &DownloadProgress| {
+            if let Err(e) = progress_handle.emit("file-download-progress", progress) {
+                eprintln!("Failed to emit file download progress: {}", e);
+            }
+        })
+        .await;
+        FILE_DOWNLOADS.lock().unwrap().remove(&download.destination);
+        if let Err(e) = app_handle.emit("file-download-complete", &result) {
+            eprintln!("Failed to emit file download result: {}", e);
+        }
+    });
+    // Still locked, so the task can't finish and remove itself before this
+    downloads.insert(key.clone(), handle);
+
+    Ok(key.display().to_string())
+}
+
+// Stops a download started by download_via_best_dns; its partial file is kept
+// so starting it again resumes
+#[tauri::command]
+pub async fn cancel_file_download(destination: String) -> Result<(), String> {
+    let handle = FILE_DOWNLOADS.lock().unwrap().remove(&PathBuf::from(destination.trim()));
+    match handle {
+        Some(handle) => {
+            handle.abort();
+            println!("Cancelled the download to {}", destination.trim());
+            Ok(())
+        }
+        None => Err(format!("No download to {} is running", destination.trim())),
+    }
+}
+
 #[tauri::command]
 pub async fn test_docker_registries(
     image_name: String,
@@ -0,0 +1,198 @@
+use std::future::Future;
+use futures::future::join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+// More parallel downloads only measure how the link is split between them
+const MAX_DOWNLOAD_CONCURRENCY: usize = 16;
+
+/// How the download test runs its servers: `concurrency` at a time, or in fair
+/// mode one at a time after resolving the URL through all of them in parallel.
+/// `segments` splits each download across that many connections.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadSchedule {
+    pub concurrency: Option<usize>,
+    #[serde(default)]
+    pub fair_mode: bool,
+    pub segments: Option<usize>,
+}
+
+impl DownloadSchedule {
+    // Downloads running side by side share the link, so the default stays one at a
+    // time; fair mode always is
+    pub fn concurrency(&self) -> usize {
+        match self.fair_mode {
+            true => 1,
+            false => self.concurrency.unwrap_or(1).clamp(1, MAX_DOWNLOAD_CONCURRENCY),
+        }
+    }
+
+    /// Runs `measure` for every job, at most [`Self::concurrency`] at a time, then
+    /// `finish` on what it measured once the slot is free for the next job. In
+    /// fair mode every job is `pre_resolve`d first, all at the same time, so the
+    /// downloads that follow one by one measure nothing but the transfer.
+    /// Results are handed to `on_result` as they come in.
+    pub async fn run<J, P, T, U, RF, MF, FF>(
+        &self,
+        jobs: Vec<J>,
+        pre_resolve: impl Fn(&J) -> RF,
+        measure: impl Fn(J, Option<P>) -> MF,
+        finish: impl Fn(T) -> FF,
+        mut on_result: impl FnMut(U),
+    ) where
+        RF: Future<Output = P>,
+        MF: Future<Output = T>,
+        FF: Future<Output = U>,
+    {
+        let pre_resolved: Vec<Option<P>> = match self.fair_mode {
+            true => join_all(jobs.iter().map(&pre_resolve)).await.into_iter().map(Some).collect(),
+            false => jobs.iter().map(|_| None).collect(),
+        };
+
+        let semaphore = Semaphore::new(self.concurrency());
+        let mut tests: FuturesUnordered<_> = jobs
+            .into_iter()
+            .zip(pre_resolved)
+            .map(|(job, pre_resolved)| {
+                let (semaphore, measure, finish) = (&semaphore, &measure, &finish);
+                async move {
+                    let measured = {
+                        // Never closed, so acquiring only waits for a free slot
+                        let _permit = semaphore.acquire().await.ok();
+                        measure(job, pre_resolved).await
+                    };
+                    finish(measured).await
+                }
+            })
+            .collect();
+
+        while let Some(result) = tests.next().await {
+            on_result(result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn schedule(concurrency: Option<usize>, fair_mode: bool) -> DownloadSchedule {
+        DownloadSchedule {
+            concurrency,
+            fair_mode,
+            segments: None,
+        }
+    }
+
+    async fn pause() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    #[test]
+    fn concurrency_is_clamped_and_fair_mode_is_sequential() {
+        assert_eq!(schedule(None, false).concurrency(), 1);
+        assert_eq!(schedule(Some(0), false).concurrency(), 1);
+        assert_eq!(schedule(Some(4), false).concurrency(), 4);
+        assert_eq!(schedule(Some(100), false).concurrency(), MAX_DOWNLOAD_CONCURRENCY);
+        assert_eq!(schedule(Some(8), true).concurrency(), 1);
+    }
+
+    #[tokio::test]
+    async fn runs_at_most_concurrency_jobs_at_a_time() {
+        let running = Mutex::new((0, 0)); // (now, most at once)
+        let mut results = vec![];
+        schedule(Some(3), false)
+            .run(
+                (0..8).collect(),
+                |_: &usize| async { unreachable!("only fair mode resolves up front") },
+                |job, pre_resolved: Option<()>| {
+                    let running = &running;
+                    async move {
+                        assert!(pre_resolved.is_none());
+                        {
+                            let mut running = running.lock().unwrap();
+                            running.0 += 1;
+                            running.1 = running.1.max(running.0);
+                        }
+                        pause().await;
+                        running.lock().unwrap().0 -= 1;
+                        job
+                    }
+                },
+                |job| async move { job },
+                |job| results.push(job),
+            )
+            .await;
+
+        assert_eq!(running.into_inner().unwrap(), (0, 3));
+        results.sort();
+        assert_eq!(results, (0..8).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn fair_mode_resolves_everything_then_runs_one_by_one() {
+        let events = Mutex::new(vec![]);
+        let log = |event: String| events.lock().unwrap().push(event);
+        schedule(Some(4), true)
+            .run(
+                (0..3).collect(),
+                |job: &usize| {
+                    let job = *job;
+                    async move {
+                        log(format!("resolving {}", job));
+                        pause().await;
+                        log(format!("resolved {}", job));
+                        job * 10
+                    }
+                },
+                |job, pre_resolved| async move {
+                    assert_eq!(pre_resolved, Some(job * 10));
+                    log(format!("start {}", job));
+                    pause().await;
+                    log(format!("end {}", job));
+                },
+                |_| async {},
+                |_| {},
+            )
+            .await;
+
+        let events = events.into_inner().unwrap();
+        // Every lookup starts before any of them ends
+        assert!(events[..3].iter().all(|event| event.starts_with("resolving")), "{:?}", events);
+        assert!(events[3..6].iter().all(|event| event.starts_with("resolved")), "{:?}", events);
+        // and the downloads only start after the last one, never overlapping
+        let downloads: Vec<&str> = events[6..].iter().map(|event| event.split(' ').next().unwrap()).collect();
+        assert_eq!(downloads, ["start", "end", "start", "end", "start", "end"]);
+    }
+
+    #[tokio::test]
+    async fn finishing_does_not_hold_a_slot() {
+        let events = Mutex::new(vec![]);
+        let log = |event: String| events.lock().unwrap().push(event);
+        schedule(Some(1), false)
+            .run(
+                vec![0, 1],
+                |_: &usize| async {},
+                |job, _: Option<()>| async move {
+                    log(format!("measured {}", job));
+                    job
+                },
+                |job| async move {
+                    log(format!("finishing {}", job));
+                    pause().await;
+                    log(format!("finished {}", job));
+                },
+                |_| {},
+            )
+            .await;
+
+        let events = events.into_inner().unwrap();
+        let position = |event: &str| events.iter().position(|logged| logged == event).unwrap();
+        // The second download runs while the first one's ECS comparison is still going
+        assert!(position("measured 1") < position("finished 0"), "{:?}", events);
+    }
+}
@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+use super::DnsTestResult;
+
+// Named domain sets for batch tests
+const DOMAIN_PRESETS: &[(&str, &[&str])] = &[
+    (
+        "developer essentials",
+        &[
+            "github.com",
+            "registry.npmjs.org",
+            "pypi.org",
+            "golang.org",
+            "android.googlesource.com",
+        ],
+    ),
+    (
+        "containers",
+        &[
+            "registry-1.docker.io",
+            "auth.docker.io",
+            "production.cloudflare.docker.com",
+            "ghcr.io",
+            "quay.io",
+        ],
+    ),
+    (
+        "android",
+        &[
+            "dl.google.com",
+            "maven.google.com",
+            "android.googlesource.com",
+            "services.gradle.org",
+            "plugins.gradle.org",
+        ],
+    ),
+];
+
+// Looks a preset up by name, ignoring case and `-`/`_` in place of spaces
+fn preset_domains(name: &str) -> Option<Vec<String>> {
+    let name = name.trim().to_lowercase().replace(['-', '_'], " ");
+    DOMAIN_PRESETS
+        .iter()
+        .find(|(preset, _)| *preset == name)
+        .map(|(_, domains)| domains.iter().map(|domain| domain.to_string()).collect())
+}
+
+pub fn preset_names() -> Vec<&'static str> {
+    DOMAIN_PRESETS.iter().map(|(name, _)| *name).collect()
+}
+
+/// The domains a batch test covers: the given ones followed by the preset's,
+/// trimmed and without duplicates.
+pub fn batch_domains(domains: Vec<String>, preset: Option<&str>) -> Result<Vec<String>, String> {
+    let mut domains = domains;
+    if let Some(preset) = preset {
+        let preset_list = preset_domains(preset)
+            .ok_or_else(|| format!("Unknown domain preset: {}", preset))?;
+        domains.extend(preset_list);
+    }
+
+    let mut unique_domains: Vec<String> = Vec::new();
+    for domain in domains {
+        let domain = domain.trim().to_string();
+        if !domain.is_empty() && !unique_domains.contains(&domain) {
+            unique_domains.push(domain);
+        }
+    }
+
+    if unique_domains.is_empty() {
+        return Err("Please enter at least one domain or choose a preset".to_string());
+    }
+    Ok(unique_domains)
+}
+
+/// One server × domain result of a batch test.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DnsMatrixCell {
+    pub dns_server: String,
+    pub provider: Option<String>,
+    pub domain: String,
+    pub reachable: bool,
+    pub result: DnsTestResult,
+}
+
+/// How many of the batch's domains a server made reachable.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerCoverage {
+    pub dns_server: String,
+    pub provider: Option<String>,
+    pub reachable: usize,
+    pub total: usize,
+    /// Share of reachable domains, from 0 to 1
+    pub coverage: f64,
+    pub unreachable_domains: Vec<String>,
+}
+
+impl ServerCoverage {
+    pub fn new(dns_server: &str, provider: Option<String>, cells: &[DnsMatrixCell]) -> Self {
+        let reachable = cells.iter().filter(|cell| cell.reachable).count();
+        let total = cells.len();
+        Self {
+            dns_server: dns_server.to_string(),
+            provider,
+            reachable,
+            total,
+            coverage: if total > 0 { reachable as f64 / total as f64 } else { 0.0 },
+            unreachable_domains: cells
+                .iter()
+                .filter(|cell| !cell.reachable)
+                .map(|cell| cell.domain.clone())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{rank_dns_servers, TestResultSet};
+    use crate::test_support::dns_result;
+
+    fn cell(dns_server: &str, domain: &str, latency: Option<u64>) -> DnsMatrixCell {
+        let mut result = dns_result(dns_server, domain, latency);
+        result.provider = Some("Provider".to_string());
+        DnsMatrixCell {
+            dns_server: dns_server.to_string(),
+            provider: result.provider.clone(),
+            domain: domain.to_string(),
+            reachable: result.status,
+            result,
+        }
+    }
+
+    #[test]
+    fn presets_are_found_by_loose_names() {
+        assert_eq!(preset_names(), vec!["developer essentials", "containers", "android"]);
+        for name in preset_names() {
+            let domains = preset_domains(name).unwrap();
+            assert_eq!(domains.len(), 5, "{}", name);
+        }
+        assert_eq!(preset_domains(" Developer-Essentials "), preset_domains("developer essentials"));
+        assert_eq!(preset_domains("developer_essentials").unwrap()[0], "github.com");
+        assert_eq!(preset_domains("docker"), None);
+    }
+
+    #[test]
+    fn batch_domains_merge_the_list_and_the_preset() {
+        let domains = batch_domains(
+            vec![" ghcr.io ".to_string(), "example.com".to_string(), String::new(), "example.com".to_string()],
+            Some("containers"),
+        )
+        .unwrap();
+        assert_eq!(
+            domains,
+            vec![
+                "ghcr.io",
+                "example.com",
+                "registry-1.docker.io",
+                "auth.docker.io",
+                "production.cloudflare.docker.com",
+                "quay.io",
+            ]
+        );
+
+        assert_eq!(batch_domains(vec![], Some("nope")), Err("Unknown domain preset: nope".to_string()));
+        assert!(batch_domains(vec![" ".to_string()], None).is_err());
+    }
+
+    #[test]
+    fn coverage_and_ranking_of_a_matrix() {
+        let domains = ["github.com", "pypi.org", "ghcr.io"];
+        // 1.1.1.1 reaches everything, 9.9.9.9 reaches github.com only, both as fast
+        let matrix: Vec<DnsMatrixCell> = domains
+            .iter()
+            .map(|domain| cell("1.1.1.1", domain, Some(40)))
+            .chain(domains.iter().map(|domain| cell("9.9.9.9", domain, (*domain == "github.com").then_some(40))))
+            .collect();
+        let (full, partial): (Vec<_>, Vec<_>) = matrix.iter().cloned().partition(|cell| cell.dns_server == "1.1.1.1");
+
+        let full = ServerCoverage::new("1.1.1.1", None, &full);
+        assert_eq!((full.reachable, full.total, full.coverage), (3, 3, 1.0));
+        assert!(full.unreachable_domains.is_empty());
+        let partial = ServerCoverage::new("9.9.9.9", None, &partial);
+        assert_eq!((partial.reachable, partial.total), (1, 3));
+        assert!((partial.coverage - 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!(partial.unreachable_domains, vec!["pypi.org", "ghcr.io"]);
+        assert_eq!(ServerCoverage::new("8.8.8.8", None, &[]).coverage, 0.0);
+
+        // The cells are what the ranking is computed from: reachability 0.35, latency 0.20
+        let ranking = rank_dns_servers(&TestResultSet {
+            dns_results: matrix.into_iter().map(|cell| cell.result).collect(),
+            download_results: vec![],
+        });
+        let scores: Vec<(&str, usize, usize, f64)> = ranking
+            .ranking
+            .iter()
+            .map(|score| (score.dns_server.as_str(), score.domains_reachable, score.domains_tested, score.score))
+            .collect();
+        assert_eq!(scores, vec![("1.1.1.1", 3, 3, 100.0), ("9.9.9.9", 1, 3, 57.6)]);
+        assert_eq!(ranking.primary.as_deref(), Some("1.1.1.1"));
+        // Same provider, but nothing else was tested
+        assert_eq!(ranking.secondary.as_deref(), Some("9.9.9.9"));
+    }
+}
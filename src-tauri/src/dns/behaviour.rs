@@ -0,0 +1,414 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
+use std::time::{Instant, SystemTime};
+use serde::{Deserialize, Serialize};
+use trust_dns_resolver::proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_resolver::proto::rr::{Name, RecordType};
+use super::poison::poisoned_reason;
+use super::{bracket_ipv6, lookup_ip, trusted_baseline, CdnNetworks, DnsServer, DnsTransport, MessageClient};
+
+// Answered by every recursive resolver, used for the reachability, recursion and EDNS probes
+const PROBE_DOMAIN: &str = "example.com";
+// Dual-stack, so an empty AAAA answer means the server drops them
+const AAAA_PROBE_DOMAIN: &str = "www.google.com";
+// Popular and often censored names, compared against the trusted resolver
+pub const REWRITE_PROBE_DOMAINS: &[&str] = &[
+    "google.com",
+    "youtube.com",
+    "wikipedia.org",
+    "github.com",
+    "instagram.com",
+    "telegram.org",
+    "x.com",
+];
+// What we ask for; most servers answer with their own, smaller limit
+const EDNS_PROBE_PAYLOAD: u16 = 4096;
+
+/// How a DNS server behaves beyond answering A queries.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResolverBehaviour {
+    pub dns_server: String,
+    pub transport: DnsTransport,
+    pub provider: Option<String>,
+    pub reachable: bool,
+    pub response_time_ms: Option<u64>,
+    /// Whether a plain DNS server also answers over TCP, which clients fall back
+    /// to for truncated answers; None for other transports
+    pub tcp_fallback: Option<bool>,
+    pub edns: Option<EdnsBehaviour>,
+    pub aaaa: Option<AaaaBehaviour>,
+    pub nxdomain: Option<NxdomainBehaviour>,
+    /// Whether it resolves names it isn't authoritative for for us, i.e. is an
+    /// open recursive resolver
+    pub recursion_available: Option<bool>,
+    pub rewrites: Vec<RewriteCheck>,
+    /// Probes that could not be run, with why
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EdnsBehaviour {
+    pub supported: bool,
+    /// UDP payload size the server advertises in its OPT record
+    pub udp_payload_size: Option<u16>,
+    pub version: Option<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AaaaBehaviour {
+    pub domain: String,
+    pub response_code: String,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// The answer for a random name that can't exist; anything but NXDOMAIN with
+/// addresses means the server sends typos to its own (usually ad) page.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NxdomainBehaviour {
+    pub name: String,
+    pub response_code: String,
+    pub hijacked: bool,
+    pub addresses: Vec<IpAddr>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RewriteCheck {
+    pub domain: String,
+    pub resolved_ips: Vec<IpAddr>,
+    pub trusted_ips: Vec<IpAddr>,
+    pub rewritten: bool,
+    pub reason: Option<String>,
+}
+
+/// The trusted resolver's answers for [`REWRITE_PROBE_DOMAINS`].
+pub type RewriteBaseline = Vec<(String, Vec<IpAddr>)>;
+
+pub async fn rewrite_baseline(trusted_dns_server: &str) -> RewriteBaseline {
+    let lookups = REWRITE_PROBE_DOMAINS.iter().map(|domain| async move {
+        (domain.to_string(), trusted_baseline(domain, trusted_dns_server).await)
+    });
+    futures::future::join_all(lookups).await
+}
+
+/// Runs every behaviour probe against one server.
+pub async fn probe_resolver(
+    dns_server: &str,
+    baseline: &RewriteBaseline,
+    networks: &CdnNetworks,
+) -> ResolverBehaviour {
+    let mut report = ResolverBehaviour {
+        dns_server: dns_server.to_string(),
+        transport: DnsTransport::of(dns_server),
+        provider: None,
+        reachable: false,
+        response_time_ms: None,
+        tcp_fallback: None,
+        edns: None,
+        aaaa: None,
+        nxdomain: None,
+        recursion_available: None,
+        rewrites: vec![],
+        errors: vec![],
+    };
+
+    let client = match MessageClient::connect(dns_server).await {
+        Ok(client) => client,
+        Err(e) => {
+            report.errors.push(format!("Could not connect: {:#}", e));
+            return report;
+        }
+    };
+
+    // The plain query doubles as the reachability and recursion probe
+    let started = Instant::now();
+    let answer = match query(PROBE_DOMAIN, RecordType::A, None) {
+        Ok(message) => client.send(message).await,
+        Err(e) => Err(e),
+    };
+    match answer {
+        Ok(answer) => {
+            report.reachable = true;
+            report.response_time_ms = Some(started.elapsed().as_millis() as u64);
+            report.recursion_available = Some(answer.recursion_available() && !answer.answers().is_empty());
+        }
+        Err(e) => {
+            report.errors.push(format!("No answer for {}: {:#}", PROBE_DOMAIN, e));
+            return report;
+        }
+    }
+
+    let (tcp_fallback, edns, aaaa, nxdomain, rewrites) = tokio::join!(
+        probe_tcp(dns_server),
+        probe_edns(&client),
+        probe_aaaa(&client),
+        probe_nxdomain(&client),
+        probe_rewrites(dns_server, baseline, networks),
+    );
+    report.tcp_fallback = tcp_fallback;
+    report.rewrites = rewrites;
+    for (probe, error) in [
+        ("EDNS", edns.as_ref().err()),
+        ("AAAA", aaaa.as_ref().err()),
+        ("NXDOMAIN", nxdomain.as_ref().err()),
+    ] {
+        if let Some(error) = error {
+            report.errors.push(format!("{} probe failed: {:#}", probe, error));
+        }
+    }
+    report.edns = edns.ok();
+    report.aaaa = aaaa.ok();
+    report.nxdomain = nxdomain.ok();
+
+    println!(
+        "Behaviour of {}: tcp={:?} edns={:?} hijacks NXDOMAIN={:?} rewrites={}",
+        dns_server,
+        report.tcp_fallback,
+        report.edns.as_ref().map(|edns| edns.udp_payload_size),
+        report.nxdomain.as_ref().map(|nxdomain| nxdomain.hijacked),
+        report.rewrites.iter().filter(|check| check.rewritten).count()
+    );
+    report
+}
+
+fn query(host: &str, record_type: RecordType, edns: Option<Edns>) -> anyhow::Result<Message> {
+    let mut query = Message::new();
+    query
+        .set_id(0)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(Name::from_ascii(host)?, record_type));
+    if let Some(edns) = edns {
+        query.set_edns(edns);
+    }
+    Ok(query)
+}
+
+fn addresses(answer: &Message) -> Vec<IpAddr> {
+    answer
+        .answers()
+        .iter()
+        .filter_map(|record| record.data()?.ip_addr())
+        .collect()
+}
+
+// Asks the same address and port over TCP; only meaningful for plain UDP servers
+async fn probe_tcp(dns_server: &str) -> Option<bool> {
+    let server = DnsServer::parse(dns_server).ok()?;
+    if server.transport != DnsTransport::Udp {
+        return None;
+    }
+    let tcp_server = format!("tcp://{}:{}", bracket_ipv6(&server.address), server.port);
+    let answered = match MessageClient::connect(&tcp_server).await {
+        Ok(client) => client.send(query(PROBE_DOMAIN, RecordType::A, None).ok()?).await.is_ok(),
+        Err(_) => false,
+    };
+    Some(answered)
+}
+
+async fn probe_edns(client: &MessageClient) -> anyhow::Result<EdnsBehaviour> {
+    let mut edns = Edns::new();
+    edns.set_max_payload(EDNS_PROBE_PAYLOAD);
+    let message = query(PROBE_DOMAIN, RecordType::A, Some(edns))?;
+    let answer = client.send(message).await?;
+
+    // Servers without EDNS drop the OPT record or reject the query outright
+    let rejected = matches!(answer.response_code(), ResponseCode::FormErr | ResponseCode::NotImp);
+    let opt = answer.extensions().as_ref().filter(|_| !rejected);
+    Ok(EdnsBehaviour {
+        supported: opt.is_some(),
+        udp_payload_size: opt.map(|edns| edns.max_payload()),
+        version: opt.map(|edns| edns.version()),
+    })
+}
+
+async fn probe_aaaa(client: &MessageClient) -> anyhow::Result<AaaaBehaviour> {
+    let message = query(AAAA_PROBE_DOMAIN, RecordType::AAAA, None)?;
+    let answer = client.send(message).await?;
+    Ok(AaaaBehaviour {
+        domain: AAAA_PROBE_DOMAIN.to_string(),
+        response_code: answer.response_code().to_string(),
+        addresses: addresses(&answer),
+    })
+}
+
+async fn probe_nxdomain(client: &MessageClient) -> anyhow::Result<NxdomainBehaviour> {
+    let name = format!("{}.com", random_label());
+    let message = query(&name, RecordType::A, None)?;
+    let answer = client.send(message).await?;
+    let addresses = addresses(&answer);
+    Ok(NxdomainBehaviour {
+        hijacked: answer.response_code() == ResponseCode::NoError && !addresses.is_empty(),
+        response_code: answer.response_code().to_string(),
+        addresses,
+        name,
+    })
+}
+
+// Goes through the same lookup as the download tests, so what is compared is
+// what a download would have connected to
+async fn probe_rewrites(dns_server: &str, baseline: &RewriteBaseline, networks: &CdnNetworks) -> Vec<RewriteCheck> {
+    let checks = baseline.iter().map(|(domain, trusted_ips)| async move {
+        let resolved_ips = lookup_ip(domain, dns_server).await.unwrap_or_default();
+        let reason = poisoned_reason(&resolved_ips, trusted_ips).or_else(|| {
+            let unrelated = !resolved_ips.is_empty()
+                && !trusted_ips.is_empty()
+                && !resolved_ips
+                    .iter()
+                    .any(|ip| trusted_ips.iter().any(|trusted| same_network(ip, trusted, networks)));
+            unrelated.then(|| "no address is on a network the trusted resolver returned".to_string())
+        });
+        RewriteCheck {
+            domain: domain.clone(),
+            rewritten: reason.is_some(),
+            reason,
+            resolved_ips,
+            trusted_ips: trusted_ips.clone(),
+        }
+    });
+    futures::future::join_all(checks).await
+}
+
+// CDNs hand out different addresses by location, so an answer only counts as
+// rewritten when it isn't even on the same operator's network or subnet
+fn same_network(a: &IpAddr, b: &IpAddr, networks: &CdnNetworks) -> bool {
+    if a == b {
+        return true;
+    }
+    let same_operator = match (networks.lookup(a), networks.lookup(b)) {
+        (Some(a), Some(b)) => a.name == b.name,
+        _ => false,
+    };
+    let same_subnet = match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..3] == b.octets()[..3],
+        (IpAddr::V6(a), IpAddr::V6(b)) => a.segments()[..3] == b.segments()[..3],
+        _ => false,
+    };
+    same_operator || same_subnet
+}
+
+// A label no zone has, different on every call
+fn random_label() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    hasher.write_u128(nanos);
+    format!("bargozin-{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use trust_dns_resolver::proto::rr::rdata::A;
+    use trust_dns_resolver::proto::rr::{RData, Record};
+    use crate::test_support::{doh_server, scripted_dns_server, tcp_dns_server, udp_dns_server};
+
+    const TRUSTED: Ipv4Addr = Ipv4Addr::new(93, 184, 215, 14);
+
+    // Answers like a well-behaved recursive resolver: NXDOMAIN for the random
+    // probe name, `address` for everything else
+    async fn honest_dns_server(address: Ipv4Addr) -> String {
+        scripted_dns_server(move |query| {
+            let mut response = query.clone();
+            response.set_message_type(MessageType::Response).set_recursion_available(true);
+            let Some(question) = query.queries().first() else {
+                return response;
+            };
+            if question.name().to_ascii().starts_with("bargozin-") {
+                response.set_response_code(ResponseCode::NXDomain);
+            } else if question.query_type() == RecordType::A {
+                let record = Record::from_rdata(question.name().clone(), 60, RData::A(A::from(address)));
+                response.add_answer(record);
+            }
+            response
+        })
+        .await
+    }
+
+    fn baseline() -> RewriteBaseline {
+        vec![("example.com".to_string(), vec![IpAddr::V4(TRUSTED)])]
+    }
+
+    #[tokio::test]
+    async fn an_honest_server_passes_every_probe() {
+        let server = honest_dns_server(TRUSTED).await;
+        let report = probe_resolver(&server, &baseline(), &CdnNetworks::builtin()).await;
+
+        assert!(report.reachable);
+        assert_eq!(report.recursion_available, Some(true));
+        let nxdomain = report.nxdomain.unwrap();
+        assert!(!nxdomain.hijacked);
+        assert_eq!(nxdomain.response_code, ResponseCode::NXDomain.to_string());
+        assert!(nxdomain.name.starts_with("bargozin-"));
+        assert!(report.edns.unwrap().supported);
+        assert_eq!(report.tcp_fallback, Some(false));
+        assert!(!report.rewrites[0].rewritten);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+    }
+
+    #[tokio::test]
+    async fn flags_a_server_answering_names_that_cannot_exist() {
+        // Answers every A query with its own address, the random name included
+        let server = udp_dns_server(Ipv4Addr::new(10, 10, 34, 35)).await;
+        let report = probe_resolver(&server, &baseline(), &CdnNetworks::builtin()).await;
+
+        let nxdomain = report.nxdomain.unwrap();
+        assert!(nxdomain.hijacked);
+        assert_eq!(nxdomain.addresses, vec![IpAddr::V4(Ipv4Addr::new(10, 10, 34, 35))]);
+        // The same forging server rewrites the probe domains too
+        let rewrite = &report.rewrites[0];
+        assert!(rewrite.rewritten);
+        assert!(rewrite.reason.as_ref().unwrap().contains("block page"), "{:?}", rewrite.reason);
+    }
+
+    #[tokio::test]
+    async fn reports_whether_a_udp_server_also_answers_over_tcp() {
+        let server = udp_dns_server(TRUSTED).await;
+        assert_eq!(probe_tcp(&server).await, Some(false));
+        tcp_dns_server(&server, TRUSTED).await;
+        assert_eq!(probe_tcp(&server).await, Some(true));
+        let report = probe_resolver(&server, &baseline(), &CdnNetworks::builtin()).await;
+        assert_eq!(report.tcp_fallback, Some(true));
+
+        // Only asked of plain UDP servers
+        let doh = doh_server(TRUSTED).await;
+        assert_eq!(probe_tcp(&doh).await, None);
+        assert_eq!(probe_tcp(&format!("tcp://{}", server)).await, None);
+    }
+
+    #[tokio::test]
+    async fn answers_off_the_trusted_network_are_rewrites() {
+        let networks = CdnNetworks::builtin();
+        let check = |address: Ipv4Addr| {
+            let networks = networks.clone();
+            async move {
+                let server = udp_dns_server(address).await;
+                probe_rewrites(&server, &baseline(), &networks).await.remove(0)
+            }
+        };
+
+        // Another address in the trusted /24 is a CDN choosing a different edge
+        let nearby = check(Ipv4Addr::new(93, 184, 215, 99)).await;
+        assert!(!nearby.rewritten);
+        assert_eq!(nearby.trusted_ips, vec![IpAddr::V4(TRUSTED)]);
+
+        let elsewhere = check(Ipv4Addr::new(5, 160, 1, 1)).await;
+        assert!(elsewhere.rewritten);
+        assert_eq!(elsewhere.resolved_ips, vec![IpAddr::V4(Ipv4Addr::new(5, 160, 1, 1))]);
+        assert!(elsewhere.reason.unwrap().contains("no address is on a network"));
+    }
+
+    #[test]
+    fn same_operator_counts_as_the_same_network() {
+        let networks = CdnNetworks::builtin();
+        let ip = |address: &str| address.parse::<IpAddr>().unwrap();
+        assert!(same_network(&ip("104.16.1.1"), &ip("172.64.2.2"), &networks));
+        assert!(same_network(&ip("2001:db8:1:2::1"), &ip("2001:db8:1:3::1"), &networks));
+        assert!(!same_network(&ip("104.16.1.1"), &ip("151.101.1.1"), &networks));
+        assert!(!same_network(&ip("93.184.215.14"), &ip("2001:db8::1"), &networks));
+    }
+}
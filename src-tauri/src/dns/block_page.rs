@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::utils::{block_pages_config_path, read_block_pages_file};
+
+// Most block pages are small; anything past this is not needed to recognise them
+const BODY_SAMPLE_LIMIT: usize = 64 * 1024;
+
+// Shipped fingerprints, checked after the user's own. More specific rules come first
+// since the first match wins. A plain 403 is just as often a login wall or an
+// IP ban, so sanctions are only reported on a body or header fingerprint.
+const BUILTIN_BLOCK_PAGES: &str = r#"
+blockPages:
+  - name: Iranian government block page
+    reason: censored
+    body: [peyvandha.ir, 10.10.34.34, 10.10.34.35, 10.10.34.36]
+  - name: Unavailable for legal reasons
+    reason: censored
+    status: [451]
+  - name: Google
+    reason: sanctioned
+    status: [403]
+    body:
+      - not available in your country
+      - isn't available in your country
+      - not available in your region
+  - name: Docker Hub
+    reason: sanctioned
+    status: [403]
+    hosts: [docker.io, docker.com]
+    body:
+      - comply with us export control regulations
+      - block all ip addresses that are located in
+  - name: npm
+    reason: sanctioned
+    status: [403]
+    hosts: [npmjs.org, npmjs.com]
+    body: [error 1009, has banned the country or region]
+  - name: GitLab
+    reason: sanctioned
+    status: [403]
+    hosts: [gitlab.com]
+    body: [error 1009, has banned the country or region, not available in your country]
+  - name: Amazon CloudFront geo restriction
+    reason: sanctioned
+    status: [403]
+    body: [configured to block access from your country]
+  - name: Export control notice
+    reason: sanctioned
+    status: [403]
+    body: [export control, sanctioned countr, embargoed countr, trade sanctions]
+  - name: Rate limit
+    reason: rate-limited
+    status: [429]
+  - name: Access denied
+    reason: auth-required
+    status: [401, 403]
+"#;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlockReason {
+    Sanctioned,
+    Censored,
+    AuthRequired,
+    RateLimited,
+}
+
+/// One known block page. Every listed criterion must hold; of `body` and
+/// `headers`, one entry matching is enough. Text is compared case-insensitively
+/// and an empty header value only checks that the header is present.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockPageRule {
+    pub name: String,
+    pub reason: BlockReason,
+    #[serde(default)]
+    pub status: Vec<u16>,
+    /// Host suffixes the rule is limited to, e.g. `npmjs.org` also covers `registry.npmjs.org`
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub body: Vec<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl BlockPageRule {
+    fn matches(&self, host: &str, response: &HttpResponseSample) -> bool {
+        if !self.status.is_empty() && !self.status.contains(&response.status) {
+            return false;
+        }
+
+        let host = host.to_lowercase();
+        let host_matches = self.hosts.iter().any(|suffix| {
+            let suffix = suffix.to_lowercase();
+            host == suffix || host.ends_with(&format!(".{}", suffix))
+        });
+        if !self.hosts.is_empty() && !host_matches {
+            return false;
+        }
+
+        if self.body.is_empty() && self.headers.is_empty() {
+            return true;
+        }
+
+        let body = response.body.to_lowercase();
+        let body_matches = self.body.iter().any(|text| body.contains(&text.to_lowercase()));
+        let header_matches = self.headers.iter().any(|(name, text)| {
+            response.headers.iter().any(|(header, value)| {
+                header.eq_ignore_ascii_case(name) && value.to_lowercase().contains(&text.to_lowercase())
+            })
+        });
+        body_matches || header_matches
+    }
+}
+
+/// Contents of a blockPages.yml file.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockPages {
+    #[serde(default)]
+    pub block_pages: Vec<BlockPageRule>,
+}
+
+impl BlockPages {
+    pub fn from_yaml(content: &str) -> anyhow::Result<Self> {
+        let block_pages: Self = serde_yaml::from_str(content)?;
+        if let Some(rule) = block_pages.block_pages.iter().find(|rule| {
+            rule.reason == BlockReason::Sanctioned && rule.body.is_empty() && rule.headers.is_empty()
+        }) {
+            anyhow::bail!("Sanction rule '{}' needs a body or headers fingerprint", rule.name);
+        }
+        Ok(block_pages)
+    }
+
+    pub fn builtin() -> Self {
+        Self::from_yaml(BUILTIN_BLOCK_PAGES).expect("built-in block page fingerprints are valid")
+    }
+
+    // The first rule matching the response, if it is a known block page
+    pub fn identify(&self, host: &str, response: &HttpResponseSample) -> Option<&BlockPageRule> {
+        self.block_pages.iter().find(|rule| rule.matches(host, response))
+    }
+}
+
+// The user's fingerprints, if any, followed by the built-in ones
+pub async fn load_block_pages() -> BlockPages {
+    let mut block_pages = BlockPages::default();
+    let path = block_pages_config_path();
+    if path.exists() {
+        match read_block_pages_file(&path).await {
+            Ok(user) => block_pages.block_pages.extend(user.block_pages),
+            Err(e) => eprintln!("Failed to read block page fingerprints from {}: {}", path.display(), e),
+        }
+    }
+    block_pages.block_pages.extend(BlockPages::builtin().block_pages);
+    block_pages
+}
+
+/// Status line, headers and the start of the body of an HTTP response.
+#[derive(Debug, Default, Clone)]
+pub struct HttpResponseSample {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpResponseSample {
+    // Only error responses are worth reading, a working site isn't a block page
+    pub async fn read(response: reqwest::Response) -> Self {
+        let read_body = !response.status().is_success();
+        Self::sample(response, read_body).await
+    }
+
+    // Behind a rejected certificate a 200 is no sign of the real site, so the body
+    // is read whatever the status
+    pub async fn read_with_body(response: reqwest::Response) -> Self {
+        Self::sample(response, true).await
+    }
+
+    async fn sample(mut response: reqwest::Response, read_body: bool) -> Self {
+        let status = response.status();
+        let mut sample = Self {
+            status: status.as_u16(),
+            reason: status.canonical_reason().unwrap_or("Unknown").to_string(),
+            headers: response
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+                .collect(),
+            body: String::new(),
+        };
+        if !read_body {
+            return sample;
+        }
+
+        let mut body = Vec::new();
+        while body.len() < BODY_SAMPLE_LIMIT {
+            match response.chunk().await {
+                Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+                Ok(None) => break,
+                Err(e) => {
+                    println!("Could not read response body: {}", e);
+                    break;
+                }
+            }
+        }
+        body.truncate(BODY_SAMPLE_LIMIT);
+        sample.body = String::from_utf8_lossy(&body).into_owned();
+        sample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forbidden(body: &str) -> HttpResponseSample {
+        HttpResponseSample {
+            status: 403,
+            reason: "Forbidden".to_string(),
+            headers: vec![],
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn plain_forbidden_is_not_a_sanction() {
+        let builtin = BlockPages::builtin();
+        for host in ["registry-1.docker.io", "registry.npmjs.org", "gitlab.com"] {
+            let rule = builtin.identify(host, &forbidden("<h1>403 Forbidden</h1>")).unwrap();
+            assert_eq!(rule.reason, BlockReason::AuthRequired, "{}", host);
+        }
+    }
+
+    #[test]
+    fn sanction_pages_are_recognised_by_their_text() {
+        let builtin = BlockPages::builtin();
+        let docker = forbidden(
+            "Since Docker is a US company, we must comply with US export control regulations. \
+             In an effort to comply with these, we now block all IP addresses that are located in Cuba, Iran, ...",
+        );
+        assert_eq!(builtin.identify("registry-1.docker.io", &docker).unwrap().name, "Docker Hub");
+        let cloudflare = forbidden("Error 1009 Access denied. The owner of this website has banned the country or region your IP address is in (IR)");
+        assert_eq!(builtin.identify("registry.npmjs.org", &cloudflare).unwrap().name, "npm");
+        assert_eq!(builtin.identify("gitlab.com", &cloudflare).unwrap().name, "GitLab");
+    }
+
+    #[test]
+    fn sanction_rules_need_a_fingerprint() {
+        let host_only = "blockPages:\n  - name: Example\n    reason: sanctioned\n    hosts: [example.com]\n";
+        assert!(BlockPages::from_yaml(host_only).is_err());
+        let with_header = "blockPages:\n  - name: Example\n    reason: sanctioned\n    headers:\n      x-block-reason: \"\"\n";
+        assert!(BlockPages::from_yaml(with_header).is_ok());
+    }
+}
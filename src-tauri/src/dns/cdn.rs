@@ -0,0 +1,538 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use trust_dns_resolver::proto::op::{Edns, Message, MessageType, OpCode, Query};
+use trust_dns_resolver::proto::rr::rdata::opt::{ClientSubnet, EdnsCode, EdnsOption};
+use trust_dns_resolver::proto::rr::{Name, RecordType};
+use crate::utils::{cdn_networks_config_path, read_cdn_networks_file, CustomDnsResolver};
+use super::ip_database::{lookup_asn, AsnInfo};
+use super::poison::is_bogon;
+use super::{lookup_ip, LookupStrategy, MessageClient};
+
+// OpenDNS answers this name with the address the query came from, which is
+// where the default client subnet is taken from
+const PUBLIC_ADDRESS_HOST: &str = "myip.opendns.com";
+const PUBLIC_ADDRESS_DNS_SERVER: &str = "208.67.222.222";
+// Probing an edge for its POP is a single request, it shouldn't hold up the download test
+const POP_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Shipped address ranges of a few big CDNs and hosts, checked after the user's own.
+// Ranges are the operators' published ones as of writing and name the CDN rather
+// than whoever announces the block; addresses outside them are tagged from the
+// bundled IP database (see ip_database.rs). The POP comes from the edge's
+// response headers.
+const BUILTIN_CDN_NETWORKS: &str = r#"
+cdnNetworks:
+  - name: Cloudflare
+    asn: 13335
+    prefixes:
+      - 173.245.48.0/20
+      - 103.21.244.0/22
+      - 103.22.200.0/22
+      - 103.31.4.0/22
+      - 141.101.64.0/18
+      - 108.162.192.0/18
+      - 190.93.240.0/20
+      - 188.114.96.0/20
+      - 197.234.240.0/22
+      - 198.41.128.0/17
+      - 162.158.0.0/15
+      - 104.16.0.0/13
+      - 104.24.0.0/14
+      - 172.64.0.0/13
+      - 131.0.72.0/22
+      - 2400:cb00::/32
+      - 2606:4700::/32
+      - 2803:f800::/32
+      - 2405:b500::/32
+      - 2405:8100::/32
+      - 2a06:98c0::/29
+      - 2c0f:f248::/32
+  - name: Fastly
+    asn: 54113
+    prefixes:
+      - 23.235.32.0/20
+      - 43.249.72.0/22
+      - 103.244.50.0/24
+      - 103.245.222.0/23
+      - 103.245.224.0/24
+      - 104.156.80.0/20
+      - 140.248.64.0/18
+      - 140.248.128.0/17
+      - 146.75.0.0/17
+      - 151.101.0.0/16
+      - 157.52.64.0/18
+      - 167.82.0.0/17
+      - 167.82.128.0/20
+      - 167.82.160.0/20
+      - 167.82.224.0/20
+      - 172.111.64.0/18
+      - 185.31.16.0/22
+      - 199.27.72.0/21
+      - 199.232.0.0/16
+      - 2a04:4e40::/32
+      - 2a04:4e42::/32
+  - name: Amazon CloudFront
+    asn: 16509
+    prefixes:
+      - 13.32.0.0/15
+      - 13.35.0.0/16
+      - 13.224.0.0/14
+      - 18.64.0.0/14
+      - 18.154.0.0/15
+      - 18.160.0.0/15
+      - 18.164.0.0/15
+      - 18.172.0.0/15
+      - 52.84.0.0/15
+      - 54.182.0.0/16
+      - 54.192.0.0/16
+      - 54.230.0.0/16
+      - 54.239.128.0/18
+      - 54.239.192.0/19
+      - 99.84.0.0/16
+      - 99.86.0.0/16
+      - 108.138.0.0/15
+      - 108.156.0.0/14
+      - 143.204.0.0/16
+      - 205.251.192.0/19
+      - 2600:9000::/28
+  - name: Google
+    asn: 15169
+    prefixes:
+      - 8.8.4.0/24
+      - 8.8.8.0/24
+      - 34.64.0.0/10
+      - 35.184.0.0/13
+      - 64.233.160.0/19
+      - 66.102.0.0/20
+      - 66.249.64.0/19
+      - 72.14.192.0/18
+      - 74.125.0.0/16
+      - 108.177.0.0/17
+      - 142.250.0.0/15
+      - 172.217.0.0/16
+      - 172.253.0.0/16
+      - 173.194.0.0/16
+      - 209.85.128.0/17
+      - 216.58.192.0/19
+      - 216.239.32.0/19
+      - 2001:4860::/32
+      - 2404:6800::/32
+      - 2607:f8b0::/32
+      - 2800:3f0::/32
+      - 2a00:1450::/32
+      - 2c0f:fb50::/32
+  - name: Akamai
+    asn: 20940
+    prefixes:
+      - 2.16.0.0/13
+      - 23.0.0.0/12
+      - 23.32.0.0/11
+      - 23.64.0.0/14
+      - 23.72.0.0/13
+      - 69.192.0.0/16
+      - 72.246.0.0/15
+      - 88.221.0.0/16
+      - 92.122.0.0/15
+      - 95.100.0.0/15
+      - 96.6.0.0/15
+      - 96.16.0.0/15
+      - 104.64.0.0/10
+      - 184.24.0.0/13
+      - 184.50.0.0/15
+      - 184.84.0.0/14
+      - 2a02:26f0::/29
+      - 2600:1400::/24
+  - name: GitHub
+    asn: 36459
+    prefixes:
+      - 140.82.112.0/20
+      - 143.55.64.0/20
+      - 185.199.108.0/22
+      - 192.30.252.0/22
+      - 2a0a:a440::/29
+      - 2606:50c0::/32
+  - name: ArvanCloud
+    asn: 202468
+    prefixes:
+      - 185.143.232.0/22
+      - 2a0b:4e40::/32
+"#;
+
+/// One operator's address ranges.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CdnNetwork {
+    pub name: String,
+    pub asn: Option<u32>,
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+}
+
+/// Contents of a cdnNetworks.yml file.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CdnNetworks {
+    #[serde(default)]
+    pub cdn_networks: Vec<CdnNetwork>,
+}
+
+impl CdnNetworks {
+    pub fn from_yaml(content: &str) -> anyhow::Result<Self> {
+        Ok(serde_yaml::from_str(content)?)
+    }
+
+    pub fn builtin() -> Self {
+        Self::from_yaml(BUILTIN_CDN_NETWORKS).expect("built-in CDN networks are valid")
+    }
+
+    // The network with the most specific prefix covering `ip`
+    pub fn lookup(&self, ip: &IpAddr) -> Option<&CdnNetwork> {
+        self.cdn_networks
+            .iter()
+            .filter_map(|network| {
+                let prefix_len = network
+                    .prefixes
+                    .iter()
+                    .filter_map(|prefix| prefix_contains(prefix, ip))
+                    .max()?;
+                Some((prefix_len, network))
+            })
+            .max_by_key(|(prefix_len, _)| *prefix_len)
+            .map(|(_, network)| network)
+    }
+}
+
+// The user's networks, if any, followed by the built-in ones
+pub async fn load_cdn_networks() -> CdnNetworks {
+    let mut networks = CdnNetworks::default();
+    let path = cdn_networks_config_path();
+    if path.exists() {
+        match read_cdn_networks_file(&path).await {
+            Ok(user) => networks.cdn_networks.extend(user.cdn_networks),
+            Err(e) => eprintln!("Failed to read CDN networks from {}: {}", path.display(), e),
+        }
+    }
+    networks.cdn_networks.extend(CdnNetworks::builtin().cdn_networks);
+    networks
+}
+
+// The prefix length if `prefix` (e.g. `104.16.0.0/13`) covers `ip`
+fn prefix_contains(prefix: &str, ip: &IpAddr) -> Option<u32> {
+    let (network, len) = prefix.split_once('/')?;
+    let network: IpAddr = network.trim().parse().ok()?;
+    let len: u32 = len.trim().parse().ok()?;
+    let (network, ip, bits) = match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => (u32::from(network) as u128, u32::from(*ip) as u128, 32),
+        (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(*ip), 128),
+        _ => return None,
+    };
+    if len > bits {
+        return None;
+    }
+    let mask = u128::MAX.checked_shl(bits - len).unwrap_or(0) & (u128::MAX >> (128 - bits));
+    (network & mask == ip & mask).then_some(len)
+}
+
+/// The POP (usually an IATA airport code such as `FRA`) a CDN edge reports in
+/// its response headers.
+pub fn pop_from_headers(headers: &HeaderMap) -> Option<String> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    // Cloudflare: `cf-ray: 8a1b2c3d4e5f6789-FRA`
+    if let Some(ray) = header("cf-ray") {
+        return ray.rsplit_once('-').map(|(_, pop)| pop.to_uppercase());
+    }
+    // CloudFront: `x-amz-cf-pop: FRA56-P1`
+    if let Some(pop) = header("x-amz-cf-pop") {
+        return pop.get(..3).map(|pop| pop.to_uppercase());
+    }
+    // Fastly: `x-served-by: cache-fra-eddf8230070-FRA`, the edge nearest us comes last
+    if let Some(served_by) = header("x-served-by").filter(|value| value.starts_with("cache-")) {
+        return served_by
+            .rsplit(',')
+            .next()
+            .and_then(|cache| cache.trim().rsplit_once('-'))
+            .map(|(_, pop)| pop.to_uppercase());
+    }
+    ["x-edge-location", "x-cdn-pop", "x-pop"]
+        .iter()
+        .find_map(|name| header(name))
+        .map(|pop| pop.trim().to_uppercase())
+}
+
+/// A CDN edge address, tagged with its network and, when known, its POP.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CdnEdge {
+    pub ip: IpAddr,
+    pub network: Option<String>,
+    pub asn: Option<u32>,
+    /// Country the address block is registered in, from the IP database
+    pub country: Option<String>,
+    pub pop: Option<String>,
+}
+
+impl CdnEdge {
+    pub fn new(ip: IpAddr, networks: &CdnNetworks, pop: Option<String>) -> Self {
+        Self::tagged(ip, networks, lookup_asn(&ip), pop)
+    }
+
+    // The CDN ranges name the network, the IP database covers everything else
+    fn tagged(ip: IpAddr, networks: &CdnNetworks, routed: Option<AsnInfo>, pop: Option<String>) -> Self {
+        let network = networks.lookup(&ip);
+        Self {
+            ip,
+            network: network
+                .map(|network| network.name.clone())
+                .or_else(|| routed.as_ref().map(|routed| routed.name.clone())),
+            asn: network
+                .and_then(|network| network.asn)
+                .or_else(|| routed.as_ref().map(|routed| routed.asn)),
+            country: routed.and_then(|routed| routed.country),
+            pop,
+        }
+    }
+}
+
+/// The edges one query returned, with or without a client subnet.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct EcsProbe {
+    pub client_subnet: Option<String>,
+    /// Prefix length the server says its answer is valid for; 0 means it
+    /// ignored the subnet
+    pub scope_prefix: Option<u8>,
+    pub edges: Vec<CdnEdge>,
+    pub error: Option<String>,
+}
+
+/// Where a resolver steers us, asked once as is and once with an EDNS Client
+/// Subnet (RFC 7871).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CdnSteering {
+    pub without_ecs: EcsProbe,
+    pub with_ecs: EcsProbe,
+    /// Whether the subnet moved us to other edges
+    pub ecs_changed_answer: bool,
+}
+
+/// The ECS subnet to send when the user didn't enter one: the /24 (or /56 for
+/// IPv6) of their public address, as RFC 7871 section 11.1 suggests for privacy.
+pub async fn public_client_subnet() -> anyhow::Result<String> {
+    let addresses = lookup_ip(PUBLIC_ADDRESS_HOST, PUBLIC_ADDRESS_DNS_SERVER).await?;
+    let address = addresses
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("{} returned no address", PUBLIC_ADDRESS_HOST))?;
+    client_subnet_of(address)
+}
+
+fn client_subnet_of(address: &IpAddr) -> anyhow::Result<String> {
+    // A hijacked lookup answers with a local or block page address
+    if is_bogon(address) {
+        anyhow::bail!("{} is not a public address", address);
+    }
+    Ok(match address {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, d, ..] = ip.segments();
+            format!("{}/56", std::net::Ipv6Addr::new(a, b, c, d & 0xff00, 0, 0, 0, 0))
+        }
+    })
+}
+
+pub fn parse_client_subnet(subnet: &str) -> anyhow::Result<ClientSubnet> {
+    ClientSubnet::from_str(subnet.trim())
+        .map_err(|e| anyhow::anyhow!("Invalid client subnet {}: {}", subnet, e))
+}
+
+pub async fn compare_ecs(
+    url: &str,
+    dns_server: &str,
+    client_subnet: &str,
+    lookup_strategy: LookupStrategy,
+    networks: &CdnNetworks,
+) -> anyhow::Result<CdnSteering> {
+    let parsed_url = reqwest::Url::parse(url)?;
+    let host = parsed_url.host_str().ok_or_else(|| anyhow::anyhow!("Invalid host"))?;
+    let subnet = parse_client_subnet(client_subnet)?;
+    let record_type = match lookup_strategy {
+        LookupStrategy::Ipv6Only => RecordType::AAAA,
+        _ => RecordType::A,
+    };
+
+    let client = MessageClient::connect(dns_server).await?;
+    let without_ecs = probe(&client, dns_server, url, host, record_type, None, networks).await;
+    let mut with_ecs = probe(&client, dns_server, url, host, record_type, Some(subnet), networks).await;
+    with_ecs.client_subnet = Some(client_subnet.trim().to_string());
+
+    let ips = |probe: &EcsProbe| {
+        let mut ips: Vec<IpAddr> = probe.edges.iter().map(|edge| edge.ip).collect();
+        ips.sort();
+        ips
+    };
+    let ecs_changed_answer = with_ecs.error.is_none() && without_ecs.error.is_none() && ips(&with_ecs) != ips(&without_ecs);
+
+    Ok(CdnSteering {
+        without_ecs,
+        with_ecs,
+        ecs_changed_answer,
+    })
+}
+
+async fn probe(
+    client: &MessageClient,
+    dns_server: &str,
+    url: &str,
+    host: &str,
+    record_type: RecordType,
+    subnet: Option<ClientSubnet>,
+    networks: &CdnNetworks,
+) -> EcsProbe {
+    let answer = match ecs_query(host, record_type, subnet) {
+        Ok(query) => client.send(query).await,
+        Err(e) => Err(e),
+    };
+    let answer = match answer {
+        Ok(answer) => answer,
+        Err(e) => {
+            return EcsProbe {
+                error: Some(format!("{:#}", e)),
+                ..EcsProbe::default()
+            }
+        }
+    };
+
+    let ips: Vec<IpAddr> = answer
+        .answers()
+        .iter()
+        .filter_map(|record| record.data()?.ip_addr())
+        .collect();
+
+    // Only the first edge is asked for its POP, the others are usually the same site
+    let mut edges = Vec::new();
+    for (index, ip) in ips.iter().enumerate() {
+        let pop = if index == 0 { probe_pop(url, host, *ip, dns_server).await } else { None };
+        edges.push(CdnEdge::new(*ip, networks, pop));
+    }
+
+    EcsProbe {
+        client_subnet: None,
+        scope_prefix: scope_prefix(&answer),
+        edges,
+        error: None,
+    }
+}
+
+fn ecs_query(host: &str, record_type: RecordType, subnet: Option<ClientSubnet>) -> anyhow::Result<Message> {
+    let mut edns = Edns::new();
+    edns.set_max_payload(1232);
+    if let Some(subnet) = subnet {
+        edns.options_mut().insert(EdnsOption::Subnet(subnet));
+    }
+
+    let mut query = Message::new();
+    query
+        .set_id(0)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(Name::from_ascii(host)?, record_type))
+        .set_edns(edns);
+    Ok(query)
+}
+
+// SCOPE PREFIX-LENGTH is the fourth byte of the option (RFC 7871 section 6)
+fn scope_prefix(answer: &Message) -> Option<u8> {
+    let option = answer.extensions().as_ref()?.option(EdnsCode::Subnet)?;
+    let bytes = Vec::<u8>::try_from(option).ok()?;
+    bytes.get(3).copied()
+}
+
+// Asks the edge at `ip` for its headers, with any redirect target looked up
+// through the server being tested like the host itself
+async fn probe_pop(url: &str, host: &str, ip: IpAddr, dns_server: &str) -> Option<String> {
+    let client = CustomDnsResolver::new(dns_server, LookupStrategy::default())
+        .ok()?
+        .pin(host, vec![ip])
+        .client_builder()
+        .timeout(POP_PROBE_TIMEOUT)
+        .build()
+        .ok()?;
+    // Headers are all we need
+    let response = client.head(url).send().await.ok()?;
+    pop_from_headers(response.headers())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use crate::test_support::udp_dns_server;
+
+    #[test]
+    fn client_subnet_is_the_public_network() {
+        let subnet = |ip: &str| client_subnet_of(&ip.parse().unwrap()).ok();
+        assert_eq!(subnet("5.160.12.34").as_deref(), Some("5.160.12.0/24"));
+        assert_eq!(subnet("2a01:5ec0:1234:5678::1").as_deref(), Some("2a01:5ec0:1234:5600::/56"));
+        assert_eq!(subnet("10.10.34.35"), None);
+        assert!(parse_client_subnet(&subnet("5.160.12.34").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn edges_outside_the_cdn_ranges_are_tagged_from_the_ip_database() {
+        let networks = CdnNetworks::builtin();
+        let routed = |asn, name: &str| {
+            Some(AsnInfo { asn, country: Some("IR".to_string()), name: name.to_string() })
+        };
+
+        // The CDN list wins, the database still gives the country
+        let edge = CdnEdge::tagged("104.16.1.1".parse().unwrap(), &networks, routed(13335, "CLOUDFLARENET"), None);
+        assert_eq!(edge.network.as_deref(), Some("Cloudflare"));
+        assert_eq!(edge.asn, Some(13335));
+        assert_eq!(edge.country.as_deref(), Some("IR"));
+
+        let edge = CdnEdge::tagged("5.160.1.1".parse().unwrap(), &networks, routed(64500, "EXAMPLE-AS"), None);
+        assert_eq!(edge.network.as_deref(), Some("EXAMPLE-AS"));
+        assert_eq!(edge.asn, Some(64500));
+
+        let edge = CdnEdge::tagged("5.160.1.1".parse().unwrap(), &networks, None, None);
+        assert_eq!((edge.network, edge.asn, edge.country), (None, None, None));
+    }
+
+    #[tokio::test]
+    async fn pop_probe_follows_redirects_through_the_tested_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = [0u8; 4096];
+                let read = stream.read(&mut buffer).await.unwrap_or(0);
+                let head = String::from_utf8_lossy(&buffer[..read]).to_ascii_lowercase();
+                let request_line = head.lines().next().unwrap_or_default().to_string();
+                let response = match head.contains("host: edge.test") {
+                    true => format!("HTTP/1.1 302 Found\r\nlocation: http://mirror.test:{}/file\r\ncontent-length: 0\r\n\r\n", port),
+                    false => "HTTP/1.1 200 OK\r\ncf-ray: 8a1b2c3d4e5f6789-FRA\r\ncontent-length: 0\r\n\r\n".to_string(),
+                };
+                seen.lock().unwrap().push(request_line);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        // mirror.test only resolves through the server under test
+        let dns_server = udp_dns_server(Ipv4Addr::LOCALHOST).await;
+        let url = format!("http://edge.test:{}/", port);
+        let pop = probe_pop(&url, "edge.test", IpAddr::V4(Ipv4Addr::LOCALHOST), &dns_server).await;
+        assert_eq!(pop.as_deref(), Some("FRA"));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|line| line.starts_with("head ")), "{:?}", requests);
+    }
+}
@@ -0,0 +1,806 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use trust_dns_resolver::proto::op::{Edns, Message, MessageType, OpCode, Query};
+use trust_dns_resolver::proto::rr::dnssec::rdata::{DNSSECRData, DNSKEY, DS, NSEC, NSEC3, RRSIG};
+use trust_dns_resolver::proto::rr::dnssec::{Algorithm, DigestType, Verifier};
+use trust_dns_resolver::proto::rr::{DNSClass, Name, RData, Record, RecordType};
+use super::{DnsTestResult, MessageClient};
+
+// Root zone KSKs as the SHA-256 DS digests in IANA's root-anchors.xml: KSK-2017,
+// and KSK-2024 which took over signing the root in October 2026
+const ROOT_ANCHORS: &[(u16, Algorithm, &str)] = &[
+    (20326, Algorithm::RSASHA256, "E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D"),
+    (38696, Algorithm::RSASHA256, "683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16"),
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DnssecStatus {
+    /// Every signed record chains up to the root trust anchor
+    Validated,
+    /// The zone isn't signed, so there is nothing to validate
+    Insecure,
+    /// Signatures are there but don't verify, or the chain to the root is broken
+    Bogus,
+    /// No signatures, although the trusted resolver shows the zone is signed
+    Stripped,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DnssecCheck {
+    pub status: DnssecStatus,
+    pub reason: Option<String>,
+}
+
+impl DnssecCheck {
+    fn new(status: DnssecStatus, reason: Option<String>) -> Self {
+        Self { status, reason }
+    }
+}
+
+// Whether the trusted resolver returns signatures for `host`, None if it can't
+// be asked. Needed to tell a server stripping signatures from an unsigned zone.
+pub async fn trusted_signatures(host: &str, trusted_dns_server: &str) -> Option<bool> {
+    let result = async {
+        let name = Name::from_ascii(host)?;
+        let client = MessageClient::connect(trusted_dns_server).await?;
+        let answer = client.send(dnssec_query(&name, RecordType::A)).await?;
+        anyhow::Ok(answer.answers().iter().any(|record| rrsig(record).is_some()))
+    }
+    .await;
+
+    match result {
+        Ok(signed) => Some(signed),
+        Err(e) => {
+            eprintln!("Trusted DNSSEC lookup of {} via {} failed: {}", host, trusted_dns_server, e);
+            None
+        }
+    }
+}
+
+/// Asks `dns_server` for the signed A records of `host` and validates them
+/// from the root trust anchor down, fetching every DNSKEY and DS on the way
+/// from the same server. Checking is disabled on the queries so a validating
+/// server hands over bogus data for us to judge instead of failing.
+pub async fn check_dnssec(host: &str, dns_server: &str, trusted_signed: Option<bool>) -> anyhow::Result<DnssecCheck> {
+    check_dnssec_from(host, dns_server, trusted_signed, root_anchors()).await
+}
+
+// Validates up to `anchors` instead of the root's, so tests can sign a root of their own
+async fn check_dnssec_from(
+    host: &str,
+    dns_server: &str,
+    trusted_signed: Option<bool>,
+    anchors: Vec<DS>,
+) -> anyhow::Result<DnssecCheck> {
+    let name = Name::from_ascii(host)?;
+    let client = MessageClient::connect(dns_server).await?;
+    let answer = client.send(dnssec_query(&name, RecordType::A)).await?;
+
+    let rrsets = rrsets(answer.answers());
+    if rrsets.is_empty() {
+        return Err(anyhow::anyhow!("No records for {} to validate", host));
+    }
+
+    if rrsets.iter().all(|rrset| rrset.signatures.is_empty()) {
+        return Ok(match trusted_signed {
+            Some(true) => DnssecCheck::new(
+                DnssecStatus::Stripped,
+                Some("The answer has no signatures although the trusted resolver returned them".to_string()),
+            ),
+            _ => DnssecCheck::new(DnssecStatus::Insecure, None),
+        });
+    }
+
+    let mut validator = ChainValidator::new(&client, anchors);
+    let mut unsigned = Vec::new();
+    for rrset in &rrsets {
+        if rrset.signatures.is_empty() {
+            // e.g. a CNAME from a signed zone into an unsigned one
+            unsigned.push(format!("{} {}", rrset.name, rrset.record_type));
+            continue;
+        }
+        if let Err(reason) = validator.verify(rrset).await {
+            return Ok(DnssecCheck::new(DnssecStatus::Bogus, Some(reason)));
+        }
+    }
+
+    if !unsigned.is_empty() {
+        let reason = format!("Unsigned records in the answer: {}", unsigned.join(", "));
+        return Ok(DnssecCheck::new(DnssecStatus::Insecure, Some(reason)));
+    }
+    if let Some(reason) = validator.insecure_reason {
+        return Ok(DnssecCheck::new(DnssecStatus::Insecure, Some(reason)));
+    }
+    Ok(DnssecCheck::new(DnssecStatus::Validated, None))
+}
+
+pub async fn apply_dnssec_check(result: &mut DnsTestResult, host: &str, trusted_signed: Option<bool>) {
+    match check_dnssec(host, &result.dns_server, trusted_signed).await {
+        Ok(check) => result.dnssec = Some(check),
+        Err(e) => println!("DNSSEC check of {} using DNS {} failed: {}", host, result.dns_server, e),
+    }
+}
+
+// Recursive query with DO set, so signatures come along, and CD set
+fn dnssec_query(name: &Name, record_type: RecordType) -> Message {
+    let mut edns = Edns::new();
+    edns.set_dnssec_ok(true).set_max_payload(1232);
+
+    let mut query = Message::new();
+    query
+        .set_id(0)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .set_checking_disabled(true)
+        .add_query(Query::query(name.clone(), record_type))
+        .set_edns(edns);
+    query
+}
+
+// Records of one name and type, with the signatures covering them
+struct Rrset {
+    name: Name,
+    record_type: RecordType,
+    records: Vec<Record>,
+    signatures: Vec<RRSIG>,
+}
+
+fn rrsets(records: &[Record]) -> Vec<Rrset> {
+    let mut rrsets: Vec<Rrset> = Vec::new();
+    for record in records.iter().filter(|record| rrsig(record).is_none()) {
+        let name = record.name().to_lowercase();
+        match rrsets
+            .iter_mut()
+            .find(|rrset| rrset.name == name && rrset.record_type == record.record_type())
+        {
+            Some(rrset) => rrset.records.push(record.clone()),
+            None => rrsets.push(Rrset {
+                name,
+                record_type: record.record_type(),
+                records: vec![record.clone()],
+                signatures: vec![],
+            }),
+        }
+    }
+
+    for record in records {
+        if let Some(sig) = rrsig(record) {
+            let name = record.name().to_lowercase();
+            if let Some(rrset) = rrsets
+                .iter_mut()
+                .find(|rrset| rrset.name == name && rrset.record_type == sig.type_covered())
+            {
+                rrset.signatures.push(sig.clone());
+            }
+        }
+    }
+    rrsets
+}
+
+fn rrsig(record: &Record) -> Option<&RRSIG> {
+    match record.data()? {
+        RData::DNSSEC(DNSSECRData::RRSIG(sig)) => Some(sig),
+        _ => None,
+    }
+}
+
+fn dnskey(record: &Record) -> Option<&DNSKEY> {
+    match record.data()? {
+        RData::DNSSEC(DNSSECRData::DNSKEY(key)) => Some(key),
+        _ => None,
+    }
+}
+
+fn ds(record: &Record) -> Option<&DS> {
+    match record.data()? {
+        RData::DNSSEC(DNSSECRData::DS(ds)) => Some(ds),
+        _ => None,
+    }
+}
+
+fn nsec(record: &Record) -> Option<&NSEC> {
+    match record.data()? {
+        RData::DNSSEC(DNSSECRData::NSEC(nsec)) => Some(nsec),
+        _ => None,
+    }
+}
+
+fn nsec3(record: &Record) -> Option<&NSEC3> {
+    match record.data()? {
+        RData::DNSSEC(DNSSECRData::NSEC3(nsec3)) => Some(nsec3),
+        _ => None,
+    }
+}
+
+// What a zone's verified DNSKEYs are, or that it is not signed from its parent
+#[derive(Clone)]
+enum ZoneKeys {
+    Secure(Vec<DNSKEY>),
+    Insecure,
+}
+
+// How a zone's parent vouches for it
+enum Delegation {
+    // The root, trusted through the anchors
+    Anchor,
+    // DS records at the parent and their signatures
+    Signed(Rrset),
+    // The parent's NSEC or NSEC3 records showing there is no DS
+    Denied { parent: Name, proof: Vec<Rrset> },
+}
+
+// A zone on the way up to the root, with what is needed to trust its keys
+struct ChainLink {
+    zone: Name,
+    keys: Rrset,
+    delegation: Delegation,
+}
+
+struct ChainValidator<'a> {
+    client: &'a MessageClient,
+    anchors: Vec<DS>,
+    zones: HashMap<Name, ZoneKeys>,
+    now: u32,
+    // Set when a signer's zone turned out to have no DS, an island of security
+    insecure_reason: Option<String>,
+}
+
+impl<'a> ChainValidator<'a> {
+    fn new(client: &'a MessageClient, anchors: Vec<DS>) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as u32);
+        Self {
+            client,
+            anchors,
+            zones: HashMap::new(),
+            now,
+            insecure_reason: None,
+        }
+    }
+
+    // Errors are the reason the records are bogus
+    async fn verify(&mut self, rrset: &Rrset) -> Result<(), String> {
+        let signer = rrset.signatures[0].signer_name().to_lowercase();
+        if !signer.zone_of(&rrset.name) {
+            return Err(format!("{} is signed by {}, which is not a parent zone", rrset.name, signer));
+        }
+        match self.zone_keys(&signer).await? {
+            ZoneKeys::Secure(keys) => verify_rrset(rrset, &keys, self.now),
+            ZoneKeys::Insecure => {
+                self.insecure_reason = Some(format!("{} is signed but its parent proves it has no DS", signer));
+                Ok(())
+            }
+        }
+    }
+
+    // Walks up from `zone` until a zone with known keys or the root, then
+    // verifies each link on the way back down
+    async fn zone_keys(&mut self, zone: &Name) -> Result<ZoneKeys, String> {
+        let mut chain: Vec<ChainLink> = Vec::new();
+        let mut next = Some(zone.clone());
+
+        while let Some(zone) = next.take() {
+            if self.zones.contains_key(&zone) {
+                break;
+            }
+
+            let (keys, _) = self.fetch(&zone, RecordType::DNSKEY).await?;
+            if zone.is_root() {
+                chain.push(ChainLink { zone, keys, delegation: Delegation::Anchor });
+                break;
+            }
+
+            let (delegation, authority) = self.fetch(&zone, RecordType::DS).await?;
+            if delegation.records.is_empty() {
+                // The server under test could just drop the DS, so an unsigned
+                // delegation only counts with the parent's signed denial
+                let proof: Vec<Rrset> = authority
+                    .into_iter()
+                    .filter(|rrset| matches!(rrset.record_type, RecordType::NSEC | RecordType::NSEC3))
+                    .collect();
+                let Some(parent) = proof
+                    .iter()
+                    .find_map(|rrset| rrset.signatures.first())
+                    .map(|sig| sig.signer_name().to_lowercase())
+                else {
+                    return Err(format!("{} has no DS and no signed proof that it doesn't exist", zone));
+                };
+                if parent == zone || !parent.zone_of(&zone) {
+                    return Err(format!(
+                        "The denial of a DS for {} is signed by {}, which is not a parent zone",
+                        zone, parent
+                    ));
+                }
+                next = Some(parent.clone());
+                chain.push(ChainLink { zone, keys, delegation: Delegation::Denied { parent, proof } });
+                continue;
+            }
+            let Some(parent) = delegation.signatures.first().map(|sig| sig.signer_name().to_lowercase()) else {
+                return Err(format!("DS records of {} are not signed", zone));
+            };
+            if parent == zone || !parent.zone_of(&zone) {
+                return Err(format!("DS records of {} are signed by {}, which is not a parent zone", zone, parent));
+            }
+            next = Some(parent);
+            chain.push(ChainLink { zone, keys, delegation: Delegation::Signed(delegation) });
+        }
+
+        while let Some(link) = chain.pop() {
+            if self.zones.contains_key(&link.zone) {
+                continue;
+            }
+            let trusted = match &link.delegation {
+                Delegation::Anchor => self.anchors.clone(),
+                Delegation::Signed(delegation) => {
+                    let parent = delegation.signatures[0].signer_name().to_lowercase();
+                    match self.zones.get(&parent).cloned() {
+                        Some(ZoneKeys::Secure(parent_keys)) => {
+                            verify_rrset(delegation, &parent_keys, self.now)?;
+                            delegation.records.iter().filter_map(ds).cloned().collect()
+                        }
+                        // Below an unsigned delegation nothing is secure
+                        _ => {
+                            self.zones.insert(link.zone.clone(), ZoneKeys::Insecure);
+                            continue;
+                        }
+                    }
+                }
+                Delegation::Denied { parent, proof } => {
+                    if let Some(ZoneKeys::Secure(parent_keys)) = self.zones.get(parent) {
+                        verify_no_ds(&link.zone, parent, proof, parent_keys, self.now)?;
+                    }
+                    self.zones.insert(link.zone.clone(), ZoneKeys::Insecure);
+                    continue;
+                }
+            };
+            let keys = trusted_keys(&link, &trusted, self.now)?;
+            self.zones.insert(link.zone, ZoneKeys::Secure(keys));
+        }
+
+        self.zones
+            .get(zone)
+            .cloned()
+            .ok_or_else(|| format!("Could not build a chain of trust for {}", zone))
+    }
+
+    // The RRset, empty if there is none, and the authority section's RRsets
+    async fn fetch(&self, name: &Name, record_type: RecordType) -> Result<(Rrset, Vec<Rrset>), String> {
+        let answer = self
+            .client
+            .send(dnssec_query(name, record_type))
+            .await
+            .map_err(|e| format!("Could not fetch {} {}: {}", name, record_type, e))?;
+        let rrset = rrsets(answer.answers())
+            .into_iter()
+            .find(|rrset| rrset.name == *name && rrset.record_type == record_type);
+        let rrset = rrset.unwrap_or(Rrset {
+            name: name.clone(),
+            record_type,
+            records: vec![],
+            signatures: vec![],
+        });
+        Ok((rrset, rrsets(answer.name_servers())))
+    }
+}
+
+// The zone's DNSKEYs, once one matching a trusted DS has signed them all
+fn trusted_keys(link: &ChainLink, trusted: &[DS], now: u32) -> Result<Vec<DNSKEY>, String> {
+    let keys: Vec<DNSKEY> = link.keys.records.iter().filter_map(dnskey).cloned().collect();
+    if keys.is_empty() {
+        return Err(format!("{} has a DS but no DNSKEY records", link.zone));
+    }
+
+    let entry_points: Vec<DNSKEY> = keys
+        .iter()
+        .filter(|key| trusted.iter().any(|ds| ds.covers(&link.zone, key).unwrap_or(false)))
+        .cloned()
+        .collect();
+    if entry_points.is_empty() {
+        return Err(format!("No DNSKEY of {} matches the DS at its parent", link.zone));
+    }
+
+    verify_rrset(&link.keys, &entry_points, now)?;
+    Ok(keys)
+}
+
+// Checks that `parent` signed a denial of any DS for `zone` (RFC 4035 section
+// 5.2, RFC 5155 section 8.9): an NSEC or NSEC3 at the delegation listing NS but
+// neither DS nor SOA, which would be the child's side of the cut, or an opt-out
+// NSEC3 covering the delegation next to the NSEC3 of its closest encloser
+fn verify_no_ds(zone: &Name, parent: &Name, proof: &[Rrset], parent_keys: &[DNSKEY], now: u32) -> Result<(), String> {
+    let is_delegation = |types: &[RecordType]| {
+        types.contains(&RecordType::NS) && !types.contains(&RecordType::DS) && !types.contains(&RecordType::SOA)
+    };
+
+    for rrset in proof.iter().filter(|rrset| rrset.name == *zone) {
+        if let Some(record) = rrset.records.iter().find_map(nsec) {
+            if !is_delegation(record.type_bit_maps()) {
+                return Err(format!("The NSEC record of {} does not show a delegation without DS", zone));
+            }
+            return verify_rrset(rrset, parent_keys, now);
+        }
+    }
+
+    // Hashed owner label and record of every NSEC3 in the parent zone
+    let hashed: Vec<(String, &NSEC3, &Rrset)> = proof
+        .iter()
+        .filter(|rrset| rrset.name.base_name() == *parent)
+        .filter_map(|rrset| {
+            let label = String::from_utf8_lossy(rrset.name.iter().next()?).to_lowercase();
+            Some((label, rrset.records.iter().find_map(nsec3)?, rrset))
+        })
+        .collect();
+    let Some(&(_, params, _)) = hashed.first() else {
+        return Err(format!("No NSEC or NSEC3 record proves that {} has no DS", zone));
+    };
+    let hash = |name: &Name| {
+        params
+            .hash_algorithm()
+            .hash(params.salt(), name, params.iterations())
+            .map(|digest| base32hex(digest.as_ref()))
+            .map_err(|e| format!("Could not hash {}: {}", name, e))
+    };
+    let matching = |owner: &str| hashed.iter().find(|(label, _, _)| label == owner);
+
+    let zone_hash = hash(zone)?;
+    if let Some((_, record, rrset)) = matching(&zone_hash) {
+        if !is_delegation(record.type_bit_maps()) {
+            return Err(format!("The NSEC3 record of {} does not show a delegation without DS", zone));
+        }
+        return verify_rrset(rrset, parent_keys, now);
+    }
+
+    // Opt-out: the closest existing ancestor is proven, and the name one label
+    // below it falls in an NSEC3 span whose delegations may be unsigned
+    let mut encloser = zone.base_name();
+    while encloser.num_labels() >= parent.num_labels() {
+        if let Some((_, _, encloser_rrset)) = matching(&hash(&encloser)?) {
+            let next_closer = zone.trim_to(encloser.num_labels() as usize + 1);
+            let next_hash = hash(&next_closer)?;
+            let covering = hashed.iter().find(|(owner, record, _)| {
+                let next = base32hex(record.next_hashed_owner_name());
+                match owner.as_str() < next.as_str() {
+                    true => owner.as_str() < next_hash.as_str() && next_hash < next,
+                    // The last NSEC3 wraps around to the first
+                    false => owner.as_str() < next_hash.as_str() || next_hash < next,
+                }
+            });
+            return match covering {
+                Some((_, record, rrset)) if record.opt_out() => {
+                    verify_rrset(encloser_rrset, parent_keys, now)?;
+                    verify_rrset(rrset, parent_keys, now)
+                }
+                Some(_) => Err(format!("The NSEC3 record covering {} has no opt-out flag", next_closer)),
+                None => Err(format!("No NSEC3 record covers {}", next_closer)),
+            };
+        }
+        encloser = encloser.base_name();
+    }
+    Err(format!("No NSEC3 record proves that {} has no DS", zone))
+}
+
+// RFC 4648 base32 with the extended hex alphabet, lowercase and unpadded, as
+// NSEC3 owner names are written
+fn base32hex(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+    let mut text = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer.iter().fold(0u64, |bits, byte| bits << 8 | u64::from(*byte));
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            text.push(ALPHABET[(bits >> (35 - i * 5) & 31) as usize] as char);
+        }
+    }
+    text
+}
+
+fn verify_rrset(rrset: &Rrset, keys: &[DNSKEY], now: u32) -> Result<(), String> {
+    let mut reason = format!("{} {} has no signature by a key of its zone", rrset.name, rrset.record_type);
+    for sig in &rrset.signatures {
+        // Serial number arithmetic (RFC 4034): the window may wrap around 2106
+        let started = now.wrapping_sub(sig.sig_inception()) as i32 >= 0;
+        let expired = sig.sig_expiration().wrapping_sub(now) as i32 <= 0;
+        if !started || expired {
+            reason = format!("Signature of {} {} is outside its validity period", rrset.name, rrset.record_type);
+            continue;
+        }
+
+        let candidates = keys.iter().filter(|key| {
+            key.zone_key()
+                && !key.revoke()
+                && key.algorithm() == sig.algorithm()
+                && key.calculate_key_tag().ok() == Some(sig.key_tag())
+        });
+        for key in candidates {
+            match key.verify_rrsig(&rrset.name, DNSClass::IN, sig, &rrset.records) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    reason = format!(
+                        "Signature of {} {} by key {} does not verify: {}",
+                        rrset.name,
+                        rrset.record_type,
+                        sig.key_tag(),
+                        e
+                    )
+                }
+            }
+        }
+    }
+    Err(reason)
+}
+
+fn root_anchors() -> Vec<DS> {
+    ROOT_ANCHORS
+        .iter()
+        .map(|&(key_tag, algorithm, digest)| {
+            let digest = (0..digest.len())
+                .step_by(2)
+                .filter_map(|i| u8::from_str_radix(&digest[i..i + 2], 16).ok())
+                .collect();
+            DS::new(key_tag, algorithm, DigestType::SHA256, digest)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use trust_dns_resolver::proto::rr::dnssec::{tbs, KeyFormat, KeyPair, Nsec3HashAlgorithm, Private};
+    use trust_dns_resolver::proto::rr::rdata::A;
+    use crate::test_support::scripted_dns_server;
+
+    // A signed zone of the fixture tree, with an Ed25519 key of its own
+    struct Zone {
+        name: Name,
+        key: KeyPair<Private>,
+        dnskey: DNSKEY,
+    }
+
+    impl Zone {
+        fn new(name: &str) -> Self {
+            let pkcs8 = KeyPair::<Private>::generate_pkcs8(Algorithm::ED25519).unwrap();
+            let key = KeyFormat::Pkcs8.decode_key(&pkcs8, None, Algorithm::ED25519).unwrap();
+            let dnskey = key.to_dnskey(Algorithm::ED25519).unwrap();
+            Self {
+                name: Name::from_ascii(name).unwrap(),
+                key,
+                dnskey,
+            }
+        }
+
+        fn ds(&self) -> DS {
+            let digest = self.dnskey.to_digest(&self.name, DigestType::SHA256).unwrap();
+            DS::new(
+                self.dnskey.calculate_key_tag().unwrap(),
+                Algorithm::ED25519,
+                DigestType::SHA256,
+                digest.as_ref().to_vec(),
+            )
+        }
+
+        // `records` followed by this zone's signature over them
+        fn signed(&self, records: Vec<Record>) -> Vec<Record> {
+            let first = &records[0];
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+            let (inception, expiration) = (now - 3600, now + 3600);
+            let key_tag = self.dnskey.calculate_key_tag().unwrap();
+            let labels = first.name().num_labels();
+            let data = tbs::rrset_tbs(
+                first.name(),
+                DNSClass::IN,
+                labels,
+                first.record_type(),
+                Algorithm::ED25519,
+                first.ttl(),
+                expiration,
+                inception,
+                key_tag,
+                &self.name,
+                &records,
+            )
+            .unwrap();
+            let signature = self.key.sign(Algorithm::ED25519, &data).unwrap();
+            let rrsig = RRSIG::new(
+                first.record_type(),
+                Algorithm::ED25519,
+                labels,
+                first.ttl(),
+                expiration,
+                inception,
+                key_tag,
+                self.name.clone(),
+                signature,
+            );
+            let rdata = RData::DNSSEC(DNSSECRData::RRSIG(rrsig));
+            let sig_record = Record::from_rdata(first.name().clone(), first.ttl(), rdata);
+            records.into_iter().chain([sig_record]).collect()
+        }
+    }
+
+    fn record(name: &str, data: RData) -> Record {
+        Record::from_rdata(Name::from_ascii(name).unwrap(), 300, data)
+    }
+
+    fn address(name: &str) -> Record {
+        record(name, RData::A(A(Ipv4Addr::new(192, 0, 2, 1))))
+    }
+
+    fn nsec3_hash(name: &str, salt: &[u8]) -> Vec<u8> {
+        let digest = Nsec3HashAlgorithm::SHA1.hash(salt, &Name::from_ascii(name).unwrap(), 5).unwrap();
+        digest.as_ref().to_vec()
+    }
+
+    // What the fixture server answers: records for a name and type, and the
+    // authority section sent along with them
+    #[derive(Default)]
+    struct Fixture {
+        answers: HashMap<(Name, RecordType), Vec<Record>>,
+        authority: HashMap<(Name, RecordType), Vec<Record>>,
+        anchors: Vec<DS>,
+    }
+
+    impl Fixture {
+        fn answer(&mut self, records: Vec<Record>) {
+            let key = (records[0].name().to_lowercase(), records[0].record_type());
+            self.answers.insert(key, records);
+        }
+
+        fn keys(&mut self, zone: &Zone) {
+            let rdata = RData::DNSSEC(DNSSECRData::DNSKEY(zone.dnskey.clone()));
+            let dnskey = Record::from_rdata(zone.name.clone(), 300, rdata);
+            self.answer(zone.signed(vec![dnskey]));
+        }
+
+        fn delegate(&mut self, parent: &Zone, child: &Zone) {
+            let ds = Record::from_rdata(child.name.clone(), 300, RData::DNSSEC(DNSSECRData::DS(child.ds())));
+            self.answer(parent.signed(vec![ds]));
+        }
+
+        fn deny_ds(&mut self, zone: &str, proof: Vec<Record>) {
+            self.authority.insert((Name::from_ascii(zone).unwrap(), RecordType::DS), proof);
+        }
+
+        async fn check(self, host: &str) -> DnssecCheck {
+            let anchors = self.anchors.clone();
+            let fixture = Arc::new(self);
+            let server = scripted_dns_server(move |query| {
+                let mut response = query.clone();
+                response.set_message_type(MessageType::Response);
+                if let Some(question) = query.queries().first() {
+                    let key = (question.name().to_lowercase(), question.query_type());
+                    response.add_answers(fixture.answers.get(&key).cloned().unwrap_or_default());
+                    response.add_name_servers(fixture.authority.get(&key).cloned().unwrap_or_default());
+                }
+                response
+            })
+            .await;
+            check_dnssec_from(host, &server, None, anchors).await.unwrap()
+        }
+    }
+
+    // The root and `test.` signed, with `secure.test.` signed below it and
+    // `www.secure.test.` answered
+    fn secure_tree() -> (Fixture, Zone) {
+        let root = Zone::new(".");
+        let tld = Zone::new("test.");
+        let secure = Zone::new("secure.test.");
+        let mut fixture = Fixture {
+            anchors: vec![root.ds()],
+            ..Default::default()
+        };
+        fixture.keys(&root);
+        fixture.keys(&tld);
+        fixture.keys(&secure);
+        fixture.delegate(&root, &tld);
+        fixture.delegate(&tld, &secure);
+        fixture.answer(secure.signed(vec![address("www.secure.test.")]));
+        (fixture, tld)
+    }
+
+    #[tokio::test]
+    async fn signed_chain_validates() {
+        let (fixture, _) = secure_tree();
+        let check = fixture.check("www.secure.test").await;
+        assert_eq!(check.status, DnssecStatus::Validated, "{:?}", check.reason);
+    }
+
+    #[tokio::test]
+    async fn tampered_answer_is_bogus() {
+        let (mut fixture, _) = secure_tree();
+        let secure = Zone::new("secure.test.");
+        let mut records = secure.signed(vec![address("www.secure.test.")]);
+        records[0] = record("www.secure.test.", RData::A(A(Ipv4Addr::new(10, 10, 34, 35))));
+        fixture.answer(records);
+        assert_eq!(fixture.check("www.secure.test").await.status, DnssecStatus::Bogus);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_ds_does_not_make_a_forgery_insecure() {
+        let (mut fixture, _) = secure_tree();
+        // The server swaps in keys of its own and leaves out the DS that would expose them
+        let forged = Zone::new("secure.test.");
+        fixture.keys(&forged);
+        fixture.answers.remove(&(Name::from_ascii("secure.test.").unwrap(), RecordType::DS));
+        fixture.answer(forged.signed(vec![address("www.secure.test.")]));
+        let check = fixture.check("www.secure.test").await;
+        assert_eq!(check.status, DnssecStatus::Bogus);
+        assert!(check.reason.unwrap().contains("no signed proof"));
+    }
+
+    // The signed NSEC of the `island.test.` delegation in `test.`
+    fn island_nsec(tld: &Zone, types: Vec<RecordType>) -> Vec<Record> {
+        let nsec = NSEC::new(Name::from_ascii("secure.test.").unwrap(), types);
+        tld.signed(vec![record("island.test.", RData::DNSSEC(DNSSECRData::NSEC(nsec)))])
+    }
+
+    #[tokio::test]
+    async fn nsec_proves_an_unsigned_delegation() {
+        let (mut fixture, tld) = secure_tree();
+        let island = Zone::new("island.test.");
+        fixture.keys(&island);
+        fixture.answer(island.signed(vec![address("www.island.test.")]));
+        fixture.deny_ds("island.test.", island_nsec(&tld, vec![RecordType::NS, RecordType::RRSIG, RecordType::NSEC]));
+
+        let check = fixture.check("www.island.test").await;
+        assert_eq!(check.status, DnssecStatus::Insecure, "{:?}", check.reason);
+    }
+
+    #[tokio::test]
+    async fn nsec_listing_a_ds_is_bogus() {
+        let (mut fixture, tld) = secure_tree();
+        let island = Zone::new("island.test.");
+        fixture.keys(&island);
+        fixture.answer(island.signed(vec![address("www.island.test.")]));
+        fixture.deny_ds("island.test.", island_nsec(&tld, vec![RecordType::NS, RecordType::DS, RecordType::RRSIG]));
+
+        assert_eq!(fixture.check("www.island.test").await.status, DnssecStatus::Bogus);
+    }
+
+    #[tokio::test]
+    async fn nsec3_proves_unsigned_and_opt_out_delegations() {
+        let salt = vec![0xab, 0xcd];
+        // An NSEC3 of `zone` from the hash `owner` to the hash `next`
+        let nsec3 = |zone: &Zone, owner: &[u8], opt_out: bool, next: Vec<u8>, types: Vec<RecordType>| {
+            let data = NSEC3::new(Nsec3HashAlgorithm::SHA1, opt_out, 5, salt.clone(), next, types);
+            let name = format!("{}.{}", base32hex(owner), zone.name);
+            zone.signed(vec![record(&name, RData::DNSSEC(DNSSECRData::NSEC3(data)))])
+        };
+
+        for (host, opt_out, expected) in [
+            ("www.island.test", false, DnssecStatus::Insecure),
+            ("www.unlisted.test", true, DnssecStatus::Insecure),
+            ("www.unlisted.test", false, DnssecStatus::Bogus),
+        ] {
+            let (mut fixture, tld) = secure_tree();
+            let zone_name = host.trim_start_matches("www.").to_string() + ".";
+            let child = Zone::new(&zone_name);
+            fixture.keys(&child);
+            fixture.answer(child.signed(vec![address(&format!("{}.", host))]));
+
+            let proof = if zone_name == "island.test." {
+                nsec3(&tld, &nsec3_hash("island.test.", &salt), false, vec![0; 20], vec![RecordType::NS])
+            } else {
+                // The apex of test. and a span from it around to itself, which
+                // covers every other hash
+                let apex = nsec3_hash("test.", &salt);
+                nsec3(&tld, &apex, opt_out, apex.clone(), vec![RecordType::SOA, RecordType::NS])
+            };
+            fixture.deny_ds(&zone_name, proof);
+
+            let check = fixture.check(host).await;
+            assert_eq!(check.status, expected, "{}: {:?}", host, check.reason);
+        }
+    }
+
+    #[test]
+    fn base32hex_matches_rfc_4648() {
+        assert_eq!(base32hex(b"f"), "co");
+        assert_eq!(base32hex(b"foobar"), "cpnmuoj1e8");
+    }
+}
@@ -0,0 +1,234 @@
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use crate::errors::TestError;
+
+// RFC 8305 recommends 250ms before starting the next connection attempt
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AddressAttempt {
+    pub address: IpAddr,
+    pub success: bool,
+    pub error: Option<String>,
+    pub error_kind: Option<TestError>,
+}
+
+// Alternates address families, keeping the resolver's order within each family
+pub fn interleave_families(ips: impl IntoIterator<Item = IpAddr>) -> Vec<IpAddr> {
+    let (mut first, mut second): (Vec<IpAddr>, Vec<IpAddr>) = (vec![], vec![]);
+    let mut first_is_v4 = None;
+
+    for ip in ips {
+        let is_v4 = *first_is_v4.get_or_insert(ip.is_ipv4());
+        if ip.is_ipv4() == is_v4 {
+            first.push(ip);
+        } else {
+            second.push(ip);
+        }
+    }
+
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
+
+/// Races TCP connections to `port` on `addresses` (RFC 8305) and returns the
+/// address that connected first. Only the connection is raced, the caller
+/// sends its one request to the winner; the raced connections are closed.
+pub async fn race_connect(
+    addresses: Vec<IpAddr>,
+    port: u16,
+    timeout: Duration,
+) -> (Option<IpAddr>, Vec<AddressAttempt>) {
+    let (winner, attempts) = race_addresses(addresses, |ip| async move {
+        match tokio::time::timeout(timeout, TcpStream::connect(SocketAddr::new(ip, port))).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(TestError::from_connect_error(&e).into()),
+            Err(_) => Err(TestError::ConnectTimeout.into()),
+        }
+    })
+    .await;
+    (winner.map(|(ip, ())| ip), attempts)
+}
+
+// The winner connected but its request failed, which is what it is reported with
+pub fn request_failed(attempts: &mut [AddressAttempt], address: IpAddr, error: &anyhow::Error) {
+    if let Some(attempt) = attempts.iter_mut().rev().find(|attempt| attempt.address == address) {
+        attempt.success = false;
+        attempt.error = Some(format!("{:#}", error));
+        attempt.error_kind = Some(TestError::classify(error));
+    }
+}
+
+// Happy-eyeballs style race over `addresses`: the next attempt starts when the
+// previous one fails or after ATTEMPT_DELAY, whichever comes first, and the
+// first attempt to succeed wins. Attempts still running at that point are dropped.
+async fn race_addresses<T, F, Fut>(
+    addresses: Vec<IpAddr>,
+    attempt: F,
+) -> (Option<(IpAddr, T)>, Vec<AddressAttempt>)
+where
+    F: Fn(IpAddr) -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let launch = |ip: IpAddr| {
+        let fut = attempt(ip);
+        async move { (ip, fut.await) }
+    };
+
+    let mut queue = addresses.into_iter();
+    let mut running = FuturesUnordered::new();
+    let mut attempts = Vec::new();
+
+    running.extend(queue.next().map(&launch));
+
+    while !running.is_empty() {
+        tokio::select! {
+            Some((ip, result)) = running.next() => match result {
+                Ok(value) => {
+                    attempts.push(AddressAttempt { address: ip, success: true, error: None, error_kind: None });
+                    return (Some((ip, value)), attempts);
+                }
+                Err(e) => {
+                    println!("Attempt via {} failed: {}", ip, e);
+                    attempts.push(AddressAttempt {
+                        address: ip,
+                        success: false,
+                        error: Some(format!("{:#}", e)),
+                        error_kind: Some(TestError::classify(&e)),
+                    });
+                    running.extend(queue.next().map(&launch));
+                }
+            },
+            _ = tokio::time::sleep(ATTEMPT_DELAY) => {
+                running.extend(queue.next().map(&launch));
+            }
+        }
+    }
+
+    (None, attempts)
+}
+
+// Summarises why every address failed, for the result's error message
+pub fn describe_failed_attempts(attempts: &[AddressAttempt]) -> String {
+    attempts
+        .iter()
+        .map(|attempt| format!("{}: {}", attempt.address, attempt.error.as_deref().unwrap_or("failed")))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+// What the failed race as a whole is reported as: the last attempt's failure
+pub fn failed_attempts_error(attempts: &[AddressAttempt]) -> TestError {
+    attempts
+        .iter()
+        .rev()
+        .find_map(|attempt| attempt.error_kind.clone())
+        .unwrap_or_else(|| TestError::Other("no address to connect to".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    fn ips(addresses: &[&str]) -> Vec<IpAddr> {
+        addresses.iter().map(|address| address.parse().unwrap()).collect()
+    }
+
+    // A port on 127.0.0.1 only, so the same port on 127.0.0.2 and up refuses connections
+    async fn listening_port() -> (TcpListener, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    #[test]
+    fn alternates_families_starting_with_the_first_answer() {
+        assert_eq!(
+            interleave_families(ips(&["1.1.1.1", "1.0.0.1", "2606:4700::1111", "8.8.8.8", "2606:4700::1001"])),
+            ips(&["1.1.1.1", "2606:4700::1111", "1.0.0.1", "2606:4700::1001", "8.8.8.8"])
+        );
+        assert_eq!(
+            interleave_families(ips(&["2606:4700::1111", "1.1.1.1", "1.0.0.1"])),
+            ips(&["2606:4700::1111", "1.1.1.1", "1.0.0.1"])
+        );
+        assert_eq!(interleave_families(ips(&["1.1.1.1", "1.0.0.1"])), ips(&["1.1.1.1", "1.0.0.1"]));
+        assert!(interleave_families(Vec::<IpAddr>::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn falls_back_as_soon_as_a_connection_is_refused() {
+        let (_listener, port) = listening_port().await;
+        let started = Instant::now();
+        let (winner, attempts) = race_connect(ips(&["127.0.0.2", "127.0.0.1"]), port, Duration::from_secs(5)).await;
+
+        assert_eq!(winner, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        // The refusal starts the next attempt, without waiting out ATTEMPT_DELAY
+        assert!(started.elapsed() < ATTEMPT_DELAY, "{:?}", started.elapsed());
+        assert_eq!(attempts.len(), 2);
+        assert!(!attempts[0].success);
+        assert_eq!(attempts[0].error_kind, Some(TestError::ConnectionRefused));
+        assert!(attempts[1].success);
+        assert_eq!(attempts[1].error, None);
+    }
+
+    #[tokio::test]
+    async fn starts_the_next_attempt_after_the_delay() {
+        let started = Instant::now();
+        let (winner, attempts) = race_addresses(ips(&["192.0.2.1", "192.0.2.2"]), |ip| async move {
+            if ip == IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)) {
+                futures::future::pending::<()>().await;
+            }
+            anyhow::Ok(ip)
+        })
+        .await;
+
+        assert_eq!(winner.map(|(ip, _)| ip), Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))));
+        assert!(started.elapsed() >= ATTEMPT_DELAY);
+        // The hanging attempt was dropped, not reported
+        assert_eq!(attempts.len(), 1);
+        assert!(attempts[0].success);
+    }
+
+    #[tokio::test]
+    async fn collects_every_failure_when_no_address_connects() {
+        let (_listener, port) = listening_port().await;
+        let (winner, attempts) = race_connect(ips(&["127.0.0.2", "127.0.0.3"]), port, Duration::from_secs(5)).await;
+
+        assert_eq!(winner, None);
+        let failed: Vec<IpAddr> = attempts.iter().filter(|attempt| !attempt.success).map(|attempt| attempt.address).collect();
+        assert_eq!(failed, ips(&["127.0.0.2", "127.0.0.3"]));
+        assert_eq!(failed_attempts_error(&attempts), TestError::ConnectionRefused);
+        assert_eq!(
+            describe_failed_attempts(&attempts),
+            "127.0.0.2: connection refused; 127.0.0.3: connection refused"
+        );
+
+        assert_eq!(
+            failed_attempts_error(&[]),
+            TestError::Other("no address to connect to".to_string())
+        );
+    }
+
+    #[test]
+    fn a_failed_request_is_reported_on_the_winner() {
+        let winner = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut attempts = vec![AddressAttempt { address: winner, success: true, error: None, error_kind: None }];
+        request_failed(&mut attempts, winner, &TestError::HttpTimeout.into());
+        assert!(!attempts[0].success);
+        assert_eq!(attempts[0].error_kind, Some(TestError::HttpTimeout));
+    }
+}
@@ -0,0 +1,160 @@
+use std::io::Read;
+use std::net::IpAddr;
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+
+// iptoasn.com's ip2asn-combined.tsv.gz (public domain), put in place by
+// scripts/update-ip-database.sh and embedded by build.rs. Builds without it get
+// an empty file and tag addresses from the CDN ranges alone.
+const BUNDLED_DATABASE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/ip2asn-combined.tsv.gz"));
+
+lazy_static::lazy_static! {
+    // Decompressed and parsed on the first lookup, it takes a moment
+    static ref BUNDLED: IpDatabase = IpDatabase::bundled();
+}
+
+/// The routed network an address belongs to.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AsnInfo {
+    pub asn: u32,
+    /// Where the network is registered, as a two-letter code
+    pub country: Option<String>,
+    /// The AS description, e.g. `CLOUDFLARENET - Cloudflare, Inc.`
+    pub name: String,
+}
+
+/// Address ranges with the network announcing them, in the ip2asn TSV
+/// format: first address, last address, ASN, country, description.
+#[derive(Debug, Default)]
+pub struct IpDatabase {
+    v4: Vec<(u32, u32, usize)>,
+    v6: Vec<(u128, u128, usize)>,
+    networks: Vec<AsnInfo>,
+}
+
+impl IpDatabase {
+    pub fn from_tsv(content: &str) -> anyhow::Result<Self> {
+        let mut database = Self::default();
+        for (number, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [start, end, asn, country, name] = fields[..] else {
+                anyhow::bail!("Line {} of the IP database has {} fields, not 5", number + 1, fields.len());
+            };
+            let asn: u32 = asn.trim().parse()?;
+            // ASN 0 marks ranges nobody announces
+            if asn == 0 {
+                continue;
+            }
+            let index = database.networks.len();
+            database.networks.push(AsnInfo {
+                asn,
+                country: Some(country.trim()).filter(|country| country.len() == 2).map(str::to_string),
+                name: name.trim().to_string(),
+            });
+            match (start.trim().parse()?, end.trim().parse()?) {
+                (IpAddr::V4(start), IpAddr::V4(end)) => database.v4.push((start.into(), end.into(), index)),
+                (IpAddr::V6(start), IpAddr::V6(end)) => database.v6.push((start.into(), end.into(), index)),
+                _ => anyhow::bail!("Line {} of the IP database mixes IPv4 and IPv6", number + 1),
+            }
+        }
+        // The file comes sorted, but lookups depend on it
+        database.v4.sort_unstable_by_key(|range| range.0);
+        database.v6.sort_unstable_by_key(|range| range.0);
+        Ok(database)
+    }
+
+    /// The gzipped TSV, as iptoasn.com publishes it.
+    pub fn from_gzip(compressed: &[u8]) -> anyhow::Result<Self> {
+        let mut content = String::new();
+        GzDecoder::new(compressed).read_to_string(&mut content)?;
+        Self::from_tsv(&content)
+    }
+
+    fn bundled() -> Self {
+        if BUNDLED_DATABASE.is_empty() {
+            println!("No IP database was bundled, run scripts/update-ip-database.sh before building");
+            return Self::default();
+        }
+        Self::from_gzip(BUNDLED_DATABASE).unwrap_or_else(|e| {
+            eprintln!("Failed to read the bundled IP database: {:#}", e);
+            Self::default()
+        })
+    }
+
+    pub fn lookup(&self, ip: &IpAddr) -> Option<&AsnInfo> {
+        let index = match ip {
+            IpAddr::V4(ip) => find(&self.v4, u32::from(*ip)),
+            IpAddr::V6(ip) => find(&self.v6, u128::from(*ip)),
+        }?;
+        self.networks.get(index)
+    }
+}
+
+// The range holding `ip`: the last one starting at or before it, if it reaches that far
+fn find<T: Ord + Copy>(ranges: &[(T, T, usize)], ip: T) -> Option<usize> {
+    let after = ranges.partition_point(|range| range.0 <= ip);
+    let (_, end, index) = ranges.get(after.checked_sub(1)?)?;
+    (ip <= *end).then_some(*index)
+}
+
+/// Looks `ip` up in the database bundled with the app.
+pub fn lookup_asn(ip: &IpAddr) -> Option<AsnInfo> {
+    BUNDLED.lookup(ip).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // In the ip2asn-combined.tsv format, unrouted ranges included
+    const SAMPLE: &str = "1.1.1.0\t1.1.1.255\t13335\tUS\tCLOUDFLARENET - Cloudflare, Inc.
+1.1.2.0\t1.1.3.255\t0\tNone\tNot routed
+8.8.4.0\t8.8.8.255\t15169\tUS\tGOOGLE - Google LLC
+5.160.0.0\t5.160.127.255\t64500\tIR\tEXAMPLE-AS Example network
+2001:4860::\t2001:4860:ffff:ffff:ffff:ffff:ffff:ffff\t15169\tUS\tGOOGLE - Google LLC
+";
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn finds_the_range_an_address_is_in() {
+        let database = IpDatabase::from_tsv(SAMPLE).unwrap();
+        let cloudflare = database.lookup(&ip("1.1.1.1")).unwrap();
+        assert_eq!(cloudflare.asn, 13335);
+        assert_eq!(cloudflare.country.as_deref(), Some("US"));
+        assert_eq!(cloudflare.name, "CLOUDFLARENET - Cloudflare, Inc.");
+
+        assert_eq!(database.lookup(&ip("8.8.8.255")).unwrap().asn, 15169);
+        assert_eq!(database.lookup(&ip("5.160.20.1")).unwrap().country.as_deref(), Some("IR"));
+        assert_eq!(database.lookup(&ip("2001:4860:4860::8888")).unwrap().asn, 15169);
+    }
+
+    #[test]
+    fn gaps_and_unrouted_ranges_are_unknown() {
+        let database = IpDatabase::from_tsv(SAMPLE).unwrap();
+        assert_eq!(database.lookup(&ip("1.1.2.1")), None);
+        assert_eq!(database.lookup(&ip("1.1.0.255")), None);
+        assert_eq!(database.lookup(&ip("5.161.0.0")), None);
+        assert_eq!(database.lookup(&ip("2001:4861::1")), None);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(IpDatabase::from_tsv("1.0.0.0\t1.0.0.255\t13335\n").is_err());
+        assert!(IpDatabase::from_tsv("1.0.0.0\t::1\t13335\tUS\tX\n").is_err());
+        assert!(IpDatabase::from_tsv("1.0.0.0\t1.0.0.255\tAS13335\tUS\tX\n").is_err());
+    }
+
+    #[test]
+    fn reads_the_gzipped_format() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(SAMPLE.as_bytes()).unwrap();
+        let database = IpDatabase::from_gzip(&encoder.finish().unwrap()).unwrap();
+        assert_eq!(database.lookup(&ip("1.1.1.1")).unwrap().asn, 13335);
+        assert!(IpDatabase::from_gzip(SAMPLE.as_bytes()).is_err());
+    }
+}
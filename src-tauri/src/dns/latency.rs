@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+
+// Upper bound on rounds per server so a typo can't keep a test running for minutes
+pub const MAX_ROUNDS: u32 = 20;
+
+/// How many rounds to run for `rounds` as asked for: at least one, at most `MAX_ROUNDS`.
+pub fn round_count(rounds: u32) -> u32 {
+    rounds.clamp(1, MAX_ROUNDS)
+}
+
+/// Latency over several rounds, in milliseconds. Rounds that got no answer
+/// count towards `loss_rate` only.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct LatencyStats {
+    pub rounds: u32,
+    pub lost: u32,
+    pub loss_rate: f64,
+    pub min: Option<u64>,
+    pub median: Option<u64>,
+    pub p95: Option<u64>,
+    pub max: Option<u64>,
+    pub mean: Option<f64>,
+    /// Standard deviation of the answered rounds
+    pub jitter: Option<f64>,
+}
+
+impl LatencyStats {
+    pub fn from_samples(samples: &[Option<u64>]) -> Self {
+        let mut answered: Vec<u64> = samples.iter().flatten().copied().collect();
+        answered.sort_unstable();
+
+        let rounds = samples.len() as u32;
+        let lost = rounds - answered.len() as u32;
+        let mut stats = Self {
+            rounds,
+            lost,
+            loss_rate: if rounds > 0 { lost as f64 / rounds as f64 } else { 0.0 },
+            ..Self::default()
+        };
+        if answered.is_empty() {
+            return stats;
+        }
+
+        let count = answered.len() as f64;
+        let mean = answered.iter().sum::<u64>() as f64 / count;
+        let variance = answered.iter().map(|&ms| (ms as f64 - mean).powi(2)).sum::<f64>() / count;
+
+        stats.min = answered.first().copied();
+        stats.max = answered.last().copied();
+        stats.median = Some(percentile(&answered, 50.0));
+        stats.p95 = Some(percentile(&answered, 95.0));
+        stats.mean = Some(mean);
+        stats.jitter = Some(variance.sqrt());
+        stats
+    }
+}
+
+// Nearest-rank percentile of sorted, non-empty samples
+fn percentile(sorted: &[u64], percent: f64) -> u64 {
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        let samples: Vec<Option<u64>> = (1..=20).rev().map(|ms| Some(ms * 10)).collect();
+        let stats = LatencyStats::from_samples(&samples);
+        assert_eq!(stats.min, Some(10));
+        assert_eq!(stats.max, Some(200));
+        // Rank ceil(0.5 * 20) = 10 and ceil(0.95 * 20) = 19
+        assert_eq!(stats.median, Some(100));
+        assert_eq!(stats.p95, Some(190));
+
+        let stats = LatencyStats::from_samples(&[Some(30), Some(10), Some(20)]);
+        assert_eq!(stats.median, Some(20));
+        assert_eq!(stats.p95, Some(30));
+    }
+
+    #[test]
+    fn jitter_is_the_standard_deviation() {
+        let stats = LatencyStats::from_samples(&[Some(2), Some(4), Some(4), Some(4), Some(5), Some(5), Some(7), Some(9)]);
+        assert_eq!(stats.mean, Some(5.0));
+        assert_eq!(stats.jitter, Some(2.0));
+    }
+
+    #[test]
+    fn lost_rounds_only_count_as_loss() {
+        let stats = LatencyStats::from_samples(&[Some(10), None, Some(30), None]);
+        assert_eq!(stats.rounds, 4);
+        assert_eq!(stats.lost, 2);
+        assert_eq!(stats.loss_rate, 0.5);
+        assert_eq!(stats.mean, Some(20.0));
+        assert_eq!(stats.max, Some(30));
+
+        let stats = LatencyStats::from_samples(&[None, None]);
+        assert_eq!(stats.loss_rate, 1.0);
+        assert_eq!(stats.median, None);
+        assert_eq!(stats.jitter, None);
+
+        assert_eq!(LatencyStats::from_samples(&[]), LatencyStats::default());
+    }
+
+    #[test]
+    fn one_sample_is_every_statistic() {
+        let stats = LatencyStats::from_samples(&[Some(42)]);
+        assert_eq!((stats.min, stats.median, stats.p95, stats.max), (Some(42), Some(42), Some(42), Some(42)));
+        assert_eq!(stats.jitter, Some(0.0));
+        assert_eq!(stats.lost, 0);
+    }
+
+    #[test]
+    fn rounds_are_clamped() {
+        assert_eq!(round_count(0), 1);
+        assert_eq!(round_count(5), 5);
+        assert_eq!(round_count(1000), MAX_ROUNDS);
+    }
+}
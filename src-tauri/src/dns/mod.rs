@@ -1,43 +1,92 @@
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
-use trust_dns_resolver::config::{ResolverConfig, ResolverOpts, NameServerConfig, Protocol};
-use trust_dns_resolver::TokioAsyncResolver;
 use serde::{Deserialize, Serialize};
-use reqwest::Client;
-use std::net::SocketAddr;
 use url::Url;
 use futures_util::StreamExt;
+use anyhow::Context;
+use crate::errors::TestError;
+use crate::timing::{transfer_speed_mbps, ConnectionClock, PhaseTimings};
+use crate::utils::{
+    dns_config_path, download_config_file, read_dns_file, CustomDnsResolver, DNS_CONFIG_URL,
+};
 
-// Original DNS servers constants
-pub const DNS_SERVERS: &[&str] = &[
-    "178.22.122.100", 
-    "185.51.200.2",   
-    "192.104.158.78",
-    "194.104.158.48", 
-    "172.29.0.100",   
-    "172.29.2.100",   
-    "10.202.10.202",  
-    "10.202.10.102",  
-    "185.55.226.26",  
-    "185.55.225.25",  
-    "185.55.224.24",
-    "10.202.10.10",   
-    "10.202.10.11",   
-    "37.27.41.228",   
-    "87.107.52.11",   
-    "87.107.52.13",   
-    "5.202.100.100",
-    "5.202.100.101",  
-    "94.103.125.157", 
-    "94.103.125.158", 
-    "8.8.8.8",
-    "8.8.4.4",
-    "1.1.1.1",
-    "1.0.0.1",
-    "9.9.9.9",
-    "149.112.112.112",
-    "149.112.112.112",
-];
+mod batch;
+mod behaviour;
+mod block_page;
+mod cdn;
+mod dnssec;
+mod happy_eyeballs;
+mod ip_database;
+mod latency;
+mod poison;
+mod providers;
+mod resolver;
+mod scoring;
+mod segmented;
+mod throughput;
+use segmented::{open_segment, plan_segments, ranged_length, segment_events, SegmentEvent, SegmentState};
+pub use batch::{batch_domains, preset_names, DnsMatrixCell, ServerCoverage};
+pub use behaviour::{probe_resolver, rewrite_baseline};
+pub use block_page::{load_block_pages, BlockPages, BlockReason, HttpResponseSample};
+pub use cdn::{
+    compare_ecs, load_cdn_networks, parse_client_subnet, pop_from_headers, public_client_subnet, CdnEdge, CdnNetworks,
+    CdnSteering,
+};
+pub use dnssec::{apply_dnssec_check, trusted_signatures, DnssecCheck};
+pub use happy_eyeballs::{
+    describe_failed_attempts, failed_attempts_error, interleave_families, race_connect, request_failed, AddressAttempt,
+};
+use latency::round_count;
+pub use latency::LatencyStats;
+pub use poison::{apply_poisoning_check, trusted_baseline, PoisoningCheck, TRUSTED_DNS_SERVER};
+pub use providers::{DnsConfig, DnsProvider, DnsProviderResult, DownloadProviderResult};
+pub use scoring::{best_download_server, rank_dns_servers, DnsRecommendation, TestResultSet};
+pub use segmented::{SegmentedDownload, MAX_SEGMENTS};
+pub use throughput::{DownloadProgress, ProgressCallback, ThroughputMeter, ThroughputSample, PROGRESS_INTERVAL};
+pub use resolver::{
+    bracket_ipv6, lookup_ip, lookup_records, AddressFamily, DnsServer, DnsTransport,
+    LookupStrategy, MessageClient, ResolvedRecord, Upstream, UpstreamAnswer,
+};
+
+// DNS providers to test: the user's dns.yml (downloaded from 403unlocker on first use),
+// or the built-in catalog when that isn't available. The second value says why a
+// dns.yml that exists couldn't be used.
+pub async fn load_dns_providers() -> (Vec<DnsProvider>, Option<String>) {
+    load_dns_providers_from(&dns_config_path()).await
+}
+
+async fn load_dns_providers_from(dns_file_path: &PathBuf) -> (Vec<DnsProvider>, Option<String>) {
+    let builtin = DnsConfig::builtin();
+    // Only a missing file is fetched, one that is there is the user's even when it doesn't parse
+    if !dns_file_path.exists() {
+        if let Err(e) = download_config_file(DNS_CONFIG_URL, dns_file_path).await {
+            eprintln!("Failed to download DNS config: {}", e);
+        }
+    }
+    let (config, config_error) = match read_dns_file(dns_file_path).await {
+        Ok(config) => (config, None),
+        Err(e) if !dns_file_path.exists() => {
+            eprintln!("No DNS config available, using built-in servers: {}", e);
+            (builtin.clone(), None)
+        }
+        Err(e) => {
+            let error = format!(
+                "Could not read {}, using the built-in DNS servers: {:#}",
+                dns_file_path.display(),
+                e
+            );
+            eprintln!("{}", error);
+            (builtin.clone(), Some(error))
+        }
+    };
+
+    let catalog = config.into_catalog(&builtin.dns_providers);
+    if catalog.is_empty() {
+        return (builtin.clone().into_catalog(&builtin.dns_providers), config_error);
+    }
+    (catalog, config_error)
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum HttpStatus {
@@ -54,9 +103,24 @@ pub struct DnsTestResult {
     pub status: bool,
     pub response_time: Option<u64>, 
     pub error_message: Option<String>,
+    pub error_kind: Option<TestError>,
     pub session_id: u64,
     pub http_status: HttpStatus,
     pub test_url: Option<String>,
+    pub transport: DnsTransport,
+    pub provider: Option<String>,
+    pub poisoning: Option<PoisoningCheck>,
+    pub dnssec: Option<DnssecCheck>,
+    pub block_reason: Option<BlockReason>,
+    pub block_page: Option<String>,
+    pub resolved_records: Vec<ResolvedRecord>,
+    pub resolution_time: Option<u64>,
+    pub http_response_time: Option<u64>,
+    pub connected_address: Option<IpAddr>,
+    pub address_family: Option<AddressFamily>,
+    pub address_attempts: Vec<AddressAttempt>,
+    pub dns_latency: LatencyStats,
+    pub http_latency: LatencyStats,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -68,8 +132,23 @@ pub struct DownloadSpeedResult {
     pub downloaded_bytes: u64,
     pub test_duration_seconds: f64,
     pub error_message: Option<String>,
+    pub error_kind: Option<TestError>,
     pub resolution_time_ms: Option<u64>,
     pub session_id: u64,
+    pub transport: DnsTransport,
+    pub provider: Option<String>,
+    pub connected_address: Option<IpAddr>,
+    pub address_family: Option<AddressFamily>,
+    pub address_attempts: Vec<AddressAttempt>,
+    pub cdn_edge: Option<CdnEdge>,
+    pub steering: Option<CdnSteering>,
+    pub throughput_samples: Vec<ThroughputSample>,
+    pub peak_speed_mbps: Option<f64>,
+    /// Average speed once TCP slow start is over
+    pub steady_state_speed_mbps: Option<f64>,
+    pub timings: PhaseTimings,
+    /// Per-connection results when the test asked for more than one connection
+    pub segmented: Option<SegmentedDownload>,
 }
 
 
@@ -78,95 +157,167 @@ pub fn ensure_https_url(input: &str) -> Option<Url> {
     Url::parse(&format!("https://{}/", clean)).ok()
 }
 
-// Simpler approach: manually resolve DNS, then use reqwest's .resolve() method
-pub async fn check_url_with_custom_dns(url: &Url, dns_ip: &str) -> Option<(u16, String)> {
+// What a URL check through a custom DNS server got to before it finished or failed
+#[derive(Debug, Default)]
+pub struct UrlCheck {
+    pub records: Vec<ResolvedRecord>,
+    pub resolution_time: Option<u64>,
+    pub http_response_time: Option<u64>,
+    pub http_response: Option<HttpResponseSample>,
+    /// What the host answered behind a rejected certificate, only read to
+    /// recognise block pages
+    pub unverified_response: Option<HttpResponseSample>,
+    pub connected_address: Option<IpAddr>,
+    pub address_attempts: Vec<AddressAttempt>,
+    pub error: Option<TestError>,
+}
+
+impl UrlCheck {
+    // Resolution time if the server answered
+    fn dns_sample(&self) -> Option<u64> {
+        self.resolution_time.filter(|_| !self.records.is_empty())
+    }
+
+    // Response time if the request got an answer; rounds where DNS failed never made one
+    fn http_sample(&self) -> Option<Option<u64>> {
+        if self.records.is_empty() {
+            return None;
+        }
+        Some(self.http_response_time.filter(|_| self.http_response.is_some()))
+    }
+}
+
+// Resolve through the custom DNS server first so the records can be reported, then
+// request the URL through a client that uses the same server
+pub async fn check_url_with_custom_dns(url: &Url, dns_ip: &str, lookup_strategy: LookupStrategy) -> UrlCheck {
     println!("Testing URL: {} with DNS: {}", url, dns_ip);
+    let mut check = UrlCheck::default();
     
     // Get the hostname from the URL
-    let host = url.host_str()?;
-    println!("Resolving hostname: {} using DNS: {}", host, dns_ip);
-    
-    let socket_addr: SocketAddr = format!("{}:53", dns_ip).parse().ok()?;
-    let nameserver = NameServerConfig {
-        socket_addr,
-        protocol: Protocol::Udp,
-        tls_dns_name: None,
-        bind_addr: None,
-        trust_negative_responses: false,
+    let host = match url.host_str() {
+        Some(host) => host,
+        None => {
+            check.error = Some(TestError::InvalidInput("URL has no host".to_string()));
+            return check;
+        }
     };
-
-    let resolver_config = ResolverConfig::from_parts(None, vec![], vec![nameserver]);
-    let mut resolver_opts = ResolverOpts::default();
-    resolver_opts.timeout = Duration::from_secs(5);
-    resolver_opts.attempts = 2;
+    println!("Resolving hostname: {} using DNS: {}", host, dns_ip);
     
-    let resolver = TokioAsyncResolver::tokio(resolver_config, resolver_opts);
+    // Resolve the hostname, keeping every record for the result
+    let resolution_start = Instant::now();
+    let lookup_result = lookup_records(host, dns_ip, lookup_strategy).await;
+    check.resolution_time = Some(resolution_start.elapsed().as_millis() as u64);
 
-    // Resolve the hostname to an IP address
-    let lookup_result = match resolver.lookup_ip(host).await {
-        Ok(result) => result,
+    check.records = match lookup_result {
+        Ok(records) => records,
         Err(e) => {
             println!("DNS resolution failed for {} using DNS {}: {:?}", host, dns_ip, e);
-            return None;
+            check.error = Some(TestError::classify(&e));
+            return check;
         }
     };
 
-    let resolved_ip = match lookup_result.iter().next() {
-        Some(ip) => ip,
-        None => {
-            println!("No IP addresses found for {} using DNS {}", host, dns_ip);
-            return None;
+    let addresses = interleave_families(check.records.iter().map(|record| record.ip));
+    println!("DNS resolution successful: {} -> {:?} (using DNS {})", host, addresses, dns_ip);
+
+    let resolver = match CustomDnsResolver::new(dns_ip, lookup_strategy) {
+        Ok(resolver) => resolver,
+        Err(e) => {
+            println!("Invalid DNS server {}: {:?}", dns_ip, e);
+            check.error = Some(TestError::InvalidInput(e.to_string()));
+            return check;
         }
     };
 
-    println!("DNS resolution successful: {} -> {} (using DNS {})", host, resolved_ip, dns_ip);
-
-    // Determine the port based on the URL scheme
-    let port = match url.scheme() {
-        "https" => 443,
-        "http" => 80,
-        _ => {
-            println!("Unsupported URL scheme: {}", url.scheme());
-            return None;
+    // Connect to whichever address answers first, falling back to the others if one
+    // can't be reached, then make the one HTTP request there with the hostname pinned to it
+    let http_start = Instant::now();
+    let timeout = Duration::from_secs(10);
+    let port = url.port_or_known_default().unwrap_or(443);
+    let (connected, mut attempts) = race_connect(addresses, port, timeout).await;
+    let mut winner = None;
+    if let Some(ip) = connected {
+        let request = async {
+            let client = resolver
+                .clone()
+                .pin(host, vec![ip])
+                .client_builder()
+                .timeout(timeout.saturating_sub(http_start.elapsed()))
+                .build()?;
+            anyhow::Ok(client.get(url.clone()).send().await?)
+        };
+        match request.await {
+            Ok(res) => winner = Some((ip, res)),
+            Err(e) => request_failed(&mut attempts, ip, &e),
         }
-    };
+    }
+    check.http_response_time = Some(http_start.elapsed().as_millis() as u64);
 
-    let socket_addr = SocketAddr::new(resolved_ip, port);
+    match winner {
+        Some((ip, res)) => {
+            let response = HttpResponseSample::read(res).await;
+            println!(
+                "HTTP request succeeded: {} ({}) - {} {} (DNS: {})",
+                host, ip, response.status, response.reason, dns_ip
+            );
+            check.connected_address = Some(ip);
+            check.http_response = Some(response);
+        }
+        None => {
+            println!(
+                "HTTP request failed for {} using DNS {}: {}",
+                host,
+                dns_ip,
+                describe_failed_attempts(&attempts)
+            );
+            check.error = Some(failed_attempts_error(&attempts));
+            check.unverified_response = fetch_behind_rejected_certificate(&resolver, url, host, &attempts).await;
+        }
+    }
+    check.address_attempts = attempts;
+    check
+}
 
-    // Build HTTP client with the resolved IP address
-    // The .resolve() method tells reqwest to use this specific IP for this hostname
-    let client = match Client::builder()
+// Block pages for HTTPS sites are served with whatever certificate the blocker
+// has, so a rejected certificate is fetched again without validation to read
+// the page. The check still fails on the certificate either way.
+async fn fetch_behind_rejected_certificate(
+    resolver: &CustomDnsResolver,
+    url: &Url,
+    host: &str,
+    attempts: &[AddressAttempt],
+) -> Option<HttpResponseSample> {
+    let rejected = attempts
+        .iter()
+        .find(|attempt| matches!(attempt.error_kind, Some(TestError::CertificateMismatch(_))))?;
+    let client = resolver
+        .clone()
+        .pin(host, vec![rejected.address])
+        .client_builder()
         .danger_accept_invalid_certs(true)
         .timeout(Duration::from_secs(10))
-        .user_agent("Mozilla/5.0 (compatible; Bargozin-DNS-Tester)")
-        .resolve(host, socket_addr)  // Map hostname to resolved IP
         .build()
-    {
-        Ok(c) => c,
+        .ok()?;
+    match client.get(url.clone()).send().await {
+        Ok(response) => Some(HttpResponseSample::read_with_body(response).await),
         Err(e) => {
-            println!("Failed to build HTTP client: {:?}", e);
-            return None;
-        }
-    };
-
-    // Make the HTTP request
-    match client.get(url.as_str()).send().await {
-        Ok(res) => {
-            let code = res.status().as_u16();
-            let msg = res.status().canonical_reason().unwrap_or("Unknown").to_string();
-            println!("HTTP request succeeded: {} - {} {} (DNS: {})", host, code, msg, dns_ip);
-            Some((code, msg))
-        }
-        Err(e) => {
-            println!("HTTP request failed for {} using DNS {}: {:?}", host, dns_ip, e);
+            println!("Could not read {} behind its rejected certificate: {}", url, e);
             None
         }
     }
 }
 
 // Original functions (keeping existing functionality)
-pub async fn test_single_dns_server(domain: String, dns_server: String, _session_id: u64) -> DnsTestResult {
+pub async fn test_single_dns_server(
+    domain: String,
+    dns_server: String,
+    lookup_strategy: LookupStrategy,
+    rounds: u32,
+    block_pages: &BlockPages,
+    _session_id: u64,
+) -> DnsTestResult {
     let start_time = std::time::Instant::now();
+    let transport = DnsTransport::of(&dns_server);
     
     // Ensure HTTPS URL like in CLI code
     let url_string = ensure_https(&domain);
@@ -178,17 +329,46 @@ pub async fn test_single_dns_server(domain: String, dns_server: String, _session
                 status: false,
                 response_time: Some(start_time.elapsed().as_millis() as u64),
                 error_message: Some("Invalid domain format".to_string()),
+                error_kind: Some(TestError::InvalidInput("Invalid domain format".to_string())),
                 session_id: 0,
                 http_status: HttpStatus::Failed("Invalid domain".to_string()),
                 test_url: Some(url_string),
+                transport,
+                provider: None,
+                poisoning: None,
+                dnssec: None,
+                block_reason: None,
+                block_page: None,
+                resolved_records: vec![],
+                resolution_time: None,
+                http_response_time: None,
+                connected_address: None,
+                address_family: None,
+                address_attempts: vec![],
+                dns_latency: LatencyStats::default(),
+                http_latency: LatencyStats::default(),
             };
         }
     };
     
     // Use custom DNS resolver like in CLI
-    match check_url_with_custom_dns(&parsed_url, &dns_server).await {
-        Some((status_code, status_msg)) => {
-            let response_time = start_time.elapsed().as_millis() as u64;
+    let check = check_url_with_custom_dns(&parsed_url, &dns_server, lookup_strategy).await;
+    // One round trip, taken before the extra rounds below add theirs
+    let response_time = start_time.elapsed().as_millis() as u64;
+
+    // Further rounds only add timings, the result itself comes from the first one
+    let mut dns_samples = vec![check.dns_sample()];
+    let mut http_samples: Vec<Option<u64>> = check.http_sample().into_iter().collect();
+    for _ in 1..round_count(rounds) {
+        let round = check_url_with_custom_dns(&parsed_url, &dns_server, lookup_strategy).await;
+        dns_samples.push(round.dns_sample());
+        http_samples.extend(round.http_sample());
+    }
+    let dns_latency = LatencyStats::from_samples(&dns_samples);
+    let http_latency = LatencyStats::from_samples(&http_samples);
+    match check.http_response {
+        Some(response) => {
+            let status_code = response.status;
             
             let http_status = match status_code {
                 200..=299 => HttpStatus::Success,
@@ -197,7 +377,14 @@ pub async fn test_single_dns_server(domain: String, dns_server: String, _session
             };
             
             // Consider 200-299 as usable (like CLI)
-            let is_usable = status_code >= 200 && status_code < 300;
+            let is_usable = (200..300).contains(&status_code);
+
+            // Tell sanction and government block pages apart from ordinary errors
+            let block_page = if is_usable {
+                None
+            } else {
+                block_pages.identify(parsed_url.host_str().unwrap_or_default(), &response)
+            };
             
             DnsTestResult {
                 dns_server,
@@ -206,23 +393,70 @@ pub async fn test_single_dns_server(domain: String, dns_server: String, _session
                 error_message: if is_usable { 
                     None 
                 } else { 
-                    Some(format!("HTTP {} - {}", status_code, status_msg)) 
+                    Some(match block_page {
+                        Some(rule) => format!("HTTP {} - {} ({})", status_code, response.reason, rule.name),
+                        None => format!("HTTP {} - {}", status_code, response.reason),
+                    })
                 },
+                error_kind: if is_usable { None } else { Some(TestError::HttpStatus(status_code)) },
                 session_id: 0,
                 http_status,
                 test_url: Some(url_string),
+                transport,
+                provider: None,
+                poisoning: None,
+                dnssec: None,
+                block_reason: block_page.map(|rule| rule.reason),
+                block_page: block_page.map(|rule| rule.name.clone()),
+                resolved_records: check.records,
+                resolution_time: check.resolution_time,
+                http_response_time: check.http_response_time,
+                connected_address: check.connected_address,
+                address_family: check.connected_address.as_ref().map(AddressFamily::of),
+                address_attempts: check.address_attempts,
+                dns_latency,
+                http_latency,
             }
         }
         None => {
-            let response_time = start_time.elapsed().as_millis() as u64;
+            let error = check
+                .error
+                .unwrap_or_else(|| TestError::Other("DNS resolution or HTTP request failed".to_string()));
+            let block_page = check
+                .unverified_response
+                .as_ref()
+                .and_then(|response| block_pages.identify(parsed_url.host_str().unwrap_or_default(), response));
+            let message = if check.address_attempts.is_empty() {
+                error.to_string()
+            } else {
+                format!("HTTP request failed: {}", describe_failed_attempts(&check.address_attempts))
+            };
             DnsTestResult {
                 dns_server,
                 status: false,
                 response_time: Some(response_time),
-                error_message: Some("DNS resolution or HTTP request failed".to_string()),
+                error_message: Some(match block_page {
+                    Some(rule) => format!("{} ({})", message, rule.name),
+                    None => message,
+                }),
+                http_status: HttpStatus::Failed(error.to_string()),
+                error_kind: Some(error),
                 session_id: 0,
-                http_status: HttpStatus::Failed("Connection failed".to_string()),
                 test_url: Some(url_string),
+                transport,
+                provider: None,
+                poisoning: None,
+                dnssec: None,
+                block_reason: block_page.map(|rule| rule.reason),
+                block_page: block_page.map(|rule| rule.name.clone()),
+                resolved_records: check.records,
+                resolution_time: check.resolution_time,
+                http_response_time: check.http_response_time,
+                connected_address: check.connected_address,
+                address_family: check.connected_address.as_ref().map(AddressFamily::of),
+                address_attempts: check.address_attempts,
+                dns_latency,
+                http_latency,
             }
         }
     }
@@ -245,119 +479,279 @@ fn ensure_https(domain: &str) -> String {
     // Parse to extract just the host part (like Go code)
     if let Ok(parsed) = url::Url::parse(&result) {
         if let Some(host) = parsed.host_str() {
-            result = format!("https://{}/", host);
+            result = match parsed.port() {
+                Some(port) => format!("https://{}:{}/", host, port),
+                None => format!("https://{}/", host),
+            };
         }
     }
     
     result
 }
 
-async fn resolve_host_with_dns(host: &str, dns_server: &str) -> anyhow::Result<IpAddr> {
-    let socket_addr: SocketAddr = format!("{}:53", dns_server).parse()?;
-    let nameserver = NameServerConfig {
-        socket_addr,
-        protocol: Protocol::Udp,
-        tls_dns_name: None,
-        bind_addr: None,
-        trust_negative_responses: false,
+// Every address the server returns for the host, in the order they should be tried
+async fn resolve_host_with_dns(
+    host: &str,
+    dns_server: &str,
+    lookup_strategy: LookupStrategy,
+) -> anyhow::Result<Vec<IpAddr>> {
+    let response = lookup_records(host, dns_server, lookup_strategy).await?;
+    Ok(interleave_families(response.into_iter().map(|record| record.ip)))
+}
+
+/// A server's addresses for the download host, looked up before its download
+/// test starts (see fair mode in the download command).
+pub struct PreResolved {
+    pub resolved_ips: anyhow::Result<Vec<IpAddr>>,
+    pub resolution_time_ms: u64,
+}
+
+pub async fn pre_resolve_download_host(
+    url: &str,
+    dns_server: &str,
+    lookup_strategy: LookupStrategy,
+    timeout_seconds: u64,
+) -> PreResolved {
+    let resolution_start = Instant::now();
+    let resolved_ips = match reqwest::Url::parse(url) {
+        Ok(parsed_url) => match parsed_url.host_str() {
+            Some(host) => {
+                let timeout_duration = Duration::from_secs(timeout_seconds);
+                resolve_download_host(host, dns_server, lookup_strategy, timeout_duration).await
+            }
+            None => Err(anyhow::anyhow!("Invalid host")),
+        },
+        Err(e) => Err(e.into()),
     };
+    PreResolved {
+        resolved_ips,
+        resolution_time_ms: resolution_start.elapsed().as_millis() as u64,
+    }
+}
 
-    let resolver_config = ResolverConfig::from_parts(None, vec![], vec![nameserver]);
-    
-    // Configure resolver options with a reasonable timeout for DNS resolution
-    let mut resolver_opts = ResolverOpts::default();
-    resolver_opts.timeout = Duration::from_secs(5); // 5 second timeout for DNS resolution
-    resolver_opts.attempts = 2; // 2 attempts max
-    
-    let resolver = TokioAsyncResolver::tokio(resolver_config, resolver_opts);
+async fn resolve_download_host(
+    host: &str,
+    dns_ip: &str,
+    lookup_strategy: LookupStrategy,
+    timeout_duration: Duration,
+) -> anyhow::Result<Vec<IpAddr>> {
+    println!("Resolving {} using DNS {}", host, dns_ip);
+    tokio::time::timeout(timeout_duration, resolve_host_with_dns(host, dns_ip, lookup_strategy))
+        .await
+        .map_err(|_| TestError::ResolverTimeout)?
+        .context("DNS resolution failed")
+}
 
-    let response = resolver.lookup_ip(host).await?;
-    response
-        .iter()
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("No IP found for host"))
+/// Settings shared by every download test of a run.
+pub struct DownloadTestOptions {
+    pub lookup_strategy: LookupStrategy,
+    pub timeout_seconds: u64,
+    pub cdn_networks: CdnNetworks,
+    /// Connections to split the file across with Range requests, 1 for a single stream
+    pub segments: usize,
 }
 
-async fn download_with_custom_dns(url: &str, dns_ip: &str, timeout_seconds: u64, _session_id: u64) -> anyhow::Result<DownloadSpeedResult> {
+async fn download_with_custom_dns(
+    url: &str,
+    dns_ip: &str,
+    options: &DownloadTestOptions,
+    session_id: u64,
+    pre_resolved: Option<PreResolved>,
+    on_progress: &ProgressCallback<'_>,
+) -> anyhow::Result<DownloadSpeedResult> {
     println!("Starting download test: {} with DNS: {}", url, dns_ip);
+    let lookup_strategy = options.lookup_strategy;
     
-    // Start the overall timer from the beginning (includes DNS resolution + connection + download)
+    // Start the overall timer from the beginning (includes DNS resolution + connection + download,
+    // unless the host was resolved beforehand)
     let overall_start = Instant::now();
-    let timeout_duration = std::time::Duration::from_secs(timeout_seconds);
-    
-    let resolution_start = Instant::now();
+    let timeout_duration = std::time::Duration::from_secs(options.timeout_seconds);
     
     let parsed_url = reqwest::Url::parse(url)?;
     let host = parsed_url.host_str().ok_or_else(|| anyhow::anyhow!("Invalid host"))?;
     
     println!("Parsed URL - host: {}, scheme: {}", host, parsed_url.scheme());
 
-    // Resolve host with custom DNS with timeout
-    println!("Resolving {} using DNS {}", host, dns_ip);
-    
-    // Apply timeout to DNS resolution
-    let resolved_ip = tokio::time::timeout(
-        timeout_duration,
-        resolve_host_with_dns(host, dns_ip)
-    ).await
-    .map_err(|_| anyhow::anyhow!("DNS resolution timed out after {} seconds", timeout_seconds))?
-    .map_err(|e| anyhow::anyhow!("DNS resolution failed: {}", e))?;
-    
-    let resolution_time_ms = resolution_start.elapsed().as_millis() as u64;
-    println!("DNS resolution successful: {} -> {} ({}ms)", host, resolved_ip, resolution_time_ms);
+    let (resolved_ips, resolution_time_ms) = match pre_resolved {
+        Some(pre_resolved) => (pre_resolved.resolved_ips?, pre_resolved.resolution_time_ms),
+        None => {
+            let resolution_start = Instant::now();
+            let resolved_ips = resolve_download_host(host, dns_ip, lookup_strategy, timeout_duration).await?;
+            (resolved_ips, resolution_start.elapsed().as_millis() as u64)
+        }
+    };
+    println!("DNS resolution successful: {} -> {:?} ({}ms)", host, resolved_ips, resolution_time_ms);
 
     // Check if we still have time left after DNS resolution
     if overall_start.elapsed() >= timeout_duration {
-        return Err(anyhow::anyhow!("Operation timed out during DNS resolution"));
+        return Err(TestError::ResolverTimeout.into());
     }
 
-    // Determine port based on scheme
-    let port = match parsed_url.scheme() {
-        "https" => 443,
-        "http" => 80,
-        _ => return Err(anyhow::anyhow!("Unsupported scheme")),
-    };
-
-    let socket_addr = SocketAddr::new(resolved_ip, port);
+    let resolver = CustomDnsResolver::new(dns_ip, lookup_strategy)?;
 
     // Calculate remaining time for HTTP operations
     let remaining_time = timeout_duration.saturating_sub(overall_start.elapsed());
     if remaining_time.is_zero() {
-        return Err(anyhow::anyhow!("Operation timed out before HTTP request"));
+        return Err(TestError::HttpTimeout.into());
     }
 
-    let client = Client::builder()
-        .danger_accept_invalid_certs(true)
-        .timeout(remaining_time) // Use remaining time, not extra time
-        .resolve(host, socket_addr)
-        .build()?;
-
     let download_start = Instant::now();
-    
-    let response = client.get(url).send().await
-        .map_err(|e| anyhow::anyhow!("HTTP request failed: {}", e))?;
+
+    // Start the download from whichever resolved address accepts a connection first
+    let port = parsed_url.port_or_known_default().unwrap_or(443);
+    let (connected, mut address_attempts) = race_connect(resolved_ips, port, remaining_time).await;
+    let request = async {
+        let ip = connected.ok_or_else(|| anyhow::Error::from(failed_attempts_error(&address_attempts)))?;
+        let clock = ConnectionClock::default();
+        let client = resolver
+            .clone()
+            .pin(host, vec![ip])
+            .timed(clock.clone())
+            .client_builder()
+            .timeout(timeout_duration.saturating_sub(overall_start.elapsed())) // Use remaining time, not extra time
+            .build()?;
+        clock.request_sent();
+        let response = client.get(url).send().await?;
+        clock.headers_received();
+        anyhow::Ok((ip, clock, response))
+    };
+    let (connected_address, clock, response) = match request.await {
+        Ok(winner) => winner,
+        Err(e) => {
+            if let Some(ip) = connected {
+                request_failed(&mut address_attempts, ip, &e);
+            }
+            return Err(e.context(format!("HTTP request failed ({})", describe_failed_attempts(&address_attempts))));
+        }
+    };
+
+    // An error or block page downloads fast but says nothing about the mirror
+    if !response.status().is_success() {
+        return Err(TestError::HttpStatus(response.status().as_u16()).into());
+    }
+    let cdn_edge = CdnEdge::new(connected_address, &options.cdn_networks, pop_from_headers(response.headers()));
+    println!(
+        "Downloading {} via {} ({}, POP {})",
+        url,
+        connected_address,
+        cdn_edge.network.as_deref().unwrap_or("unknown network"),
+        cdn_edge.pop.as_deref().unwrap_or("unknown")
+    );
 
     let mut downloaded_bytes = 0u64;
-    let mut stream = response.bytes_stream();
+    let total_bytes = response.content_length();
+    let final_url = response.url().clone();
+
+    // With several segments the first response carries the first range and the
+    // rest are fetched on connections of their own; without range support the
+    // first response is read whole as usual
+    let requested_segments = options.segments.max(1);
+    let mut segments: Vec<SegmentState> = vec![];
+    let mut segmented = None;
+    let split = (requested_segments > 1).then(|| ranged_length(&response, requested_segments));
+    let (events, transfer_start) = match split {
+        Some(Ok(length)) => {
+            let ranges = plan_segments(length, requested_segments);
+            let remaining_time = timeout_duration.saturating_sub(overall_start.elapsed());
+            let opened = futures::future::join_all(ranges[1..].iter().map(|range| {
+                open_segment(resolver.clone(), host, connected_address, &final_url, *range, remaining_time)
+            }))
+            .await;
+            println!("Downloading {} in {} segments of {} bytes", url, requested_segments, length);
+            let transfer_start = Instant::now();
+            let mut events = vec![segment_events(0, response, ranges[0].1 - ranges[0].0 + 1)];
+            segments.push(SegmentState::new(ranges[0], transfer_start));
+            for (index, (range, opened)) in (1..).zip(ranges[1..].iter().zip(opened)) {
+                match opened {
+                    Ok(response) => {
+                        events.push(segment_events(index, response, range.1 - range.0 + 1));
+                        segments.push(SegmentState::new(*range, transfer_start));
+                    }
+                    Err(e) => {
+                        println!("Segment {} of {} failed: {:#}", index, url, e);
+                        segments.push(SegmentState::failed(*range, &e));
+                    }
+                }
+            }
+            (events, transfer_start)
+        }
+        Some(Err(reason)) => {
+            println!("Falling back to a single stream for {}: {}", url, reason);
+            segmented = Some(SegmentedDownload::fallback(requested_segments, reason));
+            (vec![segment_events(0, response, u64::MAX)], Instant::now())
+        }
+        None => (vec![segment_events(0, response, u64::MAX)], Instant::now()),
+    };
+    let mut stream = futures::stream::select_all(events);
+
+    // Progress is sampled on a timer rather than per chunk, so a stalled transfer
+    // shows up as zero-speed samples instead of silence
+    let mut meter = ThroughputMeter::start();
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + PROGRESS_INTERVAL, PROGRESS_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            event = stream.next() => {
+                match event {
+                    None => break,
+                    Some(SegmentEvent::Chunk(index, Ok(len))) => {
+                        downloaded_bytes += len;
+                        if let Some(segment) = segments.get_mut(index) {
+                            segment.downloaded_bytes += len;
+                        }
+
+                        // Add periodic check for cancellation (every 1MB or every 1 second)
+                        if downloaded_bytes.is_multiple_of(1024 * 1024) || download_start.elapsed().as_secs() > 1 {
+                            tokio::task::yield_now().await; // Allow other tasks to run and check for cancellation
+                        }
+                    }
+                    // One failed connection of several is reported, not the end of the test
+                    Some(SegmentEvent::Chunk(index, Err(e))) => match segments.get_mut(index) {
+                        Some(segment) => segment.error = Some(format!("Stream error: {}", e)),
+                        None => return Err(anyhow::Error::from(e).context("Stream error")),
+                    },
+                    Some(SegmentEvent::Done(index)) => {
+                        if let Some(segment) = segments.get_mut(index) {
+                            segment.finished.get_or_insert_with(Instant::now);
+                        }
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                let sample = meter.sample(downloaded_bytes);
+                on_progress(&DownloadProgress {
+                    dns_server: dns_ip.to_string(),
+                    url: url.to_string(),
+                    session_id,
+                    downloaded_bytes,
+                    total_bytes,
+                    elapsed_ms: sample.elapsed_ms,
+                    speed_mbps: sample.speed_mbps,
+                });
+            }
+        }
 
-    while let Some(chunk_result) = stream.next().await {
         // Check if overall timeout has been reached
         if overall_start.elapsed() >= timeout_duration {
             break;
         }
-
-        let chunk = chunk_result
-            .map_err(|e| anyhow::anyhow!("Stream error: {}", e))?;
-        downloaded_bytes += chunk.len() as u64;
-        
-        // Add periodic check for cancellation (every 1MB or every 1 second)
-        if downloaded_bytes % (1024 * 1024) == 0 || download_start.elapsed().as_secs() > 1 {
-            tokio::task::yield_now().await; // Allow other tasks to run and check for cancellation
-        }
+    }
+    let transfer_time = transfer_start.elapsed();
+    let throughput = meter.finish(downloaded_bytes);
+    if !segments.is_empty() {
+        segmented = Some(SegmentedDownload {
+            requested_segments,
+            segments: segments.iter().enumerate().map(|(index, segment)| segment.result(index)).collect(),
+            fallback_reason: None,
+        });
     }
 
     let elapsed = overall_start.elapsed().as_secs_f64(); // Use overall elapsed time
-    let speed_mbps = (downloaded_bytes as f64 * 8.0) / (elapsed * 1_000_000.0);
+    // Setup and waiting for the first byte say nothing about bandwidth
+    let speed_mbps = transfer_speed_mbps(downloaded_bytes, transfer_time);
+
+    let timings = PhaseTimings::measured(&clock, Some(resolution_time_ms), transfer_time);
 
     Ok(DownloadSpeedResult {
         dns_server: dns_ip.to_string(),
@@ -367,30 +761,366 @@ async fn download_with_custom_dns(url: &str, dns_ip: &str, timeout_seconds: u64,
         downloaded_bytes,
         test_duration_seconds: elapsed,
         error_message: None,
+        error_kind: None,
         resolution_time_ms: Some(resolution_time_ms),
         session_id: 0, // This will be set by the calling function
+        transport: DnsTransport::of(dns_ip),
+        provider: None,
+        connected_address: Some(connected_address),
+        address_family: Some(AddressFamily::of(&connected_address)),
+        address_attempts,
+        cdn_edge: Some(cdn_edge),
+        steering: None,
+        throughput_samples: throughput.samples,
+        peak_speed_mbps: throughput.peak_speed_mbps,
+        steady_state_speed_mbps: throughput.steady_state_speed_mbps,
+        timings,
+        segmented,
     })
 }
 
-pub async fn test_download_speed_with_dns(url: String, dns_server: String, timeout_seconds: u64, session_id: u64) -> DownloadSpeedResult {
+pub async fn test_download_speed_with_dns(
+    url: String,
+    dns_server: String,
+    options: &DownloadTestOptions,
+    session_id: u64,
+    pre_resolved: Option<PreResolved>,
+    on_progress: &ProgressCallback<'_>,
+) -> DownloadSpeedResult {
     // Add a small delay to allow for cancellation check
     tokio::time::sleep(std::time::Duration::from_millis(10)).await;
     
-    match download_with_custom_dns(&url, &dns_server, timeout_seconds, session_id).await {
+    match download_with_custom_dns(&url, &dns_server, options, session_id, pre_resolved, on_progress).await {
         Ok(mut result) => {
             result.session_id = session_id;
             result
         },
         Err(e) => DownloadSpeedResult {
+            transport: DnsTransport::of(&dns_server),
             dns_server,
             url,
             success: false,
             download_speed_mbps: 0.0,
             downloaded_bytes: 0,
             test_duration_seconds: 0.0,
-            error_message: Some(e.to_string()),
+            error_message: Some(format!("{:#}", e)),
+            error_kind: Some(TestError::classify(&e)),
             resolution_time_ms: None,
             session_id,
+            provider: None,
+            connected_address: None,
+            address_family: None,
+            address_attempts: vec![],
+            cdn_edge: None,
+            steering: None,
+            throughput_samples: vec![],
+            peak_speed_mbps: None,
+            steady_state_speed_mbps: None,
+            timings: PhaseTimings::default(),
+            segmented: None,
         },
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use super::*;
+    use crate::test_support::{
+        doh_server, file_server, http_response, scripted_http_server, self_signed_https_server, udp_dns_server,
+    };
+
+    fn options(segments: usize) -> DownloadTestOptions {
+        DownloadTestOptions {
+            lookup_strategy: LookupStrategy::Ipv4Only,
+            timeout_seconds: 10,
+            cdn_networks: CdnNetworks::builtin(),
+            segments,
+        }
+    }
+
+    #[tokio::test]
+    async fn broken_dns_file_is_reported_and_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dns.yml");
+        let broken = "dnsServers:\n  - 10.0.0.53\n  bad indent: [\n";
+        std::fs::write(&path, broken).unwrap();
+
+        let (providers, error) = load_dns_providers_from(&path).await;
+        assert!(error.is_some());
+        let builtin = DnsConfig::builtin();
+        let expected = builtin.clone().into_catalog(&builtin.dns_providers);
+        assert_eq!(providers.len(), expected.len());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), broken);
+    }
+
+    #[tokio::test]
+    async fn user_dns_file_is_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dns.yml");
+        std::fs::write(&path, "dnsServers:\n  - 10.0.0.53\n  - 10.0.0.53\n").unwrap();
+
+        let (providers, error) = load_dns_providers_from(&path).await;
+        assert!(error.is_none());
+        let entries: Vec<String> = providers.iter().flat_map(|provider| provider.entries()).collect();
+        assert_eq!(entries, vec!["10.0.0.53".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn dns_test_reports_the_doh_transport() {
+        let endpoint = doh_server(Ipv4Addr::LOCALHOST).await;
+        let result = test_single_dns_server(
+            "example.com".to_string(),
+            endpoint,
+            LookupStrategy::Ipv4Only,
+            1,
+            &BlockPages::builtin(),
+            0,
+        )
+        .await;
+        assert_eq!(result.transport, DnsTransport::Https);
+        let resolved: Vec<IpAddr> = result.resolved_records.iter().map(|record| record.ip).collect();
+        assert_eq!(resolved, vec![IpAddr::from(Ipv4Addr::LOCALHOST)]);
+    }
+
+    #[tokio::test]
+    async fn recognises_a_block_page_behind_a_rejected_certificate() {
+        let port = self_signed_https_server(
+            "403 Forbidden",
+            "<html><body><iframe src=\"http://10.10.34.34/?type=Invalid Site\"></iframe></body></html>",
+        )
+        .await;
+        let server = udp_dns_server(Ipv4Addr::LOCALHOST).await;
+        let result = test_single_dns_server(
+            format!("blocked.example:{}", port),
+            server,
+            LookupStrategy::Ipv4Only,
+            1,
+            &BlockPages::builtin(),
+            0,
+        )
+        .await;
+
+        assert!(!result.status);
+        assert!(matches!(result.error_kind, Some(TestError::CertificateMismatch(_))), "{:?}", result.error_kind);
+        assert_eq!(result.block_reason, Some(BlockReason::Censored));
+        assert_eq!(result.block_page.as_deref(), Some("Iranian government block page"));
+        assert!(result.error_message.unwrap().ends_with("(Iranian government block page)"));
+    }
+
+    #[tokio::test]
+    async fn a_rejected_certificate_alone_is_not_a_block_page() {
+        let port = self_signed_https_server("200 OK", "<html><body>Welcome</body></html>").await;
+        let server = udp_dns_server(Ipv4Addr::LOCALHOST).await;
+        let result = test_single_dns_server(
+            format!("blocked.example:{}", port),
+            server,
+            LookupStrategy::Ipv4Only,
+            1,
+            &BlockPages::builtin(),
+            0,
+        )
+        .await;
+
+        assert!(!result.status);
+        assert!(matches!(result.error_kind, Some(TestError::CertificateMismatch(_))), "{:?}", result.error_kind);
+        assert_eq!(result.block_page, None);
+    }
+
+    #[tokio::test]
+    async fn downloads_through_a_doh_server() {
+        let port = file_server(vec![7u8; 300_000]).await;
+        let endpoint = doh_server(Ipv4Addr::LOCALHOST).await;
+        let url = format!("http://download.example:{}/file.bin", port);
+        let result = test_download_speed_with_dns(url, endpoint, &options(1), 3, None, &|_| {}).await;
+        assert!(result.success, "{:?}", result.error_message);
+        assert_eq!(result.transport, DnsTransport::Https);
+        assert_eq!(result.downloaded_bytes, 300_000);
+        assert_eq!(result.connected_address, Some(IpAddr::from(Ipv4Addr::LOCALHOST)));
+        assert_eq!(result.session_id, 3);
+    }
+
+    // Big enough for four segments
+    const SEGMENTED_FILE_BYTES: usize = 1_200_000;
+
+    // Serves `body` like file_server, except for the requested range starting at `failing_start`
+    fn ranged_response(head: &str, body: &[u8], failing_start: u64) -> Vec<u8> {
+        let range = head.lines().find_map(|line| line.strip_prefix("range: bytes="));
+        let Some((start, end)) = range.and_then(|range| range.trim().split_once('-')) else {
+            return http_response("200 OK", "accept-ranges: bytes\r\n", body);
+        };
+        let (start, end): (u64, u64) = (start.parse().unwrap(), end.parse().unwrap());
+        if start == failing_start {
+            return http_response("503 Service Unavailable", "", b"");
+        }
+        let content_range = format!("accept-ranges: bytes\r\ncontent-range: bytes {}-{}/{}\r\n", start, end, body.len());
+        http_response("206 Partial Content", &content_range, &body[start as usize..=end as usize])
+    }
+
+    async fn segmented_download(port: u16, segments: usize) -> DownloadSpeedResult {
+        let endpoint = doh_server(Ipv4Addr::LOCALHOST).await;
+        let url = format!("http://download.example:{}/file.bin", port);
+        test_download_speed_with_dns(url, endpoint, &options(segments), 3, None, &|_| {}).await
+    }
+
+    #[tokio::test]
+    async fn downloads_in_segments_through_a_doh_server() {
+        let port = file_server(vec![7u8; SEGMENTED_FILE_BYTES]).await;
+        let result = segmented_download(port, 4).await;
+        assert!(result.success, "{:?}", result.error_message);
+        assert_eq!(result.downloaded_bytes, SEGMENTED_FILE_BYTES as u64);
+
+        let segmented = result.segmented.unwrap();
+        assert_eq!(segmented.requested_segments, 4);
+        assert_eq!(segmented.fallback_reason, None);
+        let ranges: Vec<(u64, u64)> = segmented.segments.iter().map(|s| (s.range_start, s.range_end)).collect();
+        assert_eq!(ranges, plan_segments(SEGMENTED_FILE_BYTES as u64, 4));
+        for segment in &segmented.segments {
+            // The first segment reads the unranged response and is cut off at its range
+            assert_eq!(segment.downloaded_bytes, segment.range_end - segment.range_start + 1);
+            assert_eq!(segment.error, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_one_stream_without_range_support() {
+        let port = scripted_http_server(|_| http_response("200 OK", "", &[7u8; SEGMENTED_FILE_BYTES])).await;
+        let result = segmented_download(port, 4).await;
+        assert!(result.success, "{:?}", result.error_message);
+        assert_eq!(result.downloaded_bytes, SEGMENTED_FILE_BYTES as u64);
+        let segmented = result.segmented.unwrap();
+        assert!(segmented.segments.is_empty());
+        assert!(segmented.fallback_reason.unwrap().contains("Accept-Ranges"));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_one_stream_for_a_small_file() {
+        let port = file_server(vec![7u8; 300_000]).await;
+        let result = segmented_download(port, 4).await;
+        assert!(result.success, "{:?}", result.error_message);
+        assert_eq!(result.downloaded_bytes, 300_000);
+        assert!(result.segmented.unwrap().fallback_reason.unwrap().contains("too small"));
+
+        // A single connection isn't a segmented download at all
+        assert!(segmented_download(port, 1).await.segmented.is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_segments_of_a_server_that_ignores_range() {
+        let port = scripted_http_server(|_| {
+            http_response("200 OK", "accept-ranges: bytes\r\n", &[7u8; SEGMENTED_FILE_BYTES])
+        })
+        .await;
+        let result = segmented_download(port, 4).await;
+        assert!(result.success, "{:?}", result.error_message);
+
+        let segments = result.segmented.unwrap().segments;
+        let first = &segments[0];
+        assert_eq!(first.error, None);
+        assert_eq!(result.downloaded_bytes, first.range_end + 1);
+        for segment in &segments[1..] {
+            assert_eq!(segment.downloaded_bytes, 0);
+            assert!(segment.error.as_ref().unwrap().contains("ignored the Range header"), "{:?}", segment.error);
+        }
+    }
+
+    #[tokio::test]
+    async fn one_failed_segment_does_not_fail_the_download() {
+        let ranges = plan_segments(SEGMENTED_FILE_BYTES as u64, 4);
+        let failing = ranges[2];
+        let port = scripted_http_server(move |head| ranged_response(head, &[7u8; SEGMENTED_FILE_BYTES], failing.0)).await;
+        let result = segmented_download(port, 4).await;
+        assert!(result.success, "{:?}", result.error_message);
+        assert_eq!(result.downloaded_bytes, SEGMENTED_FILE_BYTES as u64 - (failing.1 - failing.0 + 1));
+
+        let segments = result.segmented.unwrap().segments;
+        assert_eq!(segments.len(), 4);
+        assert!(segments[2].error.as_ref().unwrap().contains("503"), "{:?}", segments[2].error);
+        assert_eq!(segments[2].downloaded_bytes, 0);
+        for index in [0, 1, 3] {
+            assert_eq!(segments[index].error, None);
+            assert_eq!(segments[index].downloaded_bytes, ranges[index].1 - ranges[index].0 + 1);
+        }
+    }
+
+    // Answers A queries with every address in `addresses`, in order
+    async fn multi_address_dns_server(addresses: Vec<Ipv4Addr>) -> String {
+        use trust_dns_resolver::proto::rr::rdata::A;
+        use trust_dns_resolver::proto::rr::{RData, Record, RecordType};
+        crate::test_support::scripted_dns_server(move |query| {
+            let mut response = query.clone();
+            response.set_message_type(trust_dns_resolver::proto::op::MessageType::Response);
+            if let Some(question) = query.queries().first().filter(|q| q.query_type() == RecordType::A) {
+                for address in &addresses {
+                    response.add_answer(Record::from_rdata(question.name().clone(), 60, RData::A(A::from(*address))));
+                }
+            }
+            response
+        })
+        .await
+    }
+
+    // Answers every request after `delay`, counting the requests it got
+    async fn counting_server(bind: &str, delay: Duration) -> (u16, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind(bind).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let counted = counted.clone();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buffer = [0u8; 1024];
+                    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buffer).await {
+                            // A raced connection closed without a request
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => head.extend_from_slice(&buffer[..read]),
+                        }
+                    }
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(delay).await;
+                    let _ = stream.write_all(&http_response("200 OK", "", b"body")).await;
+                });
+            }
+        });
+        (port, requests)
+    }
+
+    #[tokio::test]
+    async fn a_slow_server_gets_one_request() {
+        // Both addresses connect at once; only the connection is raced, so the slow
+        // response doesn't start a second request on the other address
+        let (port, requests) = counting_server("0.0.0.0:0", Duration::from_millis(600)).await;
+        let server = multi_address_dns_server(vec![Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(127, 0, 0, 2)]).await;
+        let url = format!("http://slow.example:{}/file", port);
+        let result = test_download_speed_with_dns(url, server, &options(1), 0, None, &|_| {}).await;
+
+        assert!(result.success, "{:?}", result.error_message);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(result.connected_address, Some(IpAddr::from(Ipv4Addr::LOCALHOST)));
+        assert_eq!(result.address_attempts.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn downloads_from_the_next_address_when_one_refuses() {
+        let (port, requests) = counting_server("127.0.0.1:0", Duration::ZERO).await;
+        let server = multi_address_dns_server(vec![Ipv4Addr::new(127, 0, 0, 2), Ipv4Addr::new(127, 0, 0, 1)]).await;
+        let url = format!("http://refused.example:{}/file", port);
+        let result = test_download_speed_with_dns(url, server, &options(1), 0, None, &|_| {}).await;
+
+        assert!(result.success, "{:?}", result.error_message);
+        assert_eq!(result.downloaded_bytes, 4);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        let attempts: Vec<(IpAddr, bool)> = result.address_attempts.iter().map(|a| (a.address, a.success)).collect();
+        assert_eq!(
+            attempts,
+            vec![(IpAddr::from(Ipv4Addr::new(127, 0, 0, 2)), false), (IpAddr::from(Ipv4Addr::LOCALHOST), true)]
+        );
+        assert_eq!(result.address_attempts[0].error_kind, Some(TestError::ConnectionRefused));
+    }
+}
@@ -0,0 +1,173 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use serde::{Deserialize, Serialize};
+use super::{lookup_ip, DnsTestResult};
+use crate::errors::TestError;
+
+// Encrypted resolver whose answers are taken as the truth when looking for tampering
+pub const TRUSTED_DNS_SERVER: &str = "https://cloudflare-dns.com/dns-query";
+
+// Networks censors point blocked names at, e.g. the peyvandha.ir block page on 10.10.34.34-36
+const BLOCK_PAGE_NETWORKS: &[(Ipv4Addr, u8)] = &[(Ipv4Addr::new(10, 10, 34, 0), 24)];
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PoisoningCheck {
+    pub poisoned: bool,
+    pub resolved_ips: Vec<String>,
+    pub trusted_ips: Vec<String>,
+    pub reason: Option<String>,
+}
+
+// Baseline answer from the trusted resolver, empty if it can't be reached
+pub async fn trusted_baseline(host: &str, trusted_dns_server: &str) -> Vec<IpAddr> {
+    match lookup_ip(host, trusted_dns_server).await {
+        Ok(ips) => ips,
+        Err(e) => {
+            eprintln!("Trusted resolution of {} via {} failed: {}", host, trusted_dns_server, e);
+            vec![]
+        }
+    }
+}
+
+/// Flags the addresses a server returned as poisoned when one points at a block
+/// page or a private/bogon address the trusted resolver didn't also return (so
+/// internal names resolving to private IPs are fine).
+pub fn check_poisoning(resolved: &[IpAddr], trusted_ips: &[IpAddr]) -> PoisoningCheck {
+    let reason = poisoned_reason(resolved, trusted_ips);
+
+    PoisoningCheck {
+        poisoned: reason.is_some(),
+        resolved_ips: resolved.iter().map(|ip| ip.to_string()).collect(),
+        trusted_ips: trusted_ips.iter().map(|ip| ip.to_string()).collect(),
+        reason,
+    }
+}
+
+// Why an answer looks poisoned: a block page or bogon address the trusted
+// resolver didn't return
+pub fn poisoned_reason(resolved: &[IpAddr], trusted_ips: &[IpAddr]) -> Option<String> {
+    resolved.iter().find_map(|ip| {
+        if trusted_ips.contains(ip) {
+            return None;
+        }
+        if is_block_page(ip) {
+            Some(format!("{} is a known block page address", ip))
+        } else if is_bogon(ip) {
+            Some(format!("{} is a private or bogon address", ip))
+        } else {
+            None
+        }
+    })
+}
+
+// Checks the answer the test already got. A poisoned answer makes the server
+// unusable, whatever the HTTP request returned
+pub fn apply_poisoning_check(result: &mut DnsTestResult, trusted_ips: &[IpAddr]) {
+    let resolved: Vec<IpAddr> = result.resolved_records.iter().map(|record| record.ip).collect();
+    let check = check_poisoning(&resolved, trusted_ips);
+    if let Some(reason) = check.reason.as_ref().filter(|_| check.poisoned) {
+        let error = TestError::PoisonedAnswer(reason.clone());
+        result.status = false;
+        result.error_message = Some(error.to_string());
+        result.error_kind = Some(error);
+    }
+    result.poisoning = Some(check);
+}
+
+fn is_block_page(ip: &IpAddr) -> bool {
+    let IpAddr::V4(ip) = ip else {
+        return false;
+    };
+    BLOCK_PAGE_NETWORKS
+        .iter()
+        .any(|&(network, prefix)| in_network(*ip, network, prefix))
+}
+
+fn in_network(ip: Ipv4Addr, network: Ipv4Addr, prefix: u8) -> bool {
+    let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+    u32::from(ip) & mask == u32::from(network) & mask
+}
+
+pub fn is_bogon(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_bogon_v4(*ip),
+        IpAddr::V6(ip) => is_bogon_v6(ip),
+    }
+}
+
+fn is_bogon_v4(ip: Ipv4Addr) -> bool {
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || in_network(ip, Ipv4Addr::new(0, 0, 0, 0), 8)
+        || in_network(ip, Ipv4Addr::new(100, 64, 0, 0), 10) // carrier-grade NAT
+        || in_network(ip, Ipv4Addr::new(198, 18, 0, 0), 15) // benchmarking
+        || in_network(ip, Ipv4Addr::new(240, 0, 0, 0), 4) // reserved
+}
+
+fn is_bogon_v6(ip: &Ipv6Addr) -> bool {
+    if let Some(mapped) = ip.to_ipv4_mapped() {
+        return is_bogon_v4(mapped);
+    }
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || (first & 0xfe00) == 0xfc00 // unique local
+        || (first & 0xffc0) == 0xfe80 // link local
+        || first == 0x2001 && ip.segments()[1] == 0x0db8 // documentation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{test_single_dns_server, BlockPages, LookupStrategy};
+    use crate::test_support::udp_dns_server;
+
+    fn ips(addresses: &[&str]) -> Vec<IpAddr> {
+        addresses.iter().map(|address| address.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn flags_block_pages_and_bogons() {
+        let trusted = ips(&["93.184.215.14"]);
+        let block_page = poisoned_reason(&ips(&["10.10.34.35"]), &trusted).unwrap();
+        assert!(block_page.contains("block page"), "{}", block_page);
+        let bogon = poisoned_reason(&ips(&["93.184.215.14", "192.168.1.1"]), &trusted).unwrap();
+        assert!(bogon.contains("bogon"), "{}", bogon);
+        let mapped = poisoned_reason(&ips(&["::ffff:127.0.0.1"]), &trusted);
+        assert!(mapped.is_some());
+    }
+
+    #[test]
+    fn accepts_public_and_trusted_answers() {
+        assert_eq!(poisoned_reason(&ips(&["93.184.215.14"]), &[]), None);
+        // Internal names may resolve to private addresses when the trusted resolver agrees
+        let internal = ips(&["10.0.0.5"]);
+        assert_eq!(poisoned_reason(&internal, &internal), None);
+    }
+
+    #[tokio::test]
+    async fn flags_a_forged_answer_from_a_dns_server() {
+        let server = udp_dns_server(Ipv4Addr::new(127, 0, 0, 2)).await;
+        let mut result = test_single_dns_server(
+            "example.com".to_string(),
+            server,
+            LookupStrategy::Ipv4Only,
+            1,
+            &BlockPages::builtin(),
+            0,
+        )
+        .await;
+        apply_poisoning_check(&mut result, &ips(&["93.184.215.14"]));
+
+        let check = result.poisoning.as_ref().unwrap();
+        assert!(check.poisoned);
+        assert_eq!(check.resolved_ips, vec!["127.0.0.2".to_string()]);
+        assert!(!result.status);
+        assert!(matches!(result.error_kind, Some(TestError::PoisonedAnswer(_))));
+    }
+}
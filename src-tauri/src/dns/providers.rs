@@ -0,0 +1,434 @@
+use serde::{Deserialize, Serialize};
+use super::{bracket_ipv6, DnsServer, DnsTestResult, DnsTransport, DownloadSpeedResult};
+
+// Shipped catalog, in the same format as the user's dns.yml. Servers under
+// `dnsServers` that no provider lists are tested under their own address.
+const BUILTIN_DNS_CONFIG: &str = r#"
+dnsProviders:
+  - name: Shecan
+    primary: 178.22.122.100
+    secondary: 185.51.200.2
+    country: IR
+    notes: Unblocks sanctioned developer services
+  - name: 403.online
+    primary: 10.202.10.202
+    secondary: 10.202.10.102
+    country: IR
+    notes: Unblocks sanctioned developer services
+  - name: Radar Game
+    primary: 10.202.10.10
+    secondary: 10.202.10.11
+    country: IR
+    notes: Aimed at online games
+  - name: Begzar
+    primary: 185.55.226.26
+    secondary: 185.55.225.25
+    country: IR
+  - name: Pishgaman
+    primary: 5.202.100.100
+    secondary: 5.202.100.101
+    country: IR
+  - name: Google
+    primary: 8.8.8.8
+    secondary: 8.8.4.4
+    transports: [udp, tcp, tls, https]
+    tlsName: dns.google
+    dohUrl: https://dns.google/dns-query
+    country: US
+  - name: Cloudflare
+    primary: 1.1.1.1
+    secondary: 1.0.0.1
+    transports: [udp, tcp, tls, https]
+    tlsName: cloudflare-dns.com
+    dohUrl: https://cloudflare-dns.com/dns-query
+    country: US
+  - name: Quad9
+    primary: 9.9.9.9
+    secondary: 149.112.112.112
+    transports: [udp, tls, https]
+    tlsName: dns.quad9.net
+    dohUrl: https://dns.quad9.net/dns-query
+    country: CH
+dnsServers:
+  - 192.104.158.78
+  - 194.104.158.48
+  - 172.29.0.100
+  - 172.29.2.100
+  - 185.55.224.24
+  - 37.27.41.228
+  - 87.107.52.11
+  - 87.107.52.13
+  - 94.103.125.157
+  - 94.103.125.158
+"#;
+
+fn default_transports() -> Vec<DnsTransport> {
+    vec![DnsTransport::Udp]
+}
+
+/// A DNS service and the servers it runs.
+///
+/// `primary` and `secondary` are resolver addresses, tested over the first of
+/// the supported `transports`. With `test_all_transports` they are tested over
+/// each of them (DoT also needs `tls_name`, DoH needs `doh_url`). When
+/// `servers` is set, exactly those entries are tested instead.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsProvider {
+    pub name: String,
+    pub primary: String,
+    #[serde(default)]
+    pub secondary: Option<String>,
+    #[serde(default = "default_transports")]
+    pub transports: Vec<DnsTransport>,
+    #[serde(default)]
+    pub test_all_transports: bool,
+    #[serde(default)]
+    pub tls_name: Option<String>,
+    #[serde(default)]
+    pub doh_url: Option<String>,
+    #[serde(default)]
+    pub country: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+    #[serde(default)]
+    pub servers: Vec<String>,
+}
+
+impl DnsProvider {
+    // A lone server entry that no provider claims, named after itself
+    fn from_entry(entry: &str) -> Self {
+        Self {
+            name: entry.to_string(),
+            primary: entry.to_string(),
+            secondary: None,
+            transports: vec![DnsTransport::of(entry)],
+            test_all_transports: false,
+            tls_name: None,
+            doh_url: None,
+            country: None,
+            notes: None,
+            servers: vec![entry.to_string()],
+        }
+    }
+
+    // A server entry a known provider runs: named after the provider, but only
+    // the entry itself is tested, not the provider's other servers
+    fn from_known_entry(known: &DnsProvider, entry: &str) -> Self {
+        Self {
+            name: known.name.clone(),
+            country: known.country.clone(),
+            notes: known.notes.clone(),
+            ..Self::from_entry(entry)
+        }
+    }
+
+    /// Server entries to test for this provider, see [`DnsServer::parse`] for the format.
+    pub fn entries(&self) -> Vec<String> {
+        // Every transport multiplies the servers to test, so the rest are only
+        // tested when asked for
+        match self.test_all_transports {
+            true => self.entries_over(&self.transports),
+            false => self.entries_over(&self.transports[..self.transports.len().min(1)]),
+        }
+    }
+
+    fn entries_over(&self, transports: &[DnsTransport]) -> Vec<String> {
+        if !self.servers.is_empty() {
+            return self.servers.clone();
+        }
+
+        // An address already written with a transport prefix is used as is
+        if self.primary.contains("://") {
+            return std::iter::once(&self.primary)
+                .chain(self.secondary.as_ref())
+                .cloned()
+                .collect();
+        }
+
+        let addresses: Vec<String> = std::iter::once(&self.primary)
+            .chain(self.secondary.as_ref())
+            .map(|address| bracket_ipv6(address))
+            .collect();
+
+        let mut entries = Vec::new();
+        for transport in transports {
+            match transport {
+                DnsTransport::Udp => entries.extend(addresses.iter().cloned()),
+                DnsTransport::Tcp => entries.extend(addresses.iter().map(|a| format!("tcp://{}", a))),
+                DnsTransport::Tls => {
+                    if let Some(tls_name) = &self.tls_name {
+                        entries.extend(addresses.iter().map(|a| format!("tls://{}#{}", a, tls_name)));
+                    }
+                }
+                DnsTransport::Https => entries.extend(self.doh_url.clone()),
+            }
+        }
+        entries
+    }
+
+    // Whether the provider runs `server`, over any transport it supports
+    fn covers(&self, server: &DnsServer) -> bool {
+        self.entries_over(&self.transports)
+            .iter()
+            .any(|entry| DnsServer::parse(entry).ok().as_ref() == Some(server))
+    }
+}
+
+/// Contents of a dns.yml file. The 403unlocker file only has `dnsServers`;
+/// `dnsProviders` lets users describe their own resolvers in more detail.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsConfig {
+    #[serde(default)]
+    pub dns_providers: Vec<DnsProvider>,
+    #[serde(default)]
+    pub dns_servers: Vec<String>,
+}
+
+impl DnsConfig {
+    pub fn from_yaml(content: &str) -> anyhow::Result<Self> {
+        let config: DnsConfig = serde_yaml::from_str(content)?;
+        if config.dns_providers.is_empty() && config.dns_servers.is_empty() {
+            return Err(anyhow::anyhow!("dnsServers key missing"));
+        }
+        Ok(config)
+    }
+
+    pub fn builtin() -> Self {
+        Self::from_yaml(BUILTIN_DNS_CONFIG).expect("built-in DNS config is valid")
+    }
+
+    /// Resolves the config into the providers to test. Plain server entries are
+    /// tested as written; when a known provider (from this config or the
+    /// built-in catalog) lists them, they take its name and details. Servers
+    /// listed twice are only tested once.
+    pub fn into_catalog(self, known: &[DnsProvider]) -> Vec<DnsProvider> {
+        let mut catalog = self.dns_providers;
+
+        for entry in self.dns_servers {
+            let server = match DnsServer::parse(&entry) {
+                Ok(server) => server,
+                Err(e) => {
+                    eprintln!("Skipping invalid DNS server {}: {}", entry, e);
+                    continue;
+                }
+            };
+
+            if catalog.iter().any(|provider| provider.covers(&server)) {
+                continue;
+            }
+
+            let entry = entry.trim();
+            match known.iter().find(|provider| provider.covers(&server)) {
+                Some(known) => {
+                    // Entries of the same provider are grouped under it
+                    let grouped = catalog
+                        .iter_mut()
+                        .find(|provider| provider.name == known.name && !provider.servers.is_empty());
+                    match grouped {
+                        Some(provider) => provider.servers.push(entry.to_string()),
+                        None => catalog.push(DnsProvider::from_known_entry(known, entry)),
+                    }
+                }
+                None => catalog.push(DnsProvider::from_entry(entry)),
+            }
+        }
+
+        dedup_provider_entries(catalog)
+    }
+}
+
+// Drops entries naming a server an earlier provider already tests (e.g. `8.8.8.8` and `udp://8.8.8.8`)
+fn dedup_provider_entries(catalog: Vec<DnsProvider>) -> Vec<DnsProvider> {
+    let mut seen: Vec<DnsServer> = Vec::new();
+    let mut result = Vec::new();
+
+    for mut provider in catalog {
+        if result.iter().any(|p: &DnsProvider| p.name == provider.name) {
+            println!("Skipping duplicate DNS provider: {}", provider.name);
+            continue;
+        }
+
+        if !provider.servers.is_empty() {
+            provider.servers = new_servers(std::mem::take(&mut provider.servers), &mut seen);
+            match provider.servers.first() {
+                Some(primary) => {
+                    provider.primary = primary.clone();
+                    provider.secondary = provider.servers.get(1).cloned();
+                    result.push(provider);
+                }
+                None => println!("Skipping DNS provider without new servers: {}", provider.name),
+            }
+            continue;
+        }
+
+        let addresses = std::iter::once(provider.primary.clone()).chain(provider.secondary.clone()).collect();
+        let mut addresses = new_servers(addresses, &mut seen).into_iter();
+        match addresses.next() {
+            Some(primary) => {
+                provider.primary = primary;
+                provider.secondary = addresses.next();
+                result.push(provider);
+            }
+            None => println!("Skipping DNS provider without new servers: {}", provider.name),
+        }
+    }
+
+    result
+}
+
+// The entries naming a server not in `seen`, which they are added to
+fn new_servers(entries: Vec<String>, seen: &mut Vec<DnsServer>) -> Vec<String> {
+    let mut new = Vec::new();
+    for entry in entries {
+        let is_new = match DnsServer::parse(&entry) {
+            Ok(server) if !seen.contains(&server) => {
+                seen.push(server);
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                eprintln!("Skipping invalid DNS server {}: {}", entry, e);
+                false
+            }
+        };
+        if is_new {
+            new.push(entry);
+        } else {
+            println!("Skipping duplicate DNS server: {}", entry);
+        }
+    }
+    new
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DnsProviderResult {
+    pub provider: String,
+    pub country: Option<String>,
+    pub notes: Option<String>,
+    pub best_server: Option<String>,
+    pub best_response_time: Option<u64>,
+    pub results: Vec<DnsTestResult>,
+}
+
+impl DnsProviderResult {
+    // The best server is the usable one that answered fastest
+    pub fn new(provider: &DnsProvider, results: Vec<DnsTestResult>) -> Self {
+        let best = results
+            .iter()
+            .filter(|result| result.status)
+            .min_by_key(|result| result.response_time.unwrap_or(u64::MAX));
+
+        Self {
+            provider: provider.name.clone(),
+            country: provider.country.clone(),
+            notes: provider.notes.clone(),
+            best_server: best.map(|result| result.dns_server.clone()),
+            best_response_time: best.and_then(|result| result.response_time),
+            results,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DownloadProviderResult {
+    pub provider: String,
+    pub country: Option<String>,
+    pub notes: Option<String>,
+    pub best_server: Option<String>,
+    pub best_speed_mbps: f64,
+    pub results: Vec<DownloadSpeedResult>,
+}
+
+impl DownloadProviderResult {
+    // The best server is the successful one that downloaded fastest
+    pub fn new(provider: &DnsProvider, results: Vec<DownloadSpeedResult>) -> Self {
+        let best = results
+            .iter()
+            .filter(|result| result.success)
+            .max_by(|a, b| a.download_speed_mbps.total_cmp(&b.download_speed_mbps));
+
+        Self {
+            provider: provider.name.clone(),
+            country: provider.country.clone(),
+            notes: provider.notes.clone(),
+            best_server: best.map(|result| result.dns_server.clone()),
+            best_speed_mbps: best.map_or(0.0, |result| result.download_speed_mbps),
+            results,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog(yaml: &str) -> Vec<DnsProvider> {
+        let builtin = DnsConfig::builtin();
+        DnsConfig::from_yaml(yaml).unwrap().into_catalog(&builtin.dns_providers)
+    }
+
+    #[test]
+    fn known_servers_are_tested_as_written() {
+        let providers = catalog("dnsServers:\n  - 8.8.8.8\n");
+        assert_eq!(providers.len(), 1);
+        assert_eq!(providers[0].name, "Google");
+        assert_eq!(providers[0].country.as_deref(), Some("US"));
+        assert_eq!(providers[0].entries(), vec!["8.8.8.8".to_string()]);
+    }
+
+    #[test]
+    fn entries_of_one_provider_are_grouped() {
+        let providers = catalog("dnsServers:\n  - 8.8.8.8\n  - tls://8.8.4.4#dns.google\n  - 10.1.2.3\n");
+        let entries: Vec<(String, Vec<String>)> = providers
+            .iter()
+            .map(|provider| (provider.name.clone(), provider.entries()))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("Google".to_string(), vec!["8.8.8.8".to_string(), "tls://8.8.4.4#dns.google".to_string()]),
+                ("10.1.2.3".to_string(), vec!["10.1.2.3".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_servers_are_tested_once() {
+        let providers = catalog("dnsServers:\n  - 10.1.2.3\n  - udp://10.1.2.3\n  - 8.8.8.8\n  - 8.8.8.8\n");
+        let entries: Vec<String> = providers.iter().flat_map(|provider| provider.entries()).collect();
+        assert_eq!(entries, vec!["10.1.2.3".to_string(), "8.8.8.8".to_string()]);
+    }
+
+    #[test]
+    fn providers_are_tested_over_their_first_transport() {
+        let builtin = DnsConfig::builtin();
+        let google = builtin.dns_providers.iter().find(|provider| provider.name == "Google").unwrap();
+        assert_eq!(google.entries(), vec!["8.8.8.8", "8.8.4.4"]);
+        assert!(builtin
+            .dns_providers
+            .iter()
+            .flat_map(|provider| provider.entries())
+            .all(|entry| !entry.contains("://")));
+    }
+
+    #[test]
+    fn all_transports_are_tested_when_asked_for() {
+        let builtin = DnsConfig::builtin();
+        let mut google = builtin.dns_providers.into_iter().find(|provider| provider.name == "Google").unwrap();
+        google.test_all_transports = true;
+        assert_eq!(
+            google.entries(),
+            vec![
+                "8.8.8.8",
+                "8.8.4.4",
+                "tcp://8.8.8.8",
+                "tcp://8.8.4.4",
+                "tls://8.8.8.8#dns.google",
+                "tls://8.8.4.4#dns.google",
+                "https://dns.google/dns-query",
+            ]
+        );
+    }
+}
@@ -0,0 +1,599 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use trust_dns_resolver::config::{
+    LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+};
+use trust_dns_resolver::proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_resolver::proto::rr::{Name, Record, RecordType};
+use trust_dns_resolver::error::ResolveErrorKind;
+use trust_dns_resolver::name_server::{ConnectionProvider, GenericConnection, TokioConnectionProvider};
+use trust_dns_resolver::proto::xfer::{DnsHandle, DnsRequest, DnsRequestOptions, FirstAnswer};
+use trust_dns_resolver::TokioAsyncResolver;
+use url::Url;
+use crate::errors::TestError;
+
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsTransport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+impl DnsTransport {
+    /// Picks the transport from how the server entry is written, see [`DnsServer::parse`].
+    pub fn of(dns_server: &str) -> Self {
+        let scheme = dns_server.split_once("://").map(|(scheme, _)| scheme);
+        match scheme {
+            Some("tcp") => DnsTransport::Tcp,
+            Some("tls") => DnsTransport::Tls,
+            Some("https") => DnsTransport::Https,
+            // Only the tests' local DoH stand-in goes without TLS
+            #[cfg(test)]
+            Some("http") => DnsTransport::Https,
+            _ => DnsTransport::Udp,
+        }
+    }
+
+    pub fn default_port(self) -> u16 {
+        match self {
+            DnsTransport::Udp | DnsTransport::Tcp => 53,
+            DnsTransport::Tls => 853,
+            DnsTransport::Https => 443,
+        }
+    }
+
+    // DoH is not served by trust-dns here, see `lookup_ip_doh`
+    fn protocol(self) -> Option<Protocol> {
+        match self {
+            DnsTransport::Udp => Some(Protocol::Udp),
+            DnsTransport::Tcp => Some(Protocol::Tcp),
+            DnsTransport::Tls => Some(Protocol::Tls),
+            DnsTransport::Https => None,
+        }
+    }
+}
+
+/// Which address families to ask a resolver for.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LookupStrategy {
+    Ipv4Only,
+    Ipv6Only,
+    #[default]
+    Both,
+}
+
+impl LookupStrategy {
+    fn ip_strategy(self) -> LookupIpStrategy {
+        match self {
+            LookupStrategy::Ipv4Only => LookupIpStrategy::Ipv4Only,
+            LookupStrategy::Ipv6Only => LookupIpStrategy::Ipv6Only,
+            LookupStrategy::Both => LookupIpStrategy::Ipv4AndIpv6,
+        }
+    }
+
+    fn record_types(self) -> &'static [RecordType] {
+        match self {
+            LookupStrategy::Ipv4Only => &[RecordType::A],
+            LookupStrategy::Ipv6Only => &[RecordType::AAAA],
+            LookupStrategy::Both => &[RecordType::A, RecordType::AAAA],
+        }
+    }
+
+    fn allows(self, ip: &IpAddr) -> bool {
+        match self {
+            LookupStrategy::Ipv4Only => ip.is_ipv4(),
+            LookupStrategy::Ipv6Only => ip.is_ipv6(),
+            LookupStrategy::Both => true,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn of(ip: &IpAddr) -> Self {
+        if ip.is_ipv4() {
+            AddressFamily::Ipv4
+        } else {
+            AddressFamily::Ipv6
+        }
+    }
+}
+
+// Hosts in `host:port` and URL positions need IPv6 literals bracketed
+pub fn bracket_ipv6(address: &str) -> String {
+    match address.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+        _ => address.to_string(),
+    }
+}
+
+/// A single DNS server entry.
+///
+/// Entries are written the way most DNS proxies accept upstreams:
+/// - `8.8.8.8` or `udp://8.8.8.8` for plain DNS over UDP
+/// - `tcp://8.8.8.8` for plain DNS over TCP
+/// - `tls://1.1.1.1#cloudflare-dns.com` for DNS-over-TLS, the fragment being the TLS name
+/// - `https://dns.google/dns-query` for DNS-over-HTTPS
+///
+/// Any of the host forms may carry an explicit port, e.g. `tls://dns.example:8853`;
+/// IPv6 addresses then need brackets, e.g. `tcp://[2001:4860:4860::8888]:53`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct DnsServer {
+    pub transport: DnsTransport,
+    /// Resolver IP or hostname, or the full endpoint URL for DoH
+    pub address: String,
+    pub port: u16,
+    pub tls_name: Option<String>,
+}
+
+impl DnsServer {
+    pub fn parse(entry: &str) -> anyhow::Result<Self> {
+        let entry = entry.trim();
+        if !cfg!(test) && entry.starts_with("http://") {
+            return Err(anyhow::anyhow!("DNS-over-HTTPS needs an https:// URL: {}", entry));
+        }
+        let transport = DnsTransport::of(entry);
+
+        if transport == DnsTransport::Https {
+            let url = Url::parse(entry)?;
+            let host = url
+                .host_str()
+                .ok_or_else(|| anyhow::anyhow!("DoH URL has no host: {}", entry))?;
+            return Ok(Self {
+                transport,
+                tls_name: Some(host.to_string()),
+                port: url.port_or_known_default().unwrap_or(transport.default_port()),
+                address: entry.to_string(),
+            });
+        }
+
+        let rest = entry.split_once("://").map_or(entry, |(_, rest)| rest);
+        let (rest, tls_name) = match rest.split_once('#') {
+            Some((rest, name)) => (rest, Some(name.to_string())),
+            None => (rest, None),
+        };
+
+        // IPv6 literals may be bracketed, as in URLs: `tls://[2606:4700:4700::1111]#cloudflare-dns.com`
+        let unbracketed = rest
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+            .unwrap_or(rest);
+
+        let (address, port) = if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+            (ip.to_string(), transport.default_port())
+        } else if let Ok(socket_addr) = rest.parse::<SocketAddr>() {
+            (socket_addr.ip().to_string(), socket_addr.port())
+        } else {
+            match rest.rsplit_once(':') {
+                Some((host, port)) => (host.to_string(), port.parse()?),
+                None => (rest.to_string(), transport.default_port()),
+            }
+        };
+
+        if address.is_empty() {
+            return Err(anyhow::anyhow!("Invalid DNS server entry: {}", entry));
+        }
+
+        let tls_name = match transport {
+            DnsTransport::Tls => tls_name.or_else(|| Some(address.clone())),
+            _ => None,
+        };
+
+        Ok(Self {
+            transport,
+            address,
+            port,
+            tls_name,
+        })
+    }
+
+    // Hostnames (e.g. a DoT server given by name) are bootstrapped through the system resolver
+    async fn socket_addr(&self) -> anyhow::Result<SocketAddr> {
+        if let Ok(ip) = self.address.parse::<IpAddr>() {
+            return Ok(SocketAddr::new(ip, self.port));
+        }
+
+        tokio::net::lookup_host((self.address.as_str(), self.port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Could not resolve DNS server {}", self.address))
+    }
+
+    /// Builds a trust-dns resolver talking only to this server. DoH entries are
+    /// not handled here, see [`lookup_ip`].
+    pub async fn resolver(&self, mut opts: ResolverOpts) -> anyhow::Result<TokioAsyncResolver> {
+        let nameserver = self.name_server_config().await?;
+
+        // Retrying over TCP on truncation would hide which transport actually answered
+        opts.try_tcp_on_error = false;
+
+        let resolver_config = ResolverConfig::from_parts(None, vec![], vec![nameserver]);
+        Ok(TokioAsyncResolver::tokio(resolver_config, opts))
+    }
+
+    async fn name_server_config(&self) -> anyhow::Result<NameServerConfig> {
+        let protocol = self
+            .transport
+            .protocol()
+            .ok_or_else(|| anyhow::anyhow!("DoH servers are queried through lookup_ip"))?;
+
+        let mut nameserver = NameServerConfig::new(self.socket_addr().await?, protocol);
+        nameserver.tls_dns_name = self.tls_name.clone();
+        nameserver.trust_negative_responses = false;
+        Ok(nameserver)
+    }
+}
+
+pub fn default_resolver_opts() -> ResolverOpts {
+    let mut resolver_opts = ResolverOpts::default();
+    resolver_opts.timeout = DNS_TIMEOUT;
+    resolver_opts.attempts = 2;
+    resolver_opts
+}
+
+/// One A or AAAA record from an answer.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ResolvedRecord {
+    pub ip: IpAddr,
+    pub record_type: String,
+    pub ttl: u32,
+}
+
+impl ResolvedRecord {
+    fn new(ip: IpAddr, ttl: u32) -> Self {
+        let record_type = if ip.is_ipv4() { "A" } else { "AAAA" };
+        Self {
+            ip,
+            record_type: record_type.to_string(),
+            ttl,
+        }
+    }
+
+    fn from_record(record: &Record) -> Option<Self> {
+        let ip = record.data()?.ip_addr()?;
+        Some(Self::new(ip, record.ttl()))
+    }
+}
+
+/// The answer section and response code an upstream server gave.
+#[derive(Debug, Clone)]
+pub struct UpstreamAnswer {
+    pub response_code: ResponseCode,
+    pub answers: Vec<Record>,
+}
+
+/// A long-lived client for one DNS server entry, for answering many queries of
+/// any record type (see the local forwarder) rather than one address lookup.
+pub enum Upstream {
+    Resolver(Box<TokioAsyncResolver>),
+    Doh { client: Client, endpoint: String },
+}
+
+impl Upstream {
+    pub async fn connect(dns_server: &str) -> anyhow::Result<Self> {
+        let server = DnsServer::parse(dns_server)?;
+        if server.transport == DnsTransport::Https {
+            let client = Client::builder()
+                .timeout(DNS_TIMEOUT)
+                .user_agent("Mozilla/5.0 (compatible; Bargozin-DNS-Tester)")
+                .build()?;
+            return Ok(Upstream::Doh {
+                client,
+                endpoint: server.address,
+            });
+        }
+
+        // Answers are cached by the caller, which knows when to drop them
+        let mut opts = default_resolver_opts();
+        opts.cache_size = 0;
+        Ok(Upstream::Resolver(Box::new(server.resolver(opts).await?)))
+    }
+
+    pub async fn query(&self, name: &Name, record_type: RecordType) -> anyhow::Result<UpstreamAnswer> {
+        match self {
+            Upstream::Doh { client, endpoint } => {
+                let answer = doh_exchange(client, endpoint, name, record_type).await?;
+                Ok(UpstreamAnswer {
+                    response_code: answer.response_code(),
+                    answers: answer.answers().to_vec(),
+                })
+            }
+            Upstream::Resolver(resolver) => match resolver.lookup(name.clone(), record_type).await {
+                Ok(lookup) => Ok(UpstreamAnswer {
+                    response_code: ResponseCode::NoError,
+                    answers: lookup.records().to_vec(),
+                }),
+                // Negative answers are answers too, only failures to get one are errors
+                Err(e) => match e.kind() {
+                    ResolveErrorKind::NoRecordsFound { response_code, .. } => Ok(UpstreamAnswer {
+                        response_code: *response_code,
+                        answers: vec![],
+                    }),
+                    _ => Err(e.into()),
+                },
+            },
+        }
+    }
+}
+
+/// Sends whole messages to one DNS server entry, for queries needing header or
+/// EDNS flags the resolver API doesn't expose, such as DNSSEC's DO and CD bits.
+pub enum MessageClient {
+    Connection {
+        // Owns the connection's background task, which stops when it is dropped
+        provider: TokioConnectionProvider,
+        connection: GenericConnection,
+        // Where truncated UDP answers are asked again
+        tcp_fallback: Option<NameServerConfig>,
+    },
+    Doh { client: Client, endpoint: String },
+}
+
+impl MessageClient {
+    pub async fn connect(dns_server: &str) -> anyhow::Result<Self> {
+        let server = DnsServer::parse(dns_server)?;
+        if server.transport == DnsTransport::Https {
+            let client = Client::builder()
+                .timeout(DNS_TIMEOUT)
+                .user_agent("Mozilla/5.0 (compatible; Bargozin-DNS-Tester)")
+                .build()?;
+            return Ok(MessageClient::Doh {
+                client,
+                endpoint: server.address,
+            });
+        }
+
+        let nameserver = server.name_server_config().await?;
+        let tcp_fallback = (nameserver.protocol == Protocol::Udp).then(|| {
+            let mut tcp = nameserver.clone();
+            tcp.protocol = Protocol::Tcp;
+            tcp
+        });
+        let provider = TokioConnectionProvider::default();
+        let connection = provider.new_connection(&nameserver, &default_resolver_opts()).await?;
+        Ok(MessageClient::Connection {
+            provider,
+            connection,
+            tcp_fallback,
+        })
+    }
+
+    pub async fn send(&self, query: Message) -> anyhow::Result<Message> {
+        match self {
+            MessageClient::Doh { client, endpoint } => doh_send(client, endpoint, &query).await,
+            MessageClient::Connection {
+                provider,
+                connection,
+                tcp_fallback,
+            } => {
+                let answer = exchange(connection.clone(), query.clone()).await?;
+                match tcp_fallback {
+                    Some(tcp) if answer.truncated() => {
+                        let connection = provider.new_connection(tcp, &default_resolver_opts()).await?;
+                        exchange(connection, query).await
+                    }
+                    _ => Ok(answer),
+                }
+            }
+        }
+    }
+}
+
+async fn exchange(mut connection: GenericConnection, query: Message) -> anyhow::Result<Message> {
+    let request = DnsRequest::new(query, DnsRequestOptions::default());
+    let response = connection.send(request).first_answer().await?;
+    Ok(response.into_message())
+}
+
+// Resolve a hostname through a single DNS server entry, whatever its transport
+pub async fn lookup_ip(host: &str, dns_server: &str) -> anyhow::Result<Vec<IpAddr>> {
+    let records = lookup_records(host, dns_server, LookupStrategy::Both).await?;
+    Ok(records.into_iter().map(|record| record.ip).collect())
+}
+
+/// Every A and/or AAAA record the server returns for `host`, IPv4 first.
+pub async fn lookup_records(
+    host: &str,
+    dns_server: &str,
+    strategy: LookupStrategy,
+) -> anyhow::Result<Vec<ResolvedRecord>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        if !strategy.allows(&ip) {
+            return Err(anyhow::anyhow!("{} does not match the lookup strategy", ip));
+        }
+        return Ok(vec![ResolvedRecord::new(ip, 0)]);
+    }
+
+    let server = DnsServer::parse(dns_server)?;
+    let mut records = match server.transport {
+        DnsTransport::Https => lookup_records_doh(host, &server.address, strategy).await?,
+        _ => {
+            let mut opts = default_resolver_opts();
+            opts.ip_strategy = strategy.ip_strategy();
+            let resolver = server.resolver(opts).await?;
+            let lookup = resolver.lookup_ip(host).await?;
+            lookup
+                .as_lookup()
+                .records()
+                .iter()
+                .filter_map(ResolvedRecord::from_record)
+                .filter(|record| strategy.allows(&record.ip))
+                .collect()
+        }
+    };
+
+    if records.is_empty() {
+        return Err(TestError::NoRecords.into());
+    }
+    records.sort_by_key(|record| record.ip.is_ipv6());
+    Ok(records)
+}
+
+// DNS-over-HTTPS as described in RFC 8484, using POST with a wire-format message.
+// Done over reqwest rather than trust-dns' own DoH client so endpoints with a
+// path other than /dns-query work too.
+async fn lookup_records_doh(
+    host: &str,
+    endpoint: &str,
+    strategy: LookupStrategy,
+) -> anyhow::Result<Vec<ResolvedRecord>> {
+    let client = Client::builder()
+        .timeout(DNS_TIMEOUT)
+        .user_agent("Mozilla/5.0 (compatible; Bargozin-DNS-Tester)")
+        .build()?;
+
+    let mut name = Name::from_ascii(host)?;
+    name.set_fqdn(true);
+
+    let answers = futures::future::join_all(
+        strategy
+            .record_types()
+            .iter()
+            .map(|&record_type| doh_query(&client, endpoint, &name, record_type)),
+    )
+    .await;
+
+    // Like trust-dns' Ipv4AndIpv6 strategy, one family failing is fine as long as the other answered
+    let mut records = Vec::new();
+    let mut last_error = None;
+    let mut any_answered = false;
+    for answer in answers {
+        match answer {
+            Ok(answer) => {
+                any_answered = true;
+                records.extend(answer);
+            }
+            Err(e) => {
+                println!("DoH lookup for {} via {} failed: {}", host, endpoint, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) if !any_answered => Err(e),
+        _ => Ok(records),
+    }
+}
+
+async fn doh_query(
+    client: &Client,
+    endpoint: &str,
+    name: &Name,
+    record_type: RecordType,
+) -> anyhow::Result<Vec<ResolvedRecord>> {
+    let answer = doh_exchange(client, endpoint, name, record_type).await?;
+
+    match answer.response_code() {
+        ResponseCode::NoError => {}
+        code => return Err(TestError::from_response_code(code).into()),
+    }
+
+    Ok(answer
+        .answers()
+        .iter()
+        .filter(|record| record.record_type() == record_type)
+        .filter_map(ResolvedRecord::from_record)
+        .collect())
+}
+
+// Sends one question to a DoH endpoint and returns the server's answer, whatever its response code
+async fn doh_exchange(
+    client: &Client,
+    endpoint: &str,
+    name: &Name,
+    record_type: RecordType,
+) -> anyhow::Result<Message> {
+    let mut query = Message::new();
+    query
+        .set_id(0) // RFC 8484 recommends ID 0 so responses are cache friendly
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name.clone(), record_type));
+
+    doh_send(client, endpoint, &query).await
+}
+
+// Posts a whole message to a DoH endpoint (RFC 8484)
+async fn doh_send(client: &Client, endpoint: &str, query: &Message) -> anyhow::Result<Message> {
+    let response = client
+        .post(endpoint)
+        .header(reqwest::header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+        .header(reqwest::header::ACCEPT, DNS_MESSAGE_CONTENT_TYPE)
+        .body(query.to_vec()?)
+        .send()
+        .await
+        .map_err(|e| {
+            // A DoH server that doesn't answer in time is a resolver timeout, not an HTTP one
+            if e.is_timeout() {
+                anyhow::Error::from(TestError::ResolverTimeout)
+            } else {
+                anyhow::Error::from(e)
+            }
+        })?;
+
+    if !response.status().is_success() {
+        let reason = format!("DoH server returned HTTP {}", response.status());
+        return Err(TestError::ResolverFailed(reason).into());
+    }
+
+    let body = response.bytes().await?;
+    Ok(Message::from_vec(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+    use crate::test_support::{doh_server, udp_dns_server};
+
+    #[test]
+    fn parses_server_entries() {
+        let doh = DnsServer::parse("https://dns.example/custom-path").unwrap();
+        assert_eq!(doh.transport, DnsTransport::Https);
+        assert_eq!(doh.address, "https://dns.example/custom-path");
+        assert_eq!(doh.port, 443);
+        assert_eq!(DnsTransport::of("http://127.0.0.1:8053/dns-query"), DnsTransport::Https);
+
+        let dot = DnsServer::parse("tls://1.1.1.1#cloudflare-dns.com").unwrap();
+        assert_eq!(dot.transport, DnsTransport::Tls);
+        assert_eq!((dot.address.as_str(), dot.port), ("1.1.1.1", 853));
+        assert_eq!(dot.tls_name.as_deref(), Some("cloudflare-dns.com"));
+
+        let tcp = DnsServer::parse("tcp://[2001:4860:4860::8888]:5353").unwrap();
+        assert_eq!((tcp.address.as_str(), tcp.port), ("2001:4860:4860::8888", 5353));
+    }
+
+    #[tokio::test]
+    async fn resolves_through_a_doh_server() {
+        let endpoint = doh_server(Ipv4Addr::new(192, 0, 2, 7)).await;
+        let records = lookup_records("example.com", &endpoint, LookupStrategy::Ipv4Only).await.unwrap();
+        assert_eq!(records, vec![ResolvedRecord::new("192.0.2.7".parse().unwrap(), 60)]);
+
+        // The stand-in has no AAAA records, which is fine while A answers
+        let ips = lookup_ip("example.com", &endpoint).await.unwrap();
+        assert_eq!(ips, vec!["192.0.2.7".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn resolves_through_a_udp_server() {
+        let server = udp_dns_server(Ipv4Addr::new(192, 0, 2, 8)).await;
+        let ips = lookup_ip("example.com", &server).await.unwrap();
+        assert_eq!(ips, vec!["192.0.2.8".parse::<IpAddr>().unwrap()]);
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use super::{DnsTestResult, DownloadSpeedResult};
+
+// Weights of the score components; components a server has no data for are left
+// out and the others scaled up to match
+const REACHABILITY_WEIGHT: f64 = 0.35;
+const LATENCY_WEIGHT: f64 = 0.20;
+const STABILITY_WEIGHT: f64 = 0.10;
+const SPEED_WEIGHT: f64 = 0.35;
+
+/// Results of the latest DNS and download runs, which rankings are computed from.
+#[derive(Debug, Default, Clone)]
+pub struct TestResultSet {
+    pub dns_results: Vec<DnsTestResult>,
+    pub download_results: Vec<DownloadSpeedResult>,
+}
+
+/// Score of one DNS server, from 0 to 100, and what went into it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DnsScore {
+    pub dns_server: String,
+    pub provider: Option<String>,
+    pub score: f64,
+    pub domains_tested: usize,
+    pub domains_reachable: usize,
+    pub median_latency_ms: Option<u64>,
+    pub jitter_ms: Option<f64>,
+    pub loss_rate: Option<f64>,
+    pub download_speed_mbps: Option<f64>,
+    pub poisoned: bool,
+}
+
+impl DnsScore {
+    // Whether the server can be recommended at all
+    fn usable(&self) -> bool {
+        if self.poisoned {
+            return false;
+        }
+        if self.domains_tested > 0 {
+            self.domains_reachable > 0
+        } else {
+            self.download_speed_mbps.is_some_and(|speed| speed > 0.0)
+        }
+    }
+
+    fn label(&self) -> String {
+        match &self.provider {
+            Some(provider) if *provider != self.dns_server => format!("{} ({})", provider, self.dns_server),
+            _ => self.dns_server.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DnsRecommendation {
+    pub ranking: Vec<DnsScore>,
+    pub primary: Option<String>,
+    pub secondary: Option<String>,
+    pub explanation: Vec<String>,
+}
+
+// What is known about one server across all its results
+#[derive(Default)]
+struct ServerStats {
+    provider: Option<String>,
+    domains_tested: usize,
+    domains_reachable: usize,
+    latencies: Vec<u64>,
+    jitters: Vec<f64>,
+    loss_rates: Vec<f64>,
+    multi_round: bool,
+    speeds: Vec<f64>,
+    poisoned: bool,
+}
+
+/// Ranks every tested server, best first, and picks a primary and a secondary
+/// from different providers where possible so one outage can't take out both.
+pub fn rank_dns_servers(results: &TestResultSet) -> DnsRecommendation {
+    let mut stats: HashMap<String, ServerStats> = HashMap::new();
+
+    for result in &results.dns_results {
+        let server = stats.entry(result.dns_server.clone()).or_default();
+        server.provider = server.provider.take().or_else(|| result.provider.clone());
+        server.domains_tested += 1;
+        server.poisoned |= result.poisoning.as_ref().is_some_and(|check| check.poisoned);
+        if result.status {
+            server.domains_reachable += 1;
+            if let Some(latency) = result.http_latency.median.or(result.http_response_time) {
+                server.latencies.push(latency);
+            }
+        }
+        if result.http_latency.rounds > 1 {
+            server.multi_round = true;
+            server.jitters.extend(result.http_latency.jitter);
+        }
+        if result.dns_latency.rounds > 1 {
+            server.loss_rates.push(result.dns_latency.loss_rate);
+        }
+        if result.http_latency.rounds > 1 {
+            server.loss_rates.push(result.http_latency.loss_rate);
+        }
+    }
+
+    for result in &results.download_results {
+        let server = stats.entry(result.dns_server.clone()).or_default();
+        server.provider = server.provider.take().or_else(|| result.provider.clone());
+        server.speeds.push(if result.success { result.download_speed_mbps } else { 0.0 });
+    }
+
+    let mut scores: Vec<DnsScore> = stats
+        .into_iter()
+        .map(|(dns_server, server)| DnsScore {
+            dns_server,
+            provider: server.provider.clone(),
+            score: 0.0,
+            domains_tested: server.domains_tested,
+            domains_reachable: server.domains_reachable,
+            median_latency_ms: median(&server.latencies),
+            jitter_ms: mean(&server.jitters).filter(|_| server.multi_round),
+            loss_rate: mean(&server.loss_rates),
+            download_speed_mbps: mean(&server.speeds),
+            poisoned: server.poisoned,
+        })
+        .collect();
+
+    // Latency and speed are scored relative to the best server in the run
+    let best_latency = scores.iter().filter_map(|s| s.median_latency_ms).min().unwrap_or(0).max(1) as f64;
+    let best_speed = scores.iter().filter_map(|s| s.download_speed_mbps).fold(0.0, f64::max);
+
+    for score in &mut scores {
+        score.score = composite_score(score, best_latency, best_speed);
+    }
+    scores.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then(a.median_latency_ms.unwrap_or(u64::MAX).cmp(&b.median_latency_ms.unwrap_or(u64::MAX)))
+    });
+
+    let primary = scores.iter().find(|s| s.usable());
+    let secondary = primary.and_then(|primary| {
+        let others = || scores.iter().filter(|s| s.usable() && s.dns_server != primary.dns_server);
+        others()
+            .find(|s| s.provider.is_none() || s.provider != primary.provider)
+            .or_else(|| others().next())
+    });
+
+    let explanation = explain(&scores, primary, secondary);
+    DnsRecommendation {
+        primary: primary.map(|s| s.dns_server.clone()),
+        secondary: secondary.map(|s| s.dns_server.clone()),
+        explanation,
+        ranking: scores,
+    }
+}
+
+/// The server to fetch `url` through: the fastest one in the latest download
+/// test of that URL, otherwise the primary of the overall ranking.
+pub fn best_download_server(results: &TestResultSet, url: &str) -> Option<String> {
+    results
+        .download_results
+        .iter()
+        .filter(|result| result.success && result.url == url)
+        .max_by(|a, b| a.download_speed_mbps.total_cmp(&b.download_speed_mbps))
+        .map(|result| result.dns_server.clone())
+        .or_else(|| rank_dns_servers(results).primary)
+}
+
+fn composite_score(score: &DnsScore, best_latency: f64, best_speed: f64) -> f64 {
+    if score.poisoned {
+        return 0.0;
+    }
+
+    let mut components = Vec::new();
+    if score.domains_tested > 0 {
+        let reachability = score.domains_reachable as f64 / score.domains_tested as f64;
+        components.push((REACHABILITY_WEIGHT, reachability));
+    }
+    if let Some(latency) = score.median_latency_ms {
+        components.push((LATENCY_WEIGHT, best_latency / latency.max(1) as f64));
+    }
+    if score.jitter_ms.is_some() || score.loss_rate.is_some() {
+        let jitter = score.jitter_ms.unwrap_or(0.0);
+        let latency = score.median_latency_ms.unwrap_or(1).max(1) as f64;
+        let stability = (1.0 - score.loss_rate.unwrap_or(0.0)) / (1.0 + jitter / latency);
+        components.push((STABILITY_WEIGHT, stability));
+    }
+    if let Some(speed) = score.download_speed_mbps.filter(|_| best_speed > 0.0) {
+        components.push((SPEED_WEIGHT, speed / best_speed));
+    }
+
+    let total_weight: f64 = components.iter().map(|(weight, _)| weight).sum();
+    if total_weight == 0.0 {
+        return 0.0;
+    }
+    let weighted: f64 = components.iter().map(|(weight, value)| weight * value).sum();
+    (weighted / total_weight * 100.0 * 10.0).round() / 10.0
+}
+
+fn explain(scores: &[DnsScore], primary: Option<&DnsScore>, secondary: Option<&DnsScore>) -> Vec<String> {
+    let mut explanation = Vec::new();
+
+    let Some(primary) = primary else {
+        explanation.push("No tested DNS server is usable: none reached a domain or downloaded without a poisoned answer".to_string());
+        return explanation;
+    };
+
+    explanation.push(format!("{} is recommended as primary with {:.1}/100: {}", primary.label(), primary.score, describe(primary)));
+
+    match secondary {
+        Some(secondary) if secondary.provider.is_some() && secondary.provider == primary.provider => {
+            explanation.push(format!(
+                "{} is the secondary with {:.1}/100; no other provider was usable, so both servers share a provider",
+                secondary.label(),
+                secondary.score
+            ));
+        }
+        Some(secondary) => {
+            explanation.push(format!(
+                "{} is the secondary with {:.1}/100, taken from a different provider so one outage can't take out both: {}",
+                secondary.label(),
+                secondary.score,
+                describe(secondary)
+            ));
+        }
+        None => explanation.push("No other usable server was found for a secondary".to_string()),
+    }
+
+    let poisoned = scores.iter().filter(|s| s.poisoned).count();
+    if poisoned > 0 {
+        explanation.push(format!("{} server(s) were ruled out for returning poisoned answers", poisoned));
+    }
+    let unreachable = scores
+        .iter()
+        .filter(|s| !s.poisoned && s.domains_tested > 0 && s.domains_reachable == 0)
+        .count();
+    if unreachable > 0 {
+        explanation.push(format!("{} server(s) were ruled out for not reaching any domain", unreachable));
+    }
+    explanation
+}
+
+fn describe(score: &DnsScore) -> String {
+    let mut parts = Vec::new();
+    if score.domains_tested > 0 {
+        parts.push(format!("reached {}/{} domains", score.domains_reachable, score.domains_tested));
+    }
+    if let Some(latency) = score.median_latency_ms {
+        parts.push(format!("median latency {} ms", latency));
+    }
+    if let Some(jitter) = score.jitter_ms {
+        parts.push(format!("jitter {:.1} ms", jitter));
+    }
+    if let Some(loss) = score.loss_rate.filter(|loss| *loss > 0.0) {
+        parts.push(format!("{:.0}% loss", loss * 100.0));
+    }
+    if let Some(speed) = score.download_speed_mbps {
+        parts.push(format!("{:.2} Mbps download", speed));
+    }
+    parts.join(", ")
+}
+
+fn median(values: &[u64]) -> Option<u64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted.get(sorted.len().saturating_sub(1) / 2).copied()
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().sum::<f64>() / values.len() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsTransport, PoisoningCheck};
+    use crate::test_support;
+    use crate::timing::PhaseTimings;
+
+    fn dns_result(dns_server: &str, provider: Option<&str>, latency: Option<u64>) -> DnsTestResult {
+        let mut result = test_support::dns_result(dns_server, "example.com", latency);
+        result.provider = provider.map(str::to_string);
+        result
+    }
+
+    fn download_result(dns_server: &str, speed: Option<f64>) -> DownloadSpeedResult {
+        DownloadSpeedResult {
+            dns_server: dns_server.to_string(),
+            url: "https://example.com/file".to_string(),
+            success: speed.is_some(),
+            download_speed_mbps: speed.unwrap_or(0.0),
+            downloaded_bytes: 0,
+            test_duration_seconds: 0.0,
+            error_message: None,
+            error_kind: None,
+            resolution_time_ms: None,
+            session_id: 0,
+            transport: DnsTransport::Udp,
+            provider: None,
+            connected_address: None,
+            address_family: None,
+            address_attempts: vec![],
+            cdn_edge: None,
+            steering: None,
+            throughput_samples: vec![],
+            peak_speed_mbps: None,
+            steady_state_speed_mbps: None,
+            timings: PhaseTimings::default(),
+            segmented: None,
+        }
+    }
+
+    fn score(domains: (usize, usize), latency: Option<u64>, loss_rate: Option<f64>, speed: Option<f64>) -> DnsScore {
+        DnsScore {
+            dns_server: "192.0.2.1".to_string(),
+            provider: None,
+            score: 0.0,
+            domains_tested: domains.1,
+            domains_reachable: domains.0,
+            median_latency_ms: latency,
+            jitter_ms: None,
+            loss_rate,
+            download_speed_mbps: speed,
+            poisoned: false,
+        }
+    }
+
+    fn ranked(recommendation: &DnsRecommendation) -> Vec<&str> {
+        recommendation.ranking.iter().map(|score| score.dns_server.as_str()).collect()
+    }
+
+    #[test]
+    fn components_are_weighted_by_their_share() {
+        // Reachability 1 and latency 0.5, stability and speed 0
+        assert_eq!(composite_score(&score((2, 2), Some(100), Some(1.0), Some(0.0)), 50.0, 10.0), 45.0);
+        // Latency 1 and stability 1, reachability and speed 0
+        assert_eq!(composite_score(&score((0, 2), Some(50), Some(0.0), Some(0.0)), 50.0, 10.0), 30.0);
+        // Speed 1 alone, everything else 0
+        assert_eq!(composite_score(&score((0, 2), Some(u64::MAX), Some(1.0), Some(10.0)), 50.0, 10.0), 35.0);
+
+        let mut poisoned = score((2, 2), Some(50), Some(0.0), Some(10.0));
+        assert_eq!(composite_score(&poisoned, 50.0, 10.0), 100.0);
+        poisoned.poisoned = true;
+        assert_eq!(composite_score(&poisoned, 50.0, 10.0), 0.0);
+    }
+
+    #[test]
+    fn missing_components_are_left_out() {
+        // Only downloaded: the speed is the whole score
+        assert_eq!(composite_score(&score((0, 0), None, None, Some(5.0)), 1.0, 10.0), 50.0);
+        // Never downloaded: reachability and latency, 0.35 and 0.20 of 0.55
+        assert_eq!(composite_score(&score((2, 2), Some(100), None, None), 50.0, 0.0), 81.8);
+        // Nobody downloaded anything, so speed can't count against anyone
+        assert_eq!(composite_score(&score((2, 2), Some(50), None, Some(0.0)), 50.0, 0.0), 100.0);
+        assert_eq!(composite_score(&score((0, 0), None, None, None), 1.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn ranks_dns_and_download_results_together() {
+        let results = TestResultSet {
+            dns_results: vec![
+                dns_result("1.1.1.1", Some("Cloudflare"), Some(50)),
+                dns_result("8.8.8.8", Some("Google"), Some(100)),
+                dns_result("9.9.9.9", Some("Quad9"), None),
+            ],
+            download_results: vec![
+                download_result("1.1.1.1", Some(5.0)),
+                download_result("8.8.8.8", Some(10.0)),
+                download_result("10.0.0.1", Some(2.5)),
+            ],
+        };
+        let recommendation = rank_dns_servers(&results);
+        let scores: Vec<(&str, f64)> = recommendation
+            .ranking
+            .iter()
+            .map(|score| (score.dns_server.as_str(), score.score))
+            .collect();
+        assert_eq!(
+            scores,
+            vec![
+                // 0.35 + 0.20 * 0.5 + 0.35 of 0.90
+                ("8.8.8.8", 88.9),
+                // 0.35 + 0.20 + 0.35 * 0.5 of 0.90
+                ("1.1.1.1", 80.6),
+                // Downloaded a quarter as fast as the best, nothing else known
+                ("10.0.0.1", 25.0),
+                ("9.9.9.9", 0.0),
+            ]
+        );
+        assert_eq!(recommendation.primary.as_deref(), Some("8.8.8.8"));
+        let unreachable = &recommendation.ranking[3];
+        assert_eq!((unreachable.domains_tested, unreachable.median_latency_ms), (1, None));
+    }
+
+    #[test]
+    fn ties_go_to_the_lower_latency() {
+        let results = TestResultSet {
+            dns_results: vec![
+                dns_result("192.0.2.2", None, Some(1001)),
+                dns_result("192.0.2.1", None, Some(1000)),
+                dns_result("192.0.2.3", None, Some(50)),
+            ],
+            download_results: vec![],
+        };
+        let recommendation = rank_dns_servers(&results);
+        assert_eq!(ranked(&recommendation), vec!["192.0.2.3", "192.0.2.1", "192.0.2.2"]);
+        assert_eq!(recommendation.ranking[1].score, recommendation.ranking[2].score);
+    }
+
+    #[test]
+    fn secondary_comes_from_another_provider() {
+        let results = TestResultSet {
+            dns_results: vec![
+                dns_result("1.1.1.1", Some("Cloudflare"), Some(10)),
+                dns_result("1.0.0.1", Some("Cloudflare"), Some(11)),
+                dns_result("8.8.8.8", Some("Google"), Some(40)),
+            ],
+            download_results: vec![],
+        };
+        let recommendation = rank_dns_servers(&results);
+        assert_eq!(ranked(&recommendation), vec!["1.1.1.1", "1.0.0.1", "8.8.8.8"]);
+        assert_eq!(recommendation.primary.as_deref(), Some("1.1.1.1"));
+        assert_eq!(recommendation.secondary.as_deref(), Some("8.8.8.8"));
+        assert!(recommendation.explanation[1].contains("different provider"));
+    }
+
+    #[test]
+    fn secondary_shares_the_provider_when_nothing_else_is_usable() {
+        let mut poisoned = dns_result("8.8.8.8", Some("Google"), Some(5));
+        poisoned.poisoning = Some(PoisoningCheck {
+            poisoned: true,
+            resolved_ips: vec!["10.10.34.35".to_string()],
+            trusted_ips: vec!["93.184.216.34".to_string()],
+            reason: None,
+        });
+        let results = TestResultSet {
+            dns_results: vec![
+                dns_result("1.1.1.1", Some("Cloudflare"), Some(10)),
+                dns_result("1.0.0.1", Some("Cloudflare"), Some(11)),
+                poisoned,
+                dns_result("9.9.9.9", Some("Quad9"), None),
+            ],
+            download_results: vec![],
+        };
+        let recommendation = rank_dns_servers(&results);
+        assert_eq!(recommendation.primary.as_deref(), Some("1.1.1.1"));
+        assert_eq!(recommendation.secondary.as_deref(), Some("1.0.0.1"));
+        assert!(recommendation.explanation[1].contains("share a provider"));
+        assert!(recommendation.explanation.contains(&"1 server(s) were ruled out for returning poisoned answers".to_string()));
+        assert!(recommendation.explanation.contains(&"1 server(s) were ruled out for not reaching any domain".to_string()));
+    }
+
+    #[test]
+    fn nothing_is_recommended_without_a_usable_server() {
+        let results = TestResultSet {
+            dns_results: vec![dns_result("9.9.9.9", None, None)],
+            download_results: vec![download_result("9.9.9.9", None)],
+        };
+        let recommendation = rank_dns_servers(&results);
+        assert_eq!((recommendation.primary, recommendation.secondary), (None, None));
+        assert_eq!(recommendation.ranking[0].download_speed_mbps, Some(0.0));
+    }
+}
@@ -0,0 +1,250 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use futures::stream::{self, BoxStream, StreamExt};
+use reqwest::header::{ACCEPT_RANGES, RANGE};
+use reqwest::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use url::Url;
+use crate::errors::TestError;
+use crate::timing::transfer_speed_mbps;
+use crate::utils::CustomDnsResolver;
+
+// More connections than this stops telling us about the link and starts
+// looking like abuse to the mirror
+pub const MAX_SEGMENTS: usize = 16;
+// Below this a segment is mostly connection setup
+const MIN_SEGMENT_BYTES: u64 = 256 * 1024;
+
+/// One connection of a segmented download.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SegmentResult {
+    pub index: usize,
+    /// First and last byte of the range, inclusive
+    pub range_start: u64,
+    pub range_end: u64,
+    pub downloaded_bytes: u64,
+    /// From the start of the parallel transfer to this connection's last byte
+    pub duration_ms: u64,
+    pub speed_mbps: f64,
+    pub error: Option<String>,
+}
+
+/// How a download test split the file across connections, or why it didn't.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SegmentedDownload {
+    pub requested_segments: usize,
+    pub segments: Vec<SegmentResult>,
+    /// Set when the test fell back to a single stream
+    pub fallback_reason: Option<String>,
+}
+
+impl SegmentedDownload {
+    pub fn fallback(requested_segments: usize, reason: String) -> Self {
+        Self {
+            requested_segments,
+            segments: vec![],
+            fallback_reason: Some(reason),
+        }
+    }
+}
+
+/// Something that happened on one of the download's connections.
+pub enum SegmentEvent {
+    /// Bytes read, or the error that ended the segment
+    Chunk(usize, reqwest::Result<u64>),
+    Done(usize),
+}
+
+/// Bookkeeping for a segment while the download runs.
+pub struct SegmentState {
+    pub range: (u64, u64),
+    pub downloaded_bytes: u64,
+    pub started: Option<Instant>,
+    pub finished: Option<Instant>,
+    pub error: Option<String>,
+}
+
+impl SegmentState {
+    pub fn new(range: (u64, u64), started: Instant) -> Self {
+        Self {
+            range,
+            downloaded_bytes: 0,
+            started: Some(started),
+            finished: None,
+            error: None,
+        }
+    }
+
+    // A segment whose request failed before it got any bytes
+    pub fn failed(range: (u64, u64), error: &anyhow::Error) -> Self {
+        Self {
+            range,
+            downloaded_bytes: 0,
+            started: None,
+            finished: None,
+            error: Some(format!("{:#}", error)),
+        }
+    }
+
+    pub fn result(&self, index: usize) -> SegmentResult {
+        let duration = match (self.started, self.finished) {
+            (Some(started), Some(finished)) => finished.duration_since(started),
+            (Some(started), None) => started.elapsed(),
+            _ => Default::default(),
+        };
+        SegmentResult {
+            index,
+            range_start: self.range.0,
+            range_end: self.range.1,
+            downloaded_bytes: self.downloaded_bytes,
+            duration_ms: duration.as_millis() as u64,
+            speed_mbps: transfer_speed_mbps(self.downloaded_bytes, duration),
+            error: self.error.clone(),
+        }
+    }
+}
+
+/// The length to split, if the response says the server takes byte ranges and
+/// is big enough for `segments` connections to be worth it.
+pub fn ranged_length(response: &Response, segments: usize) -> Result<u64, String> {
+    let accepts_bytes = response
+        .headers()
+        .get(ACCEPT_RANGES)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|unit| unit.trim().eq_ignore_ascii_case("bytes")));
+    if !accepts_bytes {
+        return Err("server does not advertise Accept-Ranges: bytes".to_string());
+    }
+    let length = response
+        .content_length()
+        .ok_or_else(|| "server did not send a Content-Length".to_string())?;
+    if length < segments as u64 * MIN_SEGMENT_BYTES {
+        return Err(format!("{} bytes is too small to split into {} segments", length, segments));
+    }
+    Ok(length)
+}
+
+// `segments` contiguous inclusive ranges covering `length` bytes
+pub fn plan_segments(length: u64, segments: usize) -> Vec<(u64, u64)> {
+    let segments = segments as u64;
+    (0..segments)
+        .map(|index| (index * length / segments, (index + 1) * length / segments - 1))
+        .collect()
+}
+
+/// Requests one range of `url` on a connection of its own, to the address the
+/// first request went to. Another host after a redirect is looked up through
+/// the same DNS server.
+pub async fn open_segment(
+    resolver: CustomDnsResolver,
+    host: &str,
+    address: IpAddr,
+    url: &Url,
+    range: (u64, u64),
+    timeout: Duration,
+) -> anyhow::Result<Response> {
+    // A client per segment, otherwise they would share one pooled connection
+    let client = resolver.pin(host, vec![address]).client_builder().timeout(timeout).build()?;
+    let response = client
+        .get(url.clone())
+        .header(RANGE, format!("bytes={}-{}", range.0, range.1))
+        .send()
+        .await?;
+    // A server that ignores Range answers 200 with the whole file
+    match response.status() {
+        StatusCode::PARTIAL_CONTENT => Ok(response),
+        StatusCode::OK => Err(anyhow::anyhow!("server ignored the Range header")),
+        status => Err(TestError::HttpStatus(status.as_u16()).into()),
+    }
+}
+
+/// The body of `response` as segment `index`: chunk sizes, cut off after
+/// `limit` bytes, then a `Done` marker. Stopping early drops the response and
+/// with it the connection.
+pub fn segment_events(index: usize, response: Response, limit: u64) -> BoxStream<'static, SegmentEvent> {
+    response
+        .bytes_stream()
+        .scan(limit, |remaining, chunk| {
+            if *remaining == 0 {
+                return futures::future::ready(None);
+            }
+            let chunk = chunk.map(|bytes| {
+                let len = (bytes.len() as u64).min(*remaining);
+                *remaining -= len;
+                len
+            });
+            futures::future::ready(Some(chunk))
+        })
+        .map(move |chunk| SegmentEvent::Chunk(index, chunk))
+        .chain(stream::once(async move { SegmentEvent::Done(index) }))
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(accept_ranges: Option<&str>, length: usize) -> Response {
+        let mut builder = http::Response::builder();
+        if let Some(units) = accept_ranges {
+            builder = builder.header(ACCEPT_RANGES, units);
+        }
+        Response::from(builder.body(vec![0u8; length]).unwrap())
+    }
+
+    #[test]
+    fn only_splits_ranged_responses_big_enough_for_every_segment() {
+        let enough = 4 * MIN_SEGMENT_BYTES as usize;
+        assert_eq!(ranged_length(&response(Some("bytes"), enough), 4), Ok(enough as u64));
+        assert_eq!(ranged_length(&response(Some("none, Bytes"), enough), 4), Ok(enough as u64));
+
+        let refused = ranged_length(&response(Some("none"), enough), 4).unwrap_err();
+        assert!(refused.contains("Accept-Ranges"), "{}", refused);
+        assert!(ranged_length(&response(None, enough), 4).is_err());
+
+        let small = ranged_length(&response(Some("bytes"), enough - 1), 4).unwrap_err();
+        assert!(small.contains("too small"), "{}", small);
+        assert!(ranged_length(&response(Some("bytes"), enough - 1), 3).is_ok());
+    }
+
+    #[test]
+    fn segments_cover_the_length_exactly() {
+        for (length, segments) in [(1_000_000, 4), (1_000_003, 3), (7, 7), (MAX_SEGMENTS as u64 * 1000 + 15, MAX_SEGMENTS)] {
+            let ranges = plan_segments(length, segments);
+            assert_eq!(ranges.len(), segments);
+            assert_eq!(ranges[0].0, 0);
+            assert_eq!(ranges.last().unwrap().1, length - 1);
+            for pair in ranges.windows(2) {
+                assert_eq!(pair[1].0, pair[0].1 + 1, "{:?}", ranges);
+            }
+            let covered: u64 = ranges.iter().map(|(start, end)| end - start + 1).sum();
+            assert_eq!(covered, length);
+            // Sizes differ by at most a byte
+            let sizes: Vec<u64> = ranges.iter().map(|(start, end)| end - start + 1).collect();
+            assert!(sizes.iter().max().unwrap() - sizes.iter().min().unwrap() <= 1, "{:?}", sizes);
+        }
+    }
+
+    async fn events(index: usize, length: usize, limit: u64) -> (u64, Vec<usize>) {
+        let mut bytes = 0;
+        let mut done = vec![];
+        let mut events = segment_events(index, response(Some("bytes"), length), limit);
+        while let Some(event) = events.next().await {
+            match event {
+                SegmentEvent::Chunk(chunk_index, chunk) => {
+                    assert_eq!(chunk_index, index);
+                    bytes += chunk.unwrap();
+                }
+                SegmentEvent::Done(done_index) => done.push(done_index),
+            }
+        }
+        (bytes, done)
+    }
+
+    #[tokio::test]
+    async fn segment_events_stop_at_the_limit() {
+        assert_eq!(events(2, 1000, 300).await, (300, vec![2]));
+        assert_eq!(events(0, 1000, u64::MAX).await, (1000, vec![0]));
+        assert_eq!(events(1, 1000, 1000).await, (1000, vec![1]));
+    }
+}
@@ -0,0 +1,210 @@
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+// How often a running download reports its progress
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+// TCP slow start ramps up over roughly the first second of a transfer, longer
+// ones are judged on their last three quarters
+const SLOW_START_MS: u64 = 1000;
+
+/// Throughput over one progress interval of a download.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ThroughputSample {
+    /// Since the first byte of the body
+    pub elapsed_ms: u64,
+    pub downloaded_bytes: u64,
+    /// Over this interval only
+    pub speed_mbps: f64,
+}
+
+/// Sent while a download test runs, every [`PROGRESS_INTERVAL`].
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DownloadProgress {
+    pub dns_server: String,
+    pub url: String,
+    pub session_id: u64,
+    pub downloaded_bytes: u64,
+    /// From Content-Length, when the server sent one
+    pub total_bytes: Option<u64>,
+    pub elapsed_ms: u64,
+    pub speed_mbps: f64,
+}
+
+pub type ProgressCallback<'a> = dyn Fn(&DownloadProgress) + Send + Sync + 'a;
+
+#[derive(Debug, Default, Clone)]
+pub struct ThroughputSummary {
+    pub samples: Vec<ThroughputSample>,
+    pub peak_speed_mbps: Option<f64>,
+    pub steady_state_speed_mbps: Option<f64>,
+}
+
+/// Turns a growing byte count into per-interval samples.
+pub struct ThroughputMeter {
+    started: Instant,
+    last_elapsed_ms: u64,
+    last_bytes: u64,
+    samples: Vec<ThroughputSample>,
+}
+
+impl ThroughputMeter {
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            last_elapsed_ms: 0,
+            last_bytes: 0,
+            samples: vec![],
+        }
+    }
+
+    pub fn sample(&mut self, downloaded_bytes: u64) -> ThroughputSample {
+        self.sample_at(downloaded_bytes, self.started.elapsed())
+    }
+
+    fn sample_at(&mut self, downloaded_bytes: u64, elapsed: Duration) -> ThroughputSample {
+        let elapsed_ms = elapsed.as_millis() as u64;
+        let sample = ThroughputSample {
+            elapsed_ms,
+            downloaded_bytes,
+            speed_mbps: mbps(
+                downloaded_bytes.saturating_sub(self.last_bytes),
+                elapsed_ms.saturating_sub(self.last_elapsed_ms),
+            ),
+        };
+        self.last_elapsed_ms = elapsed_ms;
+        self.last_bytes = downloaded_bytes;
+        self.samples.push(sample.clone());
+        sample
+    }
+
+    pub fn finish(self, downloaded_bytes: u64) -> ThroughputSummary {
+        let elapsed = self.started.elapsed();
+        self.finish_at(downloaded_bytes, elapsed)
+    }
+
+    fn finish_at(mut self, downloaded_bytes: u64, elapsed: Duration) -> ThroughputSummary {
+        // The tail since the last tick, unless it is too short to say anything
+        if elapsed.as_millis() as u64 > self.last_elapsed_ms + PROGRESS_INTERVAL.as_millis() as u64 / 4 {
+            self.sample_at(downloaded_bytes, elapsed);
+        }
+        let peak_speed_mbps = self
+            .samples
+            .iter()
+            .map(|sample| sample.speed_mbps)
+            .fold(None, |peak: Option<f64>, speed| Some(peak.map_or(speed, |peak| peak.max(speed))));
+        ThroughputSummary {
+            steady_state_speed_mbps: steady_state(&self.samples),
+            peak_speed_mbps,
+            samples: self.samples,
+        }
+    }
+}
+
+// Average speed after slow start, from the first sample past the cutoff to the last
+fn steady_state(samples: &[ThroughputSample]) -> Option<f64> {
+    let end = samples.last()?;
+    let cutoff_ms = SLOW_START_MS.max(end.elapsed_ms / 4);
+    let start = samples.iter().find(|sample| sample.elapsed_ms >= cutoff_ms)?;
+    if end.elapsed_ms <= start.elapsed_ms {
+        return None;
+    }
+    Some(mbps(
+        end.downloaded_bytes - start.downloaded_bytes,
+        end.elapsed_ms - start.elapsed_ms,
+    ))
+}
+
+fn mbps(bytes: u64, elapsed_ms: u64) -> f64 {
+    if elapsed_ms == 0 {
+        return 0.0;
+    }
+    (bytes as f64 * 8.0) / (elapsed_ms as f64 * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    // A meter ticked every 250 ms up to `end_ms`, downloading 1000 bytes per ms
+    // (8 Mbps) before `fast_from_ms` and 2000 (16 Mbps) from then on
+    fn ticked(end_ms: u64, fast_from_ms: u64) -> (ThroughputMeter, u64) {
+        let mut meter = ThroughputMeter::start();
+        let mut bytes = 0;
+        for tick in 1..=end_ms / 250 {
+            bytes += if tick * 250 > fast_from_ms { 500_000 } else { 250_000 };
+            meter.sample_at(bytes, ms(tick * 250));
+        }
+        (meter, bytes)
+    }
+
+    // The same, finished right at the last tick
+    fn finished(end_ms: u64, fast_from_ms: u64) -> ThroughputSummary {
+        let (meter, bytes) = ticked(end_ms, fast_from_ms);
+        meter.finish_at(bytes, ms(end_ms))
+    }
+
+    #[test]
+    fn samples_the_speed_of_each_interval() {
+        let mut meter = ThroughputMeter::start();
+        let first = meter.sample_at(250_000, ms(250));
+        assert_eq!((first.elapsed_ms, first.speed_mbps), (250, 8.0));
+        let second = meter.sample_at(1_250_000, ms(500));
+        assert_eq!((second.downloaded_bytes, second.speed_mbps), (1_250_000, 32.0));
+        // A stalled interval is a zero, not a gap
+        assert_eq!(meter.sample_at(1_250_000, ms(750)).speed_mbps, 0.0);
+    }
+
+    #[test]
+    fn steady_state_skips_the_first_second() {
+        // 2 s: the cutoff is SLOW_START_MS, everything after it ran at 16 Mbps
+        let summary = finished(2000, 1000);
+        assert_eq!(summary.samples.len(), 8);
+        assert_eq!(summary.steady_state_speed_mbps, Some(16.0));
+        assert_eq!(summary.peak_speed_mbps, Some(16.0));
+    }
+
+    #[test]
+    fn steady_state_of_a_long_transfer_skips_its_first_quarter() {
+        // 8 s: the cutoff is 2 s, not 1 s, so the 8 Mbps second between them doesn't count
+        let summary = finished(8000, 2000);
+        assert_eq!(summary.steady_state_speed_mbps, Some(16.0));
+
+        let with_slow_second = finished(8000, 3000);
+        let speed = with_slow_second.steady_state_speed_mbps.unwrap();
+        assert!(speed > 8.0 && speed < 16.0, "{}", speed);
+    }
+
+    #[test]
+    fn finish_drops_a_tail_too_short_to_measure() {
+        let (meter, bytes) = ticked(1000, 0);
+        let summary = meter.finish_at(bytes + 1_000_000, ms(1000 + 62));
+        assert_eq!(summary.samples.len(), 4);
+
+        let (meter, bytes) = ticked(1000, 0);
+        let summary = meter.finish_at(bytes + 1_000_000, ms(1000 + 100));
+        assert_eq!(summary.samples.len(), 5);
+        let tail = summary.samples.last().unwrap();
+        assert_eq!((tail.elapsed_ms, tail.downloaded_bytes, tail.speed_mbps), (1100, bytes + 1_000_000, 80.0));
+        assert_eq!(summary.peak_speed_mbps, Some(80.0));
+    }
+
+    #[test]
+    fn short_or_empty_transfers_have_no_summary_speeds() {
+        let summary = ThroughputMeter::start().finish_at(0, ms(10));
+        assert!(summary.samples.is_empty());
+        assert_eq!((summary.peak_speed_mbps, summary.steady_state_speed_mbps), (None, None));
+
+        // Over before slow start is
+        let summary = finished(750, 0);
+        assert_eq!(summary.peak_speed_mbps, Some(16.0));
+        assert_eq!(summary.steady_state_speed_mbps, None);
+
+        // The only sample past the cutoff is the last one, there is no interval to average
+        let summary = finished(1000, 0);
+        assert_eq!(summary.steady_state_speed_mbps, None);
+    }
+}
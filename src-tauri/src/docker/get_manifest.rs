@@ -3,6 +3,7 @@ use std::collections::HashMap;
 use std::time::Duration;
 use anyhow::Result;
 use std::io::Read;
+use crate::errors::TestError;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -74,7 +75,7 @@ pub fn fetch_tag_manifest(registry_url: &str, image_name: &str, tag: &str) -> Re
         .call()?;
 
     if response.status() != 200 {
-        return Err(anyhow::anyhow!("HTTP error {}: {}", response.status(), url));
+        return Err(anyhow::Error::from(TestError::HttpStatus(response.status())).context(url));
     }
 
     // Get headers before consuming response
@@ -107,7 +108,7 @@ pub fn fetch_digest_manifest(registry_url: &str, image_name: &str, digest: &str)
         .call()?;
 
     if response.status() != 200 {
-        return Err(anyhow::anyhow!("HTTP error {}: {}", response.status(), url));
+        return Err(anyhow::Error::from(TestError::HttpStatus(response.status())).context(url));
     }
 
     let mut response_text = String::new();
@@ -3,13 +3,18 @@ use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use regex::Regex;
 use std::io::Read;
+use crate::errors::TestError;
+use crate::timing::{transfer_speed_mbps, ConnectionClock, PhaseTimings};
 
 mod get_manifest;
 use get_manifest::{fetch_tag_manifest, fetch_digest_manifest};
 
+// What ureq follows by default; blobs are usually one redirect away on a CDN
+const MAX_REDIRECTS: usize = 5;
+
 pub const DOCKER_CONFIG_URL: &str = "https://raw.githubusercontent.com/403unlocker/403Unlocker-cli/refs/heads/main/config/dockerRegistry.yml";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -21,18 +26,9 @@ pub struct DockerRegistryTestResult {
     pub downloaded_bytes: u64,
     pub test_duration_seconds: f64,
     pub error_message: Option<String>,
+    pub error_kind: Option<TestError>,
     pub session_id: u64,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct DockerRegistryBulkTestResult {
-    pub image_name: String,
-    pub total_registries: usize,
-    pub successful_tests: Vec<DockerRegistryTestResult>,
-    pub failed_tests: Vec<DockerRegistryTestResult>,
-    pub test_duration_ms: u64,
-    pub best_registry: Option<String>,
-    pub best_speed_mbps: f64,
+    pub timings: PhaseTimings,
 }
 
 // Config management functions
@@ -80,21 +76,49 @@ pub fn validate_docker_image_name(image_name: &str) -> bool {
     regex.is_match(image_name) && !image_name.contains("@@")
 }
 
+/// What a blob download got, with the times its speed is worked out from.
+pub struct BlobDownload {
+    pub downloaded_bytes: u64,
+    pub transfer_time: Duration,
+    pub timings: PhaseTimings,
+}
+
 // Download function using ureq - returns downloaded bytes even on timeout
-pub fn download_with_ureq(url: &str, max_duration: Duration) -> Result<u64> {
+pub fn download_with_ureq(url: &str, max_duration: Duration) -> Result<BlobDownload> {
     let start_time = Instant::now();
     println!("Starting download from: {}", url);
     
-    let agent = ureq::AgentBuilder::new()
+    let clock = ConnectionClock::default();
+    let agent = clock
+        .instrument_ureq(ureq::AgentBuilder::new())
         .timeout(max_duration)
         .user_agent("registry-speed-tester/0.1")
         .build();
 
-    let response = agent.get(url).call()?;
+    // Redirects are followed here so the time to the first byte is for the last hop alone
+    let mut next_url = url::Url::parse(url)?;
+    let mut hops = 0;
+    let response = loop {
+        clock.request_sent();
+        let response = agent.get(next_url.as_str()).call()?;
+        clock.headers_received();
+        if !(300..400).contains(&response.status()) {
+            break response;
+        }
+        hops += 1;
+        if hops > MAX_REDIRECTS {
+            anyhow::bail!("Too many redirects downloading {}", url);
+        }
+        let location = response
+            .header("location")
+            .ok_or_else(|| anyhow::anyhow!("Redirect from {} without a location", next_url))?;
+        next_url = next_url.join(location)?;
+    };
     
     if response.status() != 200 {
-        return Err(anyhow::anyhow!("HTTP error: {}", response.status()));
+        return Err(TestError::HttpStatus(response.status()).into());
     }
+    let transfer_start = Instant::now();
 
     let mut buffer = [0u8; 8192];
     let mut total_bytes: u64 = 0;
@@ -132,7 +156,7 @@ pub fn download_with_ureq(url: &str, max_duration: Duration) -> Result<u64> {
                     println!("Download interrupted after downloading {} bytes: {}", total_bytes, e);
                     break;
                 } else {
-                    return Err(anyhow::anyhow!("Download failed: {}", e));
+                    return Err(anyhow::Error::from(e).context("Download failed"));
                 }
             }
         }
@@ -146,7 +170,13 @@ pub fn download_with_ureq(url: &str, max_duration: Duration) -> Result<u64> {
     };
     
     println!("Download completed: {} bytes in {:.2}s, final speed: {:.2} Mbps", total_bytes, final_elapsed.as_secs_f64(), final_speed_mbps);
-    Ok(total_bytes)
+    let transfer_time = transfer_start.elapsed();
+    Ok(BlobDownload {
+        downloaded_bytes: total_bytes,
+        transfer_time,
+        // The blob's lookup is timed as ureq makes it, there is none from before
+        timings: PhaseTimings::measured(&clock, None, transfer_time),
+    })
 }
 
 pub async fn test_docker_registry_download_speed(
@@ -166,7 +196,9 @@ pub async fn test_docker_registry_download_speed(
             downloaded_bytes: 0,
             test_duration_seconds: 0.0,
             error_message: Some("Invalid Docker image name format".to_string()),
+            error_kind: Some(TestError::InvalidInput("Invalid Docker image name format".to_string())),
             session_id: 0, // No longer using sessions
+            timings: PhaseTimings::default(),
         };
     }
 
@@ -184,13 +216,11 @@ pub async fn test_docker_registry_download_speed(
 
     // Try the blob-based download approach
     match test_registry_with_manifest_approach(&registry_url, &repository, &tag, download_duration).await {
-        Ok(downloaded_bytes) => {
+        Ok((blob, timings)) => {
             let elapsed = start_time.elapsed().as_secs_f64();
-            let speed_mbps = if elapsed > 0.0 {
-                (downloaded_bytes as f64 * 8.0) / (elapsed * 1_000_000.0)
-            } else {
-                0.0
-            };
+            let downloaded_bytes = blob.downloaded_bytes;
+            // Manifest lookups and connection setup say nothing about bandwidth
+            let speed_mbps = transfer_speed_mbps(downloaded_bytes, blob.transfer_time);
 
             // If we downloaded any data, consider it a success (even if it timed out)
             if downloaded_bytes > 0 {
@@ -204,7 +234,9 @@ pub async fn test_docker_registry_download_speed(
                     downloaded_bytes,
                     test_duration_seconds: elapsed,
                     error_message: None,
+                    error_kind: None,
                     session_id: 0, // No longer using sessions
+                    timings,
                 }
             } else {
                 println!("❌ No data downloaded from {}", registry);
@@ -217,13 +249,15 @@ pub async fn test_docker_registry_download_speed(
                     downloaded_bytes: 0,
                     test_duration_seconds: elapsed,
                     error_message: Some("No data downloaded".to_string()),
+                    error_kind: Some(TestError::Other("No data downloaded".to_string())),
                     session_id: 0, // No longer using sessions
+                    timings,
                 }
             }
         }
         Err(e) => {
             let elapsed = start_time.elapsed().as_secs_f64();
-            println!("❌ Download failed for {}: {}", registry, e);
+            println!("❌ Download failed for {}: {:#}", registry, e);
             
             DockerRegistryTestResult {
                 registry: registry.to_string(),
@@ -232,8 +266,10 @@ pub async fn test_docker_registry_download_speed(
                 download_speed_mbps: 0.0,
                 downloaded_bytes: 0, // No bytes downloaded on failure
                 test_duration_seconds: elapsed,
-                error_message: Some(e.to_string()),
+                error_message: Some(format!("{:#}", e)),
+                error_kind: Some(TestError::classify(&e)),
                 session_id: 0, // No longer using sessions
+                timings: PhaseTimings::default(),
             }
         }
     }
@@ -269,11 +305,11 @@ async fn test_registry_with_manifest_approach(
     repository: &str,
     tag: &str,
     max_duration: Duration,
-) -> Result<u64> {
+) -> Result<(BlobDownload, PhaseTimings)> {
     let start_time = Instant::now();
     
     println!("Testing registry: {} with image: {}:{}", registry_url, repository, tag);
-    
+
     // Try to get the actual manifest that contains layer information
     let layer_digest = match tokio::task::spawn_blocking({
         let registry_url = registry_url.to_string();
@@ -287,13 +323,13 @@ async fn test_registry_with_manifest_approach(
         },
         Err(e) => {
             println!("Failed to get layer digest: {}", e);
-            return Err(anyhow::anyhow!("Failed to get layer digest: {}", e));
+            return Err(e.context("Failed to get layer digest"));
         },
     };
     
     // Check if we still have time for downloading
     if start_time.elapsed() >= max_duration {
-        return Err(anyhow::anyhow!("Timeout during manifest fetching"));
+        return Err(anyhow::Error::from(TestError::HttpTimeout).context("Timeout during manifest fetching"));
     }
     
     // Download layer blob for speed testing
@@ -303,12 +339,14 @@ async fn test_registry_with_manifest_approach(
     let remaining_duration = max_duration - start_time.elapsed();
     
     // Use tokio::task::spawn_blocking to run the synchronous ureq download in async context
-    let downloaded_bytes = tokio::task::spawn_blocking(move || {
+    let blob = tokio::task::spawn_blocking(move || {
         download_with_ureq(&blob_url, remaining_duration)
     }).await??;
     
-    println!("Downloaded {} bytes from {}", downloaded_bytes, registry_url);
-    Ok(downloaded_bytes)
+    println!("Downloaded {} bytes from {}", blob.downloaded_bytes, registry_url);
+
+    let timings = blob.timings.clone();
+    Ok((blob, timings))
 }
 
 // Simplified helper function to get the first layer digest - following the user's example
@@ -317,13 +355,13 @@ fn get_first_layer_digest(registry_url: &str, repository: &str, tag: &str) -> Re
     
     // Step 1: Fetch tag manifest (exactly like user's example)
     let manifest_list = fetch_tag_manifest(registry_url, repository, tag)
-        .map_err(|e| anyhow::anyhow!("Failed to fetch tag manifest: {}", e))?;
+        .context("Failed to fetch tag manifest")?;
     
     if manifest_list.manifests.is_empty() {
         // Try direct manifest fetch as fallback
         println!("No manifests in list, trying direct manifest fetch");
         let direct_manifest = fetch_digest_manifest(registry_url, repository, tag)
-            .map_err(|e| anyhow::anyhow!("Failed to fetch direct manifest: {}", e))?;
+            .context("Failed to fetch direct manifest")?;
         
         if direct_manifest.layers.is_empty() {
             return Err(anyhow::anyhow!("No layers found in direct manifest"));
@@ -338,7 +376,7 @@ fn get_first_layer_digest(registry_url: &str, repository: &str, tag: &str) -> Re
     
     // Step 3: Fetch digest manifest (exactly like user's example)
     let digest_manifest = fetch_digest_manifest(registry_url, repository, first_manifest_digest)
-        .map_err(|e| anyhow::anyhow!("Failed to fetch digest manifest: {}", e))?;
+        .context("Failed to fetch digest manifest")?;
     
     if digest_manifest.layers.is_empty() {
         return Err(anyhow::anyhow!("No layers found in digest manifest"));
@@ -361,4 +399,24 @@ fn get_first_layer_digest(registry_url: &str, repository: &str, tag: &str) -> Re
     }
     
     Ok(layer_digest.clone())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::redirecting_server;
+
+    #[test]
+    fn blob_ttfb_leaves_out_the_redirect_hop() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let port = runtime.block_on(redirecting_server(Duration::from_millis(400), Duration::from_millis(100)));
+
+        let blob = download_with_ureq(&format!("http://127.0.0.1:{}/start", port), Duration::from_secs(5)).unwrap();
+        assert_eq!(blob.downloaded_bytes, 4);
+        // The lookup ureq made for the last hop
+        assert!(blob.timings.dns_ms.is_some());
+        // Plain HTTP through ureq doesn't say when the socket connected
+        assert_eq!(blob.timings.tcp_connect_ms, None);
+        assert_eq!(blob.timings.ttfb_ms, None);
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt::Write;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Md5,
+}
+
+impl ChecksumAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "SHA-256",
+            ChecksumAlgorithm::Md5 => "MD5",
+        }
+    }
+
+    fn digest_length(self) -> usize {
+        match self {
+            ChecksumAlgorithm::Sha256 => 64,
+            ChecksumAlgorithm::Md5 => 32,
+        }
+    }
+}
+
+/// A checksum as typed in, `sha256:<hex>`, `md5:<hex>` or just the hex digest.
+#[derive(Debug, Clone)]
+pub struct ExpectedChecksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub digest: String,
+}
+
+impl ExpectedChecksum {
+    pub fn parse(input: &str) -> anyhow::Result<Self> {
+        let input = input.trim();
+        let (algorithm, digest) = match input.split_once(':') {
+            Some((name, digest)) => {
+                let algorithm = match name.trim().to_ascii_lowercase().replace('-', "").as_str() {
+                    "sha256" => ChecksumAlgorithm::Sha256,
+                    "md5" => ChecksumAlgorithm::Md5,
+                    other => anyhow::bail!("Unsupported checksum algorithm: {}", other),
+                };
+                (algorithm, digest.trim())
+            }
+            // Bare digests are told apart by length
+            None => match input.len() {
+                64 => (ChecksumAlgorithm::Sha256, input),
+                32 => (ChecksumAlgorithm::Md5, input),
+                _ => anyhow::bail!("Checksum must be a SHA-256 or MD5 hex digest"),
+            },
+        };
+        if digest.len() != algorithm.digest_length() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("Checksum is not a valid {} hex digest", algorithm.name());
+        }
+        Ok(Self {
+            algorithm,
+            digest: digest.to_ascii_lowercase(),
+        })
+    }
+}
+
+/// Outcome of checking a saved file against the expected checksum.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChecksumCheck {
+    pub algorithm: ChecksumAlgorithm,
+    pub expected: String,
+    pub actual: String,
+    pub matches: bool,
+}
+
+// Hashes the file as it is on disk, so a resumed download is checked whole
+pub async fn verify_file(path: &Path, expected: &ExpectedChecksum) -> anyhow::Result<ChecksumCheck> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buffer = vec![0u8; 1024 * 1024];
+    let mut sha256 = Sha256::new();
+    let mut md5 = Md5::new();
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        match expected.algorithm {
+            ChecksumAlgorithm::Sha256 => sha256.update(&buffer[..read]),
+            ChecksumAlgorithm::Md5 => md5.update(&buffer[..read]),
+        }
+    }
+    let actual = match expected.algorithm {
+        ChecksumAlgorithm::Sha256 => to_hex(&sha256.finalize()),
+        ChecksumAlgorithm::Md5 => to_hex(&md5.finalize()),
+    };
+    Ok(ChecksumCheck {
+        algorithm: expected.algorithm,
+        matches: actual == expected.digest,
+        expected: expected.digest.clone(),
+        actual,
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    const ABC_MD5: &str = "900150983cd24fb0d6963f7d28e17f72";
+
+    #[test]
+    fn parses_prefixed_and_bare_digests() {
+        let checksum = ExpectedChecksum::parse(&format!(" SHA-256:{} ", ABC_SHA256.to_uppercase())).unwrap();
+        assert_eq!(checksum.algorithm, ChecksumAlgorithm::Sha256);
+        assert_eq!(checksum.digest, ABC_SHA256);
+
+        let checksum = ExpectedChecksum::parse(&format!("md5:{}", ABC_MD5)).unwrap();
+        assert_eq!(checksum.algorithm, ChecksumAlgorithm::Md5);
+
+        // Without a prefix the length decides
+        assert_eq!(ExpectedChecksum::parse(ABC_SHA256).unwrap().algorithm, ChecksumAlgorithm::Sha256);
+        assert_eq!(ExpectedChecksum::parse(ABC_MD5).unwrap().algorithm, ChecksumAlgorithm::Md5);
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_digest() {
+        assert!(ExpectedChecksum::parse(&format!("sha1:{}", ABC_MD5)).is_err());
+        assert!(ExpectedChecksum::parse(&format!("sha256:{}", ABC_MD5)).is_err());
+        assert!(ExpectedChecksum::parse(&ABC_MD5.replace('9', "g")).is_err());
+        assert!(ExpectedChecksum::parse("abc").is_err());
+    }
+
+    #[tokio::test]
+    async fn verifies_files_with_either_algorithm() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc");
+        std::fs::write(&path, "abc").unwrap();
+
+        for digest in [ABC_SHA256, ABC_MD5] {
+            let check = verify_file(&path, &ExpectedChecksum::parse(digest).unwrap()).await.unwrap();
+            assert!(check.matches, "{:?}", check);
+            assert_eq!(check.actual, digest);
+        }
+        let check = verify_file(&path, &ExpectedChecksum::parse(&ABC_MD5.replace('9', "0")).unwrap())
+            .await
+            .unwrap();
+        assert!(!check.matches);
+    }
+}
@@ -0,0 +1,388 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use anyhow::Context;
+use futures_util::StreamExt;
+use reqwest::header::{HeaderMap, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use crate::dns::{DownloadProgress, LookupStrategy, ProgressCallback, ThroughputMeter, PROGRESS_INTERVAL};
+use crate::errors::TestError;
+use crate::timing::transfer_speed_mbps;
+use crate::utils::CustomDnsResolver;
+
+mod checksum;
+use checksum::ChecksumCheck;
+pub use checksum::ExpectedChecksum;
+
+// A real download has no overall timeout, but a dead server shouldn't hang it
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A file to fetch through one DNS server and save.
+pub struct FileDownload {
+    pub url: String,
+    pub destination: PathBuf,
+    pub dns_server: String,
+    pub lookup_strategy: LookupStrategy,
+    pub checksum: Option<ExpectedChecksum>,
+}
+
+/// Sent when a file download finishes, fails or fails verification.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileDownloadResult {
+    pub url: String,
+    pub destination: String,
+    pub dns_server: String,
+    pub success: bool,
+    /// Size of the saved file
+    pub total_bytes: u64,
+    /// Bytes that were already there from an interrupted download
+    pub resumed_from: u64,
+    pub duration_seconds: f64,
+    /// Over the bytes fetched this time
+    pub speed_mbps: f64,
+    pub checksum: Option<ChecksumCheck>,
+    pub error_message: Option<String>,
+    pub error_kind: Option<TestError>,
+}
+
+/// Where a download into `destination` is kept until it is complete; an
+/// interrupted download is resumed from it.
+pub fn partial_path(destination: &Path) -> PathBuf {
+    let mut name = destination.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    destination.with_file_name(name)
+}
+
+/// The file name to use when the destination is a directory.
+pub fn file_name_from_url(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.path_segments()?.next_back().map(str::to_string))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "download".to_string())
+}
+
+pub async fn download_to_file(download: &FileDownload, on_progress: &ProgressCallback<'_>) -> FileDownloadResult {
+    let started = Instant::now();
+    let mut result = FileDownloadResult {
+        url: download.url.clone(),
+        destination: download.destination.display().to_string(),
+        dns_server: download.dns_server.clone(),
+        success: false,
+        total_bytes: 0,
+        resumed_from: 0,
+        duration_seconds: 0.0,
+        speed_mbps: 0.0,
+        checksum: None,
+        error_message: None,
+        error_kind: None,
+    };
+    if let Err(e) = save(download, &mut result, on_progress).await {
+        println!("Download of {} to {} failed: {:#}", download.url, result.destination, e);
+        result.error_kind = Some(TestError::classify(&e));
+        result.error_message = Some(format!("{:#}", e));
+    }
+    result.duration_seconds = started.elapsed().as_secs_f64();
+    result
+}
+
+async fn save(
+    download: &FileDownload,
+    result: &mut FileDownloadResult,
+    on_progress: &ProgressCallback<'_>,
+) -> anyhow::Result<()> {
+    let partial = partial_path(&download.destination);
+    let validator_file = validator_path(&download.destination);
+    let existing = tokio::fs::metadata(&partial).await.map(|metadata| metadata.len()).unwrap_or(0);
+    let saved_validator = tokio::fs::read_to_string(&validator_file).await.ok();
+    // Without a validator there's no telling whether the file on the server is still the same
+    let resume = match (existing, saved_validator.as_deref()) {
+        (0, _) => None,
+        (_, Some(validator)) => Some((existing, validator.trim())),
+        (_, None) => {
+            println!("No validator saved for {}, starting {} over", partial.display(), download.url);
+            None
+        }
+    };
+
+    let client = CustomDnsResolver::new(&download.dns_server, download.lookup_strategy)?
+        .client_builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .read_timeout(READ_TIMEOUT)
+        .build()?;
+    let mut response = request(&client, &download.url, resume).await?;
+    let mut resumed_from = resume_offset(response.status(), content_range(&response), existing)?;
+    if resumed_from.is_none() {
+        println!(
+            "The file at {} changed since the download was interrupted, starting over",
+            download.url
+        );
+        response = request(&client, &download.url, None).await?;
+        resumed_from = resume_offset(response.status(), content_range(&response), 0)?;
+    }
+    let resumed_from = resumed_from.context("The server answered a plain request with partial content")?;
+    let status = response.status();
+    let complete_length = content_range(&response).and_then(content_range_length);
+    if existing > 0 {
+        println!(
+            "Resuming {} at {} of {:?} bytes ({})",
+            download.url, resumed_from, complete_length, status
+        );
+    }
+    result.resumed_from = resumed_from;
+
+    if status != StatusCode::RANGE_NOT_SATISFIABLE {
+        let total_bytes = match status {
+            StatusCode::PARTIAL_CONTENT => complete_length,
+            _ => response.content_length(),
+        };
+        // Kept with the partial file so a later attempt only resumes onto the same file
+        if resumed_from == 0 {
+            match validator(response.headers()) {
+                Some(validator) => tokio::fs::write(&validator_file, validator).await?,
+                None => remove_if_present(&validator_file).await?,
+            }
+        }
+        let mut file = match resumed_from {
+            0 => tokio::fs::File::create(&partial).await,
+            _ => tokio::fs::OpenOptions::new().append(true).open(&partial).await,
+        }
+        .with_context(|| format!("Could not write {}", partial.display()))?;
+
+        let mut downloaded_bytes = 0u64;
+        let transfer_start = Instant::now();
+        let mut stream = response.bytes_stream();
+        let mut meter = ThroughputMeter::start();
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + PROGRESS_INTERVAL, PROGRESS_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                chunk_result = stream.next() => {
+                    let Some(chunk_result) = chunk_result else {
+                        break;
+                    };
+                    // What arrived so far stays in the partial file for the next attempt
+                    let chunk = chunk_result.context("Stream error, start the download again to resume")?;
+                    file.write_all(&chunk).await?;
+                    downloaded_bytes += chunk.len() as u64;
+                }
+                _ = ticker.tick() => {
+                    let sample = meter.sample(downloaded_bytes);
+                    on_progress(&DownloadProgress {
+                        dns_server: download.dns_server.clone(),
+                        url: download.url.clone(),
+                        session_id: 0,
+                        downloaded_bytes: resumed_from + downloaded_bytes,
+                        total_bytes,
+                        elapsed_ms: sample.elapsed_ms,
+                        speed_mbps: sample.speed_mbps,
+                    });
+                }
+            }
+        }
+        file.flush().await?;
+        result.speed_mbps = transfer_speed_mbps(downloaded_bytes, transfer_start.elapsed());
+
+        // A connection closed early ends the stream without an error
+        if let Some(total_bytes) = total_bytes {
+            if resumed_from + downloaded_bytes < total_bytes {
+                anyhow::bail!(
+                    "Connection closed after {} of {} bytes, start the download again to resume",
+                    resumed_from + downloaded_bytes,
+                    total_bytes
+                );
+            }
+        }
+    }
+    result.total_bytes = tokio::fs::metadata(&partial).await?.len();
+
+    if let Some(expected) = &download.checksum {
+        let check = checksum::verify_file(&partial, expected).await?;
+        let matches = check.matches;
+        result.checksum = Some(check);
+        // Resuming from a corrupt file would only fail again
+        if !matches {
+            tokio::fs::remove_file(&partial).await?;
+            remove_if_present(&validator_file).await?;
+            anyhow::bail!("Checksum mismatch, the download was discarded");
+        }
+    }
+
+    tokio::fs::rename(&partial, &download.destination)
+        .await
+        .with_context(|| format!("Could not move the download to {}", download.destination.display()))?;
+    remove_if_present(&validator_file).await?;
+    println!(
+        "Saved {} to {} ({} bytes) via {}",
+        download.url, result.destination, result.total_bytes, download.dns_server
+    );
+    result.success = true;
+    Ok(())
+}
+
+// `resume` is where the partial file ends and the validator it was saved
+// with; If-Range makes the server send the whole file if that no longer matches
+async fn request(
+    client: &reqwest::Client,
+    url: &str,
+    resume: Option<(u64, &str)>,
+) -> anyhow::Result<reqwest::Response> {
+    let mut request = client.get(url);
+    if let Some((existing, validator)) = resume {
+        request = request
+            .header(RANGE, format!("bytes={}-", existing))
+            .header(IF_RANGE, validator);
+    }
+    request.send().await.context("HTTP request failed")
+}
+
+// Where the response continues the partial file of `existing` bytes: there,
+// at 0 for a whole file, or None when the partial file has to be dropped
+fn resume_offset(status: StatusCode, content_range: Option<&str>, existing: u64) -> anyhow::Result<Option<u64>> {
+    match status {
+        // Only if it starts where the partial file ends
+        StatusCode::PARTIAL_CONTENT => {
+            Ok(content_range.and_then(content_range_start).filter(|&start| existing > 0 && start == existing))
+        }
+        // Asked for the bytes after the end: the partial file is already whole,
+        // unless the file on the server changed size since
+        StatusCode::RANGE_NOT_SATISFIABLE if existing > 0 => {
+            Ok(content_range.and_then(content_range_length).filter(|&length| length == existing))
+        }
+        // Servers without range support, or a file that changed, send it all again
+        status if status.is_success() => Ok(Some(0)),
+        status => Err(TestError::HttpStatus(status.as_u16()).into()),
+    }
+}
+
+/// Where the validator (ETag or Last-Modified) of the file being saved to
+/// `destination` is kept next to its partial file.
+pub fn validator_path(destination: &Path) -> PathBuf {
+    let mut name = partial_path(destination).into_os_string();
+    name.push(".validator");
+    PathBuf::from(name)
+}
+
+// A strong ETag, or else Last-Modified; If-Range can't use a weak ETag
+fn validator(headers: &HeaderMap) -> Option<&str> {
+    let etag = headers.get(ETAG).and_then(|value| value.to_str().ok());
+    let last_modified = headers.get(LAST_MODIFIED).and_then(|value| value.to_str().ok());
+    etag.filter(|etag| !etag.starts_with("W/")).or(last_modified)
+}
+
+async fn remove_if_present(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn content_range(response: &reqwest::Response) -> Option<&str> {
+    response.headers().get(CONTENT_RANGE)?.to_str().ok()
+}
+
+// The first byte from `Content-Range: bytes a-b/length`
+fn content_range_start(value: &str) -> Option<u64> {
+    let range = value.trim().strip_prefix("bytes ")?.split_once('/')?.0;
+    range.split_once('-')?.0.trim().parse().ok()
+}
+
+// The full length from `Content-Range: bytes a-b/length` or `bytes */length`
+fn content_range_length(value: &str) -> Option<u64> {
+    value.rsplit_once('/')?.1.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::file_server;
+
+    #[test]
+    fn partial_files_sit_next_to_the_destination() {
+        let destination = Path::new("/downloads/ubuntu.iso");
+        assert_eq!(partial_path(destination), Path::new("/downloads/ubuntu.iso.part"));
+        assert_eq!(validator_path(destination), Path::new("/downloads/ubuntu.iso.part.validator"));
+    }
+
+    #[test]
+    fn reads_content_range() {
+        assert_eq!(content_range_start("bytes 100-199/1000"), Some(100));
+        assert_eq!(content_range_length("bytes 100-199/1000"), Some(1000));
+        assert_eq!(content_range_start("bytes */1000"), None);
+        assert_eq!(content_range_length("bytes */1000"), Some(1000));
+        assert_eq!(content_range_length("bytes 0-99/*"), None);
+        assert_eq!(content_range_start("items 0-99/100"), None);
+    }
+
+    #[test]
+    fn resumes_only_where_the_partial_file_ends() {
+        let offset = |status, range| resume_offset(status, range, 100).unwrap();
+        assert_eq!(offset(StatusCode::PARTIAL_CONTENT, Some("bytes 100-999/1000")), Some(100));
+        assert_eq!(offset(StatusCode::PARTIAL_CONTENT, Some("bytes 0-999/1000")), None);
+        assert_eq!(offset(StatusCode::PARTIAL_CONTENT, None), None);
+        assert_eq!(offset(StatusCode::OK, None), Some(0));
+        assert_eq!(offset(StatusCode::RANGE_NOT_SATISFIABLE, Some("bytes */100")), Some(100));
+        assert_eq!(offset(StatusCode::RANGE_NOT_SATISFIABLE, Some("bytes */1000")), None);
+        assert!(resume_offset(StatusCode::NOT_FOUND, None, 100).is_err());
+    }
+
+    #[test]
+    fn weak_etags_are_not_validators() {
+        let mut headers = HeaderMap::new();
+        headers.insert(LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap());
+        headers.insert(ETAG, "W/\"1\"".parse().unwrap());
+        assert_eq!(validator(&headers), Some("Wed, 21 Oct 2015 07:28:00 GMT"));
+        headers.insert(ETAG, "\"1\"".parse().unwrap());
+        assert_eq!(validator(&headers), Some("\"1\""));
+    }
+
+    fn body() -> Vec<u8> {
+        (0..200_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    // Downloads to `destination` after leaving `partial` bytes, saved with `validator`, behind
+    async fn resume(partial: &[u8], validator: Option<&str>) -> (FileDownloadResult, Vec<u8>) {
+        let port = file_server(body()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("file.bin");
+        std::fs::write(partial_path(&destination), partial).unwrap();
+        if let Some(validator) = validator {
+            std::fs::write(validator_path(&destination), validator).unwrap();
+        }
+        let download = FileDownload {
+            url: format!("http://127.0.0.1:{}/file.bin", port),
+            destination: destination.clone(),
+            dns_server: "8.8.8.8".to_string(),
+            lookup_strategy: LookupStrategy::default(),
+            checksum: None,
+        };
+        let result = download_to_file(&download, &|_| {}).await;
+        assert!(result.success, "{:?}", result.error_message);
+        assert!(!validator_path(&destination).exists());
+        (result, std::fs::read(&destination).unwrap())
+    }
+
+    #[tokio::test]
+    async fn resumes_the_same_file() {
+        let (result, saved) = resume(&body()[..50_000], Some("\"200000\"")).await;
+        assert_eq!(result.resumed_from, 50_000);
+        assert_eq!(saved, body());
+    }
+
+    #[tokio::test]
+    async fn starts_over_when_the_file_changed() {
+        let (result, saved) = resume(&[1u8; 50_000], Some("\"123\"")).await;
+        assert_eq!(result.resumed_from, 0);
+        assert_eq!(saved, body());
+    }
+
+    #[tokio::test]
+    async fn starts_over_without_a_validator() {
+        let (result, saved) = resume(&[1u8; 50_000], None).await;
+        assert_eq!(result.resumed_from, 0);
+        assert_eq!(saved, body());
+    }
+}
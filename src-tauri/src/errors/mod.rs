@@ -0,0 +1,377 @@
+use std::fmt;
+use std::io;
+use serde::{Deserialize, Serialize};
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::proto::error::ProtoErrorKind;
+use trust_dns_resolver::proto::op::ResponseCode;
+// reqwest and ureq link the same rustls 0.23 as tokio-rustls
+use tokio_rustls::rustls;
+
+/// Why a DNS, download or registry test failed.
+///
+/// DNS-level failures (NXDOMAIN, REFUSED, a reset right after the TLS
+/// ClientHello) usually point at filtering, while timeouts and SERVFAIL are more
+/// often plain outages, so results carry this alongside the error message.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TestError {
+    Nxdomain,
+    Servfail,
+    Refused,
+    // The server answered NOERROR without any usable address
+    NoRecords,
+    // The answer pointed at a block page or bogon, see dns::poison
+    PoisonedAnswer(String),
+    ResolverTimeout,
+    ResolverFailed(String),
+    ConnectionRefused,
+    ConnectionReset,
+    ConnectTimeout,
+    // Connected, but the server stopped sending in the middle of a response
+    ReadTimeout,
+    ConnectionFailed(String),
+    TlsHandshakeFailed(String),
+    CertificateMismatch(String),
+    HttpTimeout,
+    HttpStatus(u16),
+    InvalidInput(String),
+    Other(String),
+}
+
+impl fmt::Display for TestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestError::Nxdomain => write!(f, "domain does not exist (NXDOMAIN)"),
+            TestError::Servfail => write!(f, "DNS server failed to answer (SERVFAIL)"),
+            TestError::Refused => write!(f, "DNS server refused the query (REFUSED)"),
+            TestError::NoRecords => write!(f, "no IP found for host"),
+            TestError::PoisonedAnswer(reason) => write!(f, "DNS answer poisoned: {}", reason),
+            TestError::ResolverTimeout => write!(f, "DNS resolution timed out"),
+            TestError::ResolverFailed(reason) => write!(f, "DNS resolution failed: {}", reason),
+            TestError::ConnectionRefused => write!(f, "connection refused"),
+            TestError::ConnectionReset => write!(f, "connection reset"),
+            TestError::ConnectTimeout => write!(f, "connection timed out"),
+            TestError::ReadTimeout => write!(f, "read timed out, the server stopped sending"),
+            TestError::ConnectionFailed(reason) => write!(f, "connection failed: {}", reason),
+            TestError::TlsHandshakeFailed(reason) => write!(f, "TLS handshake failed: {}", reason),
+            TestError::CertificateMismatch(reason) => write!(f, "certificate rejected: {}", reason),
+            TestError::HttpTimeout => write!(f, "HTTP request timed out"),
+            TestError::HttpStatus(code) => write!(f, "HTTP {}", code),
+            TestError::InvalidInput(reason) => write!(f, "{}", reason),
+            TestError::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for TestError {}
+
+impl TestError {
+    /// Finds the most specific cause in an error chain. Errors raised as a
+    /// `TestError` are kept as they are.
+    pub fn classify(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(e) = cause.downcast_ref::<TestError>() {
+                return e.clone();
+            }
+            if let Some(e) = cause.downcast_ref::<ResolveError>() {
+                return Self::from_resolve_error(e);
+            }
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                return Self::from_reqwest_error(e);
+            }
+            if let Some(e) = cause.downcast_ref::<ureq::Error>() {
+                return Self::from_ureq_error(e);
+            }
+            if let Some(kind) = Self::from_tls_error(cause) {
+                return kind;
+            }
+            // Connect errors come wrapped in the client's error, which says so;
+            // a bare io::Error is from reading a body, ureq's included
+            if let Some(e) = cause.downcast_ref::<io::Error>() {
+                if let Some(kind) = Self::from_io_error(e, Phase::Read) {
+                    return kind;
+                }
+            }
+        }
+        TestError::Other(format!("{:#}", error))
+    }
+
+    /// A TCP connection that could not be opened.
+    pub fn from_connect_error(error: &io::Error) -> Self {
+        Self::from_io_error(error, Phase::Connect).unwrap_or_else(|| TestError::ConnectionFailed(error.to_string()))
+    }
+
+    pub fn from_response_code(code: ResponseCode) -> Self {
+        match code {
+            ResponseCode::NXDomain => TestError::Nxdomain,
+            ResponseCode::ServFail => TestError::Servfail,
+            ResponseCode::Refused => TestError::Refused,
+            ResponseCode::NoError => TestError::NoRecords,
+            code => TestError::ResolverFailed(code.to_string()),
+        }
+    }
+
+    fn from_resolve_error(error: &ResolveError) -> Self {
+        let from_io = |e: &io::Error| {
+            Self::from_dot_error(e)
+                .or_else(|| Self::from_io_error(e, Phase::Resolve))
+                .unwrap_or_else(|| TestError::ResolverFailed(e.to_string()))
+        };
+        match error.kind() {
+            ResolveErrorKind::NoRecordsFound { response_code, .. } => Self::from_response_code(*response_code),
+            ResolveErrorKind::Timeout => TestError::ResolverTimeout,
+            ResolveErrorKind::Io(e) => from_io(e),
+            ResolveErrorKind::Proto(e) => match e.kind() {
+                ProtoErrorKind::Timeout => TestError::ResolverTimeout,
+                ProtoErrorKind::Io(e) => from_io(e),
+                _ => TestError::ResolverFailed(e.to_string()),
+            },
+            _ => TestError::ResolverFailed(error.to_string()),
+        }
+    }
+
+    // trust-dns only passes DoT handshake failures on as text, in an io::Error
+    // of kind ConnectionRefused, so a rejected certificate can't be told apart
+    // from other handshake failures there
+    fn from_dot_error(error: &io::Error) -> Option<Self> {
+        let reason = error.to_string().strip_prefix("tls error: ")?.to_string();
+        (error.kind() == io::ErrorKind::ConnectionRefused).then_some(TestError::TlsHandshakeFailed(reason))
+    }
+
+    fn from_reqwest_error(error: &reqwest::Error) -> Self {
+        if let Some(status) = error.status() {
+            return TestError::HttpStatus(status.as_u16());
+        }
+        if error.is_timeout() {
+            return if error.is_connect() {
+                TestError::ConnectTimeout
+            } else if error.is_body() || error.is_decode() {
+                TestError::ReadTimeout
+            } else {
+                TestError::HttpTimeout
+            };
+        }
+        if error.is_builder() {
+            return TestError::InvalidInput(error.to_string());
+        }
+
+        // The interesting part (refused, reset, rejected certificate) sits further down the chain
+        let phase = if error.is_connect() { Phase::Connect } else { Phase::Read };
+        if let Some(kind) = Self::from_sources(error, phase) {
+            return kind;
+        }
+        if error.is_connect() {
+            TestError::ConnectionFailed(describe_chain(error))
+        } else {
+            TestError::Other(describe_chain(error))
+        }
+    }
+
+    fn from_ureq_error(error: &ureq::Error) -> Self {
+        match error {
+            ureq::Error::Status(code, _) => TestError::HttpStatus(*code),
+            ureq::Error::Transport(transport) => {
+                let phase = match transport.kind() {
+                    ureq::ErrorKind::ConnectionFailed => Phase::Connect,
+                    _ => Phase::Read,
+                };
+                if let Some(kind) = Self::from_sources(transport, phase) {
+                    return kind;
+                }
+                match transport.kind() {
+                    ureq::ErrorKind::Dns => TestError::ResolverFailed(transport.to_string()),
+                    ureq::ErrorKind::ConnectionFailed => TestError::ConnectionFailed(transport.to_string()),
+                    ureq::ErrorKind::InvalidUrl | ureq::ErrorKind::UnknownScheme => {
+                        TestError::InvalidInput(transport.to_string())
+                    }
+                    _ => TestError::Other(transport.to_string()),
+                }
+            }
+        }
+    }
+
+    // The first TLS or io cause below `error`
+    fn from_sources(error: &(dyn std::error::Error + 'static), phase: Phase) -> Option<Self> {
+        let mut source = error.source();
+        while let Some(cause) = source {
+            if let Some(kind) = Self::from_tls_error(cause) {
+                return Some(kind);
+            }
+            if let Some(e) = cause.downcast_ref::<io::Error>() {
+                if let Some(kind) = Self::from_io_error(e, phase) {
+                    return Some(kind);
+                }
+            }
+            source = cause.source();
+        }
+        None
+    }
+
+    fn from_io_error(error: &io::Error, phase: Phase) -> Option<Self> {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => Some(TestError::ConnectionRefused),
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => Some(TestError::ConnectionReset),
+            // ureq reports read timeouts as WouldBlock on some platforms
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Some(phase.timeout()),
+            _ => None,
+        }
+    }
+
+    // rustls errors arrive on their own or wrapped in (possibly nested)
+    // io::Errors, whose source() skips the wrapped error itself
+    fn from_tls_error(error: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        let mut error = error;
+        while let Some(e) = error.downcast_ref::<io::Error>() {
+            error = e.get_ref()?;
+        }
+        let tls_error = error.downcast_ref::<rustls::Error>()?;
+        Some(match tls_error {
+            rustls::Error::InvalidCertificate(_) => TestError::CertificateMismatch(tls_error.to_string()),
+            _ => TestError::TlsHandshakeFailed(tls_error.to_string()),
+        })
+    }
+}
+
+// Where a timeout reported as an io::Error happened, as far as the error
+// wrapping it tells
+#[derive(Clone, Copy)]
+enum Phase {
+    Resolve,
+    Connect,
+    Read,
+}
+
+impl Phase {
+    fn timeout(self) -> TestError {
+        match self {
+            Phase::Resolve => TestError::ResolverTimeout,
+            Phase::Connect => TestError::ConnectTimeout,
+            Phase::Read => TestError::ReadTimeout,
+        }
+    }
+}
+
+// reqwest's own message ("error sending request for url ...") hides the cause
+fn describe_chain(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut parts = vec![error.to_string()];
+    let mut source = error.source();
+    while let Some(cause) = source {
+        parts.push(cause.to_string());
+        source = cause.source();
+    }
+    parts.join(": ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use anyhow::Context;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use trust_dns_resolver::proto::error::ProtoError;
+
+    // A local server that sends `reply` to every connection and then keeps it
+    // open without sending anything else
+    async fn stalling_server(reply: &'static [u8]) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buffer = [0u8; 4096];
+                    let _ = stream.read(&mut buffer).await;
+                    let _ = stream.write_all(reply).await;
+                    tokio::time::sleep(Duration::from_secs(30)).await;
+                });
+            }
+        });
+        port
+    }
+
+    fn classify_io(kind: io::ErrorKind) -> TestError {
+        TestError::classify(&anyhow::Error::from(io::Error::from(kind)).context("Request failed"))
+    }
+
+    #[test]
+    fn keeps_test_errors_and_response_codes() {
+        let error = anyhow::Error::from(TestError::HttpStatus(451)).context("Manifest request failed");
+        assert_eq!(TestError::classify(&error), TestError::HttpStatus(451));
+        assert_eq!(TestError::from_response_code(ResponseCode::NXDomain), TestError::Nxdomain);
+        assert_eq!(TestError::from_response_code(ResponseCode::Refused), TestError::Refused);
+        assert_eq!(TestError::from_response_code(ResponseCode::NoError), TestError::NoRecords);
+    }
+
+    #[test]
+    fn maps_io_errors() {
+        assert_eq!(classify_io(io::ErrorKind::ConnectionRefused), TestError::ConnectionRefused);
+        assert_eq!(classify_io(io::ErrorKind::ConnectionAborted), TestError::ConnectionReset);
+        assert_eq!(classify_io(io::ErrorKind::TimedOut), TestError::ReadTimeout);
+        assert_eq!(classify_io(io::ErrorKind::WouldBlock), TestError::ReadTimeout);
+    }
+
+    #[test]
+    fn tells_certificate_errors_by_type() {
+        let certificate = rustls::Error::InvalidCertificate(rustls::CertificateError::UnknownIssuer);
+        let wrapped = anyhow::Error::from(io::Error::new(io::ErrorKind::InvalidData, certificate)).context("TLS failed");
+        assert!(matches!(TestError::classify(&wrapped), TestError::CertificateMismatch(_)));
+
+        let alert = rustls::Error::AlertReceived(rustls::AlertDescription::HandshakeFailure);
+        assert!(matches!(TestError::classify(&alert.into()), TestError::TlsHandshakeFailed(_)));
+
+        // Mentioning a certificate doesn't make an error a certificate error
+        let text = anyhow::anyhow!("could not read the certificate file");
+        assert!(matches!(TestError::classify(&text), TestError::Other(_)));
+    }
+
+    #[test]
+    fn dot_handshake_failures_are_tls_errors() {
+        let io_error = io::Error::new(io::ErrorKind::ConnectionRefused, "tls error: invalid peer certificate: UnknownIssuer");
+        let error = ResolveError::from(ProtoError::from(io_error));
+        assert!(matches!(TestError::classify(&error.into()), TestError::TlsHandshakeFailed(_)));
+
+        let refused = ResolveError::from(ProtoError::from(io::Error::from(io::ErrorKind::ConnectionRefused)));
+        assert_eq!(TestError::classify(&refused.into()), TestError::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn reqwest_errors_are_split_by_phase() {
+        let client = reqwest::Client::builder().read_timeout(Duration::from_millis(300)).build().unwrap();
+
+        // Headers arrive, then the body stalls
+        let port = stalling_server(b"HTTP/1.1 200 OK\r\ncontent-length: 100\r\n\r\nabc").await;
+        let response = client.get(format!("http://127.0.0.1:{}/", port)).send().await.unwrap();
+        let error = response.bytes().await.unwrap_err();
+        assert_eq!(TestError::classify(&error.into()), TestError::ReadTimeout);
+
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let error = client.get(format!("http://127.0.0.1:{}/", closed)).send().await.unwrap_err();
+        assert_eq!(TestError::classify(&error.into()), TestError::ConnectionRefused);
+
+        // Plain HTTP where a TLS handshake was expected
+        let port = stalling_server(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        let error = client
+            .get(format!("https://127.0.0.1:{}/", port))
+            .send()
+            .await
+            .context("HTTP request failed")
+            .unwrap_err();
+        assert!(matches!(TestError::classify(&error), TestError::TlsHandshakeFailed(_)), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn ureq_read_timeouts_are_read_timeouts() {
+        let port = stalling_server(b"HTTP/1.1 200 OK\r\ncontent-length: 100\r\n\r\n").await;
+        let error = tokio::task::spawn_blocking(move || {
+            let agent = ureq::AgentBuilder::new().timeout_read(Duration::from_millis(300)).build();
+            let response = agent.get(&format!("http://127.0.0.1:{}/", port)).call()?;
+            let mut body = Vec::new();
+            std::io::Read::read_to_end(&mut response.into_reader(), &mut body)?;
+            anyhow::Ok(())
+        })
+        .await
+        .unwrap()
+        .unwrap_err();
+        assert_eq!(TestError::classify(&error), TestError::ReadTimeout);
+    }
+}
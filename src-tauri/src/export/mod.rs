@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use crate::dns::{bracket_ipv6, DnsServer, DnsTestResult, DnsTransport};
+use crate::forwarder::{result_host, rules_from_results, ForwardRule};
+
+const HEADER: &str = "Generated by Bargozin from the latest DNS test";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfigFormat {
+    Dnsmasq,
+    Coredns,
+    Smartdns,
+    Hosts,
+}
+
+impl ConfigFormat {
+    pub const ALL: [ConfigFormat; 4] = [
+        ConfigFormat::Dnsmasq,
+        ConfigFormat::Coredns,
+        ConfigFormat::Smartdns,
+        ConfigFormat::Hosts,
+    ];
+
+    pub fn file_name(self) -> &'static str {
+        match self {
+            ConfigFormat::Dnsmasq => "dnsmasq.conf",
+            ConfigFormat::Coredns => "Corefile",
+            ConfigFormat::Smartdns => "smartdns.conf",
+            ConfigFormat::Hosts => "hosts",
+        }
+    }
+}
+
+/// One generated file.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedConfig {
+    pub format: ConfigFormat,
+    pub path: String,
+    pub content: String,
+}
+
+/// Per-domain resolver choices, and the address each tested host was reached
+/// at, ready to be written out in the formats of common DNS daemons.
+#[derive(Debug, Clone, Default)]
+pub struct DnsConfigExport {
+    pub rules: Vec<ForwardRule>,
+    pub default_server: Option<String>,
+    pub pinned: Vec<(String, IpAddr)>,
+}
+
+impl DnsConfigExport {
+    pub fn from_results(results: &[DnsTestResult], default_server: Option<String>) -> Self {
+        let rules = rules_from_results(results);
+
+        // Hosts are pinned to the address they were reached at through their chosen server
+        let pinned = rules
+            .iter()
+            .filter_map(|rule| {
+                let result = results.iter().find(|result| {
+                    result.status
+                        && result.dns_server == rule.dns_server
+                        && result_host(result).as_deref() == Some(rule.suffix.as_str())
+                })?;
+                let ip = result
+                    .connected_address
+                    .or_else(|| result.resolved_records.first().map(|record| record.ip))?;
+                Some((rule.suffix.clone(), ip))
+            })
+            .collect();
+
+        Self {
+            rules,
+            default_server,
+            pinned,
+        }
+    }
+
+    pub fn render(&self, format: ConfigFormat) -> String {
+        match format {
+            ConfigFormat::Dnsmasq => self.dnsmasq(),
+            ConfigFormat::Coredns => self.coredns(),
+            ConfigFormat::Smartdns => self.smartdns(),
+            ConfigFormat::Hosts => self.hosts(),
+        }
+    }
+
+    // `server=/domain/ip#port` lines; dnsmasq only forwards over plain DNS
+    fn dnsmasq(&self) -> String {
+        let mut out = format!("# {}\n", HEADER);
+        for rule in &self.rules {
+            match plain_server(&rule.dns_server) {
+                Some(server) => writeln!(out, "server=/{}/{}", rule.suffix, dnsmasq_address(&server)),
+                None => writeln!(out, "# server=/{}/ skipped: dnsmasq can't forward to {}", rule.suffix, rule.dns_server),
+            }
+            .unwrap();
+        }
+        if let Some(default) = &self.default_server {
+            match plain_server(default) {
+                // Only the default server answers everything else, not the system's resolv.conf ones
+                Some(server) => writeln!(out, "no-resolv\nserver={}", dnsmasq_address(&server)),
+                None => writeln!(out, "# default server skipped: dnsmasq can't forward to {}", default),
+            }
+            .unwrap();
+        }
+        out
+    }
+
+    // One server block per upstream, listing every zone it serves; CoreDNS
+    // picks the most specific zone for each query
+    fn coredns(&self) -> String {
+        let mut out = format!("# {}\n", HEADER);
+        for (dns_server, suffixes) in group_by_server(&self.rules) {
+            match coredns_forward(dns_server) {
+                Some(forward) => {
+                    writeln!(out, "{} {{\n{}    cache\n}}\n", suffixes.join(" "), forward).unwrap();
+                }
+                None => {
+                    writeln!(out, "# {} skipped: CoreDNS can't forward to {}\n", suffixes.join(" "), dns_server).unwrap();
+                }
+            }
+        }
+        if let Some(default) = &self.default_server {
+            match coredns_forward(default) {
+                Some(forward) => writeln!(out, ". {{\n{}    cache\n}}", forward).unwrap(),
+                None => writeln!(out, "# default server skipped: CoreDNS can't forward to {}", default).unwrap(),
+            }
+        }
+        out
+    }
+
+    // Each upstream goes in its own group, and `nameserver /domain/group` sends
+    // the domain there. The default server is the only one in the default group.
+    fn smartdns(&self) -> String {
+        let mut out = format!("# {}\n", HEADER);
+        let mut nameservers = String::new();
+        for (index, (dns_server, suffixes)) in group_by_server(&self.rules).into_iter().enumerate() {
+            let group = format!("bargozin{}", index + 1);
+            match smartdns_server(dns_server) {
+                Some(server) => {
+                    writeln!(out, "{} -group {} -exclude-default-group", server, group).unwrap();
+                    for suffix in suffixes {
+                        writeln!(nameservers, "nameserver /{}/{}", suffix, group).unwrap();
+                    }
+                }
+                None => writeln!(out, "# {} skipped: SmartDNS can't forward to {}", suffixes.join(", "), dns_server).unwrap(),
+            }
+        }
+        if let Some(default) = &self.default_server {
+            match smartdns_server(default) {
+                Some(server) => writeln!(out, "{}", server).unwrap(),
+                None => writeln!(out, "# default server skipped: SmartDNS can't forward to {}", default).unwrap(),
+            }
+        }
+        out.push_str(&nameservers);
+        out
+    }
+
+    fn hosts(&self) -> String {
+        let mut out = format!("# {}\n", HEADER);
+        for (host, ip) in &self.pinned {
+            writeln!(out, "{} {}", ip, host).unwrap();
+        }
+        out
+    }
+
+    /// Writes the given formats into `directory`, replacing earlier exports.
+    pub async fn write(&self, directory: &Path, formats: &[ConfigFormat]) -> anyhow::Result<Vec<ExportedConfig>> {
+        tokio::fs::create_dir_all(directory)
+            .await
+            .with_context(|| format!("Could not create {}", directory.display()))?;
+
+        let mut exported = Vec::new();
+        for &format in formats {
+            let path = directory.join(format.file_name());
+            let content = self.render(format);
+            tokio::fs::write(&path, &content)
+                .await
+                .with_context(|| format!("Could not write {}", path.display()))?;
+            exported.push(ExportedConfig {
+                format,
+                path: path.display().to_string(),
+                content,
+            });
+        }
+        Ok(exported)
+    }
+}
+
+// Upstreams in the order they first appear, each with the suffixes it serves
+fn group_by_server(rules: &[ForwardRule]) -> Vec<(&str, Vec<&str>)> {
+    let mut order: Vec<&str> = Vec::new();
+    let mut groups: HashMap<&str, Vec<&str>> = HashMap::new();
+    for rule in rules {
+        let suffixes = groups.entry(&rule.dns_server).or_insert_with(|| {
+            order.push(&rule.dns_server);
+            Vec::new()
+        });
+        suffixes.push(&rule.suffix);
+    }
+    order
+        .into_iter()
+        .map(|server| (server, groups.remove(server).unwrap_or_default()))
+        .collect()
+}
+
+// The server if it is plain DNS at an IP, which every daemon here understands
+fn plain_server(entry: &str) -> Option<DnsServer> {
+    let server = DnsServer::parse(entry).ok()?;
+    let plain = matches!(server.transport, DnsTransport::Udp | DnsTransport::Tcp);
+    (plain && server.address.parse::<IpAddr>().is_ok()).then_some(server)
+}
+
+fn dnsmasq_address(server: &DnsServer) -> String {
+    if server.port == server.transport.default_port() {
+        server.address.clone()
+    } else {
+        format!("{}#{}", server.address, server.port)
+    }
+}
+
+// The body of a CoreDNS `forward` plugin for the server, which takes IPs only
+fn coredns_forward(entry: &str) -> Option<String> {
+    let server = DnsServer::parse(entry).ok()?;
+    server.address.parse::<IpAddr>().ok()?;
+    let address = format!("{}:{}", bracket_ipv6(&server.address), server.port);
+    match server.transport {
+        DnsTransport::Udp => Some(format!("    forward . {}\n", address)),
+        DnsTransport::Tcp => Some(format!("    forward . {} {{\n        force_tcp\n    }}\n", address)),
+        DnsTransport::Tls => Some(format!(
+            "    forward . tls://{} {{\n        tls_servername {}\n    }}\n",
+            address,
+            server.tls_name.unwrap_or(server.address.clone())
+        )),
+        DnsTransport::Https => None,
+    }
+}
+
+// The SmartDNS `server*` directive for the server, without a group
+fn smartdns_server(entry: &str) -> Option<String> {
+    let server = DnsServer::parse(entry).ok()?;
+    if server.transport != DnsTransport::Https {
+        server.address.parse::<IpAddr>().ok()?;
+    }
+    let address = format!("{}:{}", bracket_ipv6(&server.address), server.port);
+    Some(match server.transport {
+        DnsTransport::Udp => format!("server {}", address),
+        DnsTransport::Tcp => format!("server-tcp {}", address),
+        DnsTransport::Tls => match server.tls_name {
+            Some(name) if name != server.address => format!("server-tls {} -host-name {}", address, name),
+            _ => format!("server-tls {}", address),
+        },
+        DnsTransport::Https => format!("server-https {}", server.address),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(suffix: &str, dns_server: &str) -> ForwardRule {
+        ForwardRule {
+            suffix: suffix.to_string(),
+            dns_server: dns_server.to_string(),
+        }
+    }
+
+    // One rule per kind of upstream, with two suffixes sharing a server
+    fn export() -> DnsConfigExport {
+        DnsConfigExport {
+            rules: vec![
+                rule("example.com", "8.8.8.8"),
+                rule("example.org", "127.0.0.1:5353"),
+                rule("example.net", "tcp://9.9.9.9"),
+                rule("example.io", "tls://1.1.1.1#cloudflare-dns.com"),
+                rule("example.dev", "https://dns.google/dns-query"),
+                rule("www.example.com", "8.8.8.8"),
+                rule("v6.example", "[2001:4860:4860::8888]:5353"),
+            ],
+            default_server: Some("1.1.1.1".to_string()),
+            pinned: vec![
+                ("example.com".to_string(), "93.184.215.14".parse().unwrap()),
+                ("v6.example".to_string(), "2001:db8::1".parse().unwrap()),
+            ],
+        }
+    }
+
+    #[test]
+    fn renders_dnsmasq() {
+        assert_eq!(
+            export().render(ConfigFormat::Dnsmasq),
+            "# Generated by Bargozin from the latest DNS test
+server=/example.com/8.8.8.8
+server=/example.org/127.0.0.1#5353
+server=/example.net/9.9.9.9
+# server=/example.io/ skipped: dnsmasq can't forward to tls://1.1.1.1#cloudflare-dns.com
+# server=/example.dev/ skipped: dnsmasq can't forward to https://dns.google/dns-query
+server=/www.example.com/8.8.8.8
+server=/v6.example/2001:4860:4860::8888#5353
+no-resolv
+server=1.1.1.1
+"
+        );
+    }
+
+    #[test]
+    fn renders_coredns() {
+        assert_eq!(
+            export().render(ConfigFormat::Coredns),
+            "# Generated by Bargozin from the latest DNS test
+example.com www.example.com {
+    forward . 8.8.8.8:53
+    cache
+}
+
+example.org {
+    forward . 127.0.0.1:5353
+    cache
+}
+
+example.net {
+    forward . 9.9.9.9:53 {
+        force_tcp
+    }
+    cache
+}
+
+example.io {
+    forward . tls://1.1.1.1:853 {
+        tls_servername cloudflare-dns.com
+    }
+    cache
+}
+
+# example.dev skipped: CoreDNS can't forward to https://dns.google/dns-query
+
+v6.example {
+    forward . [2001:4860:4860::8888]:5353
+    cache
+}
+
+. {
+    forward . 1.1.1.1:53
+    cache
+}
+"
+        );
+    }
+
+    #[test]
+    fn renders_smartdns() {
+        assert_eq!(
+            export().render(ConfigFormat::Smartdns),
+            "# Generated by Bargozin from the latest DNS test
+server 8.8.8.8:53 -group bargozin1 -exclude-default-group
+server 127.0.0.1:5353 -group bargozin2 -exclude-default-group
+server-tcp 9.9.9.9:53 -group bargozin3 -exclude-default-group
+server-tls 1.1.1.1:853 -host-name cloudflare-dns.com -group bargozin4 -exclude-default-group
+server-https https://dns.google/dns-query -group bargozin5 -exclude-default-group
+server [2001:4860:4860::8888]:5353 -group bargozin6 -exclude-default-group
+server 1.1.1.1:53
+nameserver /example.com/bargozin1
+nameserver /www.example.com/bargozin1
+nameserver /example.org/bargozin2
+nameserver /example.net/bargozin3
+nameserver /example.io/bargozin4
+nameserver /example.dev/bargozin5
+nameserver /v6.example/bargozin6
+"
+        );
+    }
+
+    #[test]
+    fn renders_hosts() {
+        assert_eq!(
+            export().render(ConfigFormat::Hosts),
+            "# Generated by Bargozin from the latest DNS test
+93.184.215.14 example.com
+2001:db8::1 v6.example
+"
+        );
+    }
+
+    #[test]
+    fn a_doh_default_server_is_skipped_where_unsupported() {
+        let export = DnsConfigExport {
+            rules: vec![],
+            default_server: Some("https://dns.google/dns-query".to_string()),
+            pinned: vec![],
+        };
+        assert_eq!(
+            export.render(ConfigFormat::Dnsmasq),
+            "# Generated by Bargozin from the latest DNS test
+# default server skipped: dnsmasq can't forward to https://dns.google/dns-query
+"
+        );
+        assert_eq!(
+            export.render(ConfigFormat::Coredns),
+            "# Generated by Bargozin from the latest DNS test
+# default server skipped: CoreDNS can't forward to https://dns.google/dns-query
+"
+        );
+        assert_eq!(
+            export.render(ConfigFormat::Smartdns),
+            "# Generated by Bargozin from the latest DNS test
+server-https https://dns.google/dns-query
+"
+        );
+    }
+}
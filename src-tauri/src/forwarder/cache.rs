@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use trust_dns_resolver::proto::op::ResponseCode;
+use trust_dns_resolver::proto::rr::RecordType;
+use crate::dns::UpstreamAnswer;
+
+// Enough for a desktop's browsing without growing unbounded
+const MAX_ENTRIES: usize = 4096;
+// How long answers without records (NXDOMAIN, NODATA) are kept
+const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+// Upper bound so a huge TTL can't pin a stale answer after the rules change
+const MAX_TTL: Duration = Duration::from_secs(3600);
+
+struct CacheEntry {
+    answer: UpstreamAnswer,
+    stored: Instant,
+    expires: Instant,
+}
+
+/// Answers keyed by lowercase name and record type, kept for their lowest TTL.
+#[derive(Default)]
+pub struct DnsCache {
+    entries: HashMap<(String, RecordType), CacheEntry>,
+}
+
+impl DnsCache {
+    // The cached answer with TTLs counted down by the time it has been kept
+    pub fn get(&mut self, name: &str, record_type: RecordType) -> Option<UpstreamAnswer> {
+        self.get_at(name, record_type, Instant::now())
+    }
+
+    fn get_at(&mut self, name: &str, record_type: RecordType, now: Instant) -> Option<UpstreamAnswer> {
+        let key = (name.to_string(), record_type);
+        let entry = self.entries.get(&key)?;
+        if now >= entry.expires {
+            self.entries.remove(&key);
+            return None;
+        }
+
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let mut answer = entry.answer.clone();
+        for record in &mut answer.answers {
+            let ttl = record.ttl().saturating_sub(elapsed);
+            record.set_ttl(ttl);
+        }
+        Some(answer)
+    }
+
+    pub fn insert(&mut self, name: &str, record_type: RecordType, answer: UpstreamAnswer) {
+        // Server failures say nothing about the name, try again next time
+        if answer.response_code == ResponseCode::ServFail {
+            return;
+        }
+        let ttl = match answer.answers.iter().map(|record| record.ttl()).min() {
+            Some(ttl) => Duration::from_secs(ttl.into()).min(MAX_TTL),
+            None => NEGATIVE_TTL,
+        };
+        if ttl.is_zero() {
+            return;
+        }
+
+        if self.entries.len() >= MAX_ENTRIES {
+            let now = Instant::now();
+            self.entries.retain(|_, entry| entry.expires > now);
+        }
+        if self.entries.len() >= MAX_ENTRIES {
+            // Still full of live answers, drop the one closest to expiring
+            if let Some(key) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone())
+            {
+                self.entries.remove(&key);
+            }
+        }
+
+        let stored = Instant::now();
+        self.entries.insert(
+            (name.to_string(), record_type),
+            CacheEntry {
+                answer,
+                stored,
+                expires: stored + ttl,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use trust_dns_resolver::proto::rr::rdata::A;
+    use trust_dns_resolver::proto::rr::{Name, RData, Record};
+
+    fn answer(response_code: ResponseCode, ttls: &[u32]) -> UpstreamAnswer {
+        let name = Name::from_ascii("example.com.").unwrap();
+        UpstreamAnswer {
+            response_code,
+            answers: ttls
+                .iter()
+                .map(|ttl| Record::from_rdata(name.clone(), *ttl, RData::A(A(Ipv4Addr::new(93, 184, 215, 14)))))
+                .collect(),
+        }
+    }
+
+    fn ttls(answer: &UpstreamAnswer) -> Vec<u32> {
+        answer.answers.iter().map(|record| record.ttl()).collect()
+    }
+
+    #[test]
+    fn answers_count_down_and_expire_with_the_lowest_ttl() {
+        let mut cache = DnsCache::default();
+        cache.insert("example.com", RecordType::A, answer(ResponseCode::NoError, &[300, 60]));
+        let now = Instant::now();
+
+        let fresh = cache.get_at("example.com", RecordType::A, now).unwrap();
+        assert_eq!(ttls(&fresh), vec![300, 60]);
+        let later = cache.get_at("example.com", RecordType::A, now + Duration::from_secs(45)).unwrap();
+        assert_eq!(ttls(&later), vec![255, 15]);
+        assert!(cache.get_at("example.com", RecordType::AAAA, now).is_none());
+
+        assert!(cache.get_at("example.com", RecordType::A, now + Duration::from_secs(61)).is_none());
+        // Expired entries are dropped, not just hidden
+        assert!(cache.get_at("example.com", RecordType::A, now).is_none());
+    }
+
+    #[test]
+    fn negative_answers_are_kept_briefly_and_failures_not_at_all() {
+        let mut cache = DnsCache::default();
+        cache.insert("missing.example", RecordType::A, answer(ResponseCode::NXDomain, &[]));
+        cache.insert("broken.example", RecordType::A, answer(ResponseCode::ServFail, &[]));
+        cache.insert("uncached.example", RecordType::A, answer(ResponseCode::NoError, &[0]));
+        let now = Instant::now();
+
+        assert!(cache.get_at("missing.example", RecordType::A, now).is_some());
+        assert!(cache.get_at("missing.example", RecordType::A, now + NEGATIVE_TTL + Duration::from_secs(1)).is_none());
+        assert!(cache.get_at("broken.example", RecordType::A, now).is_none());
+        assert!(cache.get_at("uncached.example", RecordType::A, now).is_none());
+    }
+
+    #[test]
+    fn long_ttls_are_capped() {
+        let mut cache = DnsCache::default();
+        cache.insert("example.com", RecordType::A, answer(ResponseCode::NoError, &[86400]));
+        assert!(cache.get_at("example.com", RecordType::A, Instant::now() + MAX_TTL + Duration::from_secs(1)).is_none());
+    }
+}
@@ -0,0 +1,282 @@
+mod cache;
+mod rules;
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use trust_dns_resolver::proto::op::{Edns, Message, MessageType, ResponseCode};
+use crate::dns::{Upstream, UpstreamAnswer};
+use cache::DnsCache;
+
+pub use rules::{result_host, rule_for, rules_from_results, ForwardRule};
+
+// Unprivileged, so no root is needed; point a client or dnsmasq at 127.0.0.1#5053
+pub const DEFAULT_FORWARDER_PORT: u16 = 5053;
+// Classic DNS limit for UDP replies to clients that don't advertise EDNS
+const UDP_PLAIN_LIMIT: usize = 512;
+// EDNS payload size we advertise, the DNS flag day 2020 recommendation
+const EDNS_PAYLOAD: u16 = 1232;
+
+/// Where a running forwarder listens and how it routes queries.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ForwarderInfo {
+    pub address: SocketAddr,
+    pub default_server: String,
+    pub rules: Vec<ForwardRule>,
+}
+
+/// One query the forwarder answered.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryLogEntry {
+    pub name: String,
+    pub record_type: String,
+    pub upstream: String,
+    pub cached: bool,
+    pub response_code: String,
+    pub answers: usize,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+type QueryLogger = Arc<dyn Fn(&QueryLogEntry) + Send + Sync>;
+
+struct Forwarder {
+    rules: Vec<ForwardRule>,
+    default_server: String,
+    upstreams: HashMap<String, Upstream>,
+    cache: Mutex<DnsCache>,
+    on_query: QueryLogger,
+}
+
+/// A running forwarder; it stops when this is dropped.
+pub struct ForwarderHandle {
+    pub info: ForwarderInfo,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for ForwarderHandle {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        println!("DNS forwarder on {} stopped", self.info.address);
+    }
+}
+
+/// Starts forwarding on 127.0.0.1:`port` over UDP and TCP. Names matching a
+/// rule go to its server, everything else to `default_server`.
+pub async fn start_forwarder(
+    port: u16,
+    default_server: String,
+    rules: Vec<ForwardRule>,
+    on_query: impl Fn(&QueryLogEntry) + Send + Sync + 'static,
+) -> anyhow::Result<ForwarderHandle> {
+    let mut upstreams = HashMap::new();
+    let servers = std::iter::once(&default_server).chain(rules.iter().map(|rule| &rule.dns_server));
+    for server in servers {
+        if !upstreams.contains_key(server) {
+            let upstream = Upstream::connect(server)
+                .await
+                .with_context(|| format!("Invalid upstream DNS server {}", server))?;
+            upstreams.insert(server.clone(), upstream);
+        }
+    }
+
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let udp = UdpSocket::bind(address)
+        .await
+        .with_context(|| format!("Could not listen on {}/udp", address))?;
+    // Port 0 lets the OS pick, TCP then shares the port UDP got
+    let address = udp.local_addr()?;
+    let tcp = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Could not listen on {}/tcp", address))?;
+
+    let info = ForwarderInfo {
+        address,
+        default_server: default_server.clone(),
+        rules: rules.clone(),
+    };
+    let forwarder = Arc::new(Forwarder {
+        rules,
+        default_server,
+        upstreams,
+        cache: Mutex::new(DnsCache::default()),
+        on_query: Arc::new(on_query),
+    });
+
+    println!(
+        "DNS forwarder listening on {} with {} rule(s), default upstream {}",
+        address,
+        info.rules.len(),
+        info.default_server
+    );
+    let tasks = vec![
+        tokio::spawn(serve_udp(Arc::new(udp), forwarder.clone())),
+        tokio::spawn(serve_tcp(tcp, forwarder)),
+    ];
+    Ok(ForwarderHandle { info, tasks })
+}
+
+async fn serve_udp(socket: Arc<UdpSocket>, forwarder: Arc<Forwarder>) {
+    let mut buf = vec![0u8; u16::MAX as usize];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                // Windows reports ICMP port unreachable from an earlier send here
+                eprintln!("DNS forwarder UDP receive failed: {}", e);
+                continue;
+            }
+        };
+        let request = buf[..len].to_vec();
+        let socket = socket.clone();
+        let forwarder = forwarder.clone();
+        tokio::spawn(async move {
+            if let Some(response) = forwarder.answer(&request, true).await {
+                if let Err(e) = socket.send_to(&response, peer).await {
+                    eprintln!("DNS forwarder could not reply to {}: {}", peer, e);
+                }
+            }
+        });
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, forwarder: Arc<Forwarder>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let forwarder = forwarder.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_tcp_connection(stream, &forwarder).await {
+                        println!("DNS forwarder TCP connection from {} closed: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => eprintln!("DNS forwarder TCP accept failed: {}", e),
+        }
+    }
+}
+
+// Queries on a TCP connection are length-prefixed and may be pipelined (RFC 7766)
+async fn serve_tcp_connection(mut stream: TcpStream, forwarder: &Forwarder) -> anyhow::Result<()> {
+    loop {
+        let len = match stream.read_u16().await {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut request = vec![0u8; len];
+        stream.read_exact(&mut request).await?;
+
+        if let Some(response) = forwarder.answer(&request, false).await {
+            stream.write_u16(response.len() as u16).await?;
+            stream.write_all(&response).await?;
+        }
+    }
+}
+
+impl Forwarder {
+    // The wire-format reply to a wire-format query, or None for garbage that
+    // doesn't even parse as a header
+    async fn answer(&self, request: &[u8], udp: bool) -> Option<Vec<u8>> {
+        let query = Message::from_vec(request).ok()?;
+        let mut response = Message::new();
+        response
+            .set_id(query.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(query.op_code())
+            .set_recursion_desired(query.recursion_desired())
+            .set_recursion_available(true)
+            .add_queries(query.queries().to_vec());
+        if query.extensions().is_some() {
+            let mut edns = Edns::new();
+            edns.set_max_payload(EDNS_PAYLOAD);
+            response.set_edns(edns);
+        }
+
+        let Some(question) = query.queries().first().filter(|_| query.message_type() == MessageType::Query) else {
+            response.set_response_code(ResponseCode::FormErr);
+            return response.to_vec().ok();
+        };
+
+        let started = Instant::now();
+        let name = rules::normalize_name(&question.name().to_ascii());
+        let record_type = question.query_type();
+        let upstream = rule_for(&self.rules, &name)
+            .map(|rule| rule.dns_server.as_str())
+            .unwrap_or(&self.default_server);
+
+        let cached = self.cache.lock().unwrap().get(&name, record_type);
+        let (result, from_cache) = match cached {
+            Some(answer) => (Ok(answer), true),
+            None => {
+                let result = match self.upstreams.get(upstream) {
+                    Some(client) => client.query(question.name(), record_type).await,
+                    None => Err(anyhow::anyhow!("No connection to {}", upstream)),
+                };
+                if let Ok(answer) = &result {
+                    self.cache.lock().unwrap().insert(&name, record_type, answer.clone());
+                }
+                (result, false)
+            }
+        };
+
+        let (answer, error) = match result {
+            Ok(answer) => (answer, None),
+            Err(e) => (
+                UpstreamAnswer {
+                    response_code: ResponseCode::ServFail,
+                    answers: vec![],
+                },
+                Some(format!("{:#}", e)),
+            ),
+        };
+        response.set_response_code(answer.response_code);
+        response.add_answers(answer.answers.iter().cloned());
+
+        let mut bytes = response.to_vec().ok()?;
+        let limit = match query.extensions() {
+            Some(edns) => (edns.max_payload() as usize).max(UDP_PLAIN_LIMIT),
+            None => UDP_PLAIN_LIMIT,
+        };
+        if udp && bytes.len() > limit {
+            // Too big for the client's buffer; the TC bit tells it to retry over TCP
+            response.take_answers();
+            response.set_truncated(true);
+            bytes = response.to_vec().ok()?;
+        }
+
+        let entry = QueryLogEntry {
+            name,
+            record_type: record_type.to_string(),
+            upstream: upstream.to_string(),
+            cached: from_cache,
+            response_code: answer.response_code.to_string(),
+            answers: answer.answers.len(),
+            duration_ms: started.elapsed().as_millis() as u64,
+            error,
+        };
+        println!(
+            "DNS forwarder: {} {} via {}{} -> {} ({} answers, {} ms)",
+            entry.record_type,
+            entry.name,
+            entry.upstream,
+            if entry.cached { " (cached)" } else { "" },
+            entry.response_code,
+            entry.answers,
+            entry.duration_ms
+        );
+        (self.on_query)(&entry);
+
+        Some(bytes)
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use crate::dns::DnsTestResult;
+
+/// Sends queries for `suffix` and every name under it to `dns_server`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ForwardRule {
+    pub suffix: String,
+    pub dns_server: String,
+}
+
+impl ForwardRule {
+    fn matches(&self, name: &str) -> bool {
+        let suffix = normalize_name(&self.suffix);
+        name == suffix || name.ends_with(&format!(".{}", suffix))
+    }
+}
+
+// Lowercase without the trailing root dot, as names are compared
+pub fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+// The rule with the longest suffix covering `name`, so `registry-1.docker.io`
+// beats `docker.io`
+pub fn rule_for<'a>(rules: &'a [ForwardRule], name: &str) -> Option<&'a ForwardRule> {
+    let name = normalize_name(name);
+    rules
+        .iter()
+        .filter(|rule| rule.matches(&name))
+        .max_by_key(|rule| normalize_name(&rule.suffix).len())
+}
+
+// Tested hosts that must agree before their parent domain gets a rule; one
+// host alone says nothing about its siblings
+const MIN_AGREEING_HOSTS: usize = 2;
+
+/// Builds rules from domain test results: each tested host goes to the usable
+/// server that answered it fastest. Where several tested hosts under a parent
+/// domain all agree on a server, the parent gets a rule too so its other
+/// subdomains follow.
+pub fn rules_from_results(results: &[DnsTestResult]) -> Vec<ForwardRule> {
+    // host -> (latency, server) of the fastest usable result
+    let mut best: HashMap<String, (u64, String)> = HashMap::new();
+    for result in results {
+        let Some(host) = result_host(result) else {
+            continue;
+        };
+        let poisoned = result.poisoning.as_ref().is_some_and(|check| check.poisoned);
+        if !result.status || poisoned {
+            continue;
+        }
+        let latency = result
+            .http_latency
+            .median
+            .or(result.http_response_time)
+            .or(result.response_time)
+            .unwrap_or(u64::MAX);
+        let entry = best.entry(host).or_insert((u64::MAX, String::new()));
+        if entry.1.is_empty() || latency < entry.0 {
+            *entry = (latency, result.dns_server.clone());
+        }
+    }
+
+    let mut rules: Vec<ForwardRule> = best
+        .iter()
+        .map(|(host, (_, dns_server))| ForwardRule {
+            suffix: host.clone(),
+            dns_server: dns_server.clone(),
+        })
+        .collect();
+
+    let mut parents: HashMap<String, Vec<&str>> = HashMap::new();
+    for (host, (_, dns_server)) in &best {
+        if let Some(parent) = parent_domain(host) {
+            parents.entry(parent).or_default().push(dns_server);
+        }
+    }
+    for (parent, servers) in parents {
+        let agreed = servers.len() >= MIN_AGREEING_HOSTS && servers.iter().all(|server| *server == servers[0]);
+        if agreed && !best.contains_key(&parent) {
+            rules.push(ForwardRule {
+                suffix: parent,
+                dns_server: servers[0].to_string(),
+            });
+        }
+    }
+
+    rules.sort_by(|a, b| a.suffix.cmp(&b.suffix));
+    rules
+}
+
+// The host a result's test URL points at
+pub fn result_host(result: &DnsTestResult) -> Option<String> {
+    let url = Url::parse(result.test_url.as_deref()?).ok()?;
+    url.host_str().map(normalize_name)
+}
+
+// The registrable part of a host, roughly: the last two labels, or three when
+// the second-level label is a common one like `co.uk` or `ac.ir`
+fn parent_domain(host: &str) -> Option<String> {
+    const SECOND_LEVEL: &[&str] = &["co", "com", "net", "org", "ac", "gov", "edu"];
+
+    if host.parse::<std::net::IpAddr>().is_ok() {
+        return None;
+    }
+    let labels: Vec<&str> = host.split('.').collect();
+    let keep = match labels.len() {
+        0..=2 => return None,
+        n if SECOND_LEVEL.contains(&labels[n - 2]) => 3,
+        _ => 2,
+    };
+    if labels.len() <= keep {
+        return None;
+    }
+    Some(labels[labels.len() - keep..].join("."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsTransport, HttpStatus, LatencyStats};
+
+    fn result(dns_server: &str, host: &str, response_time: u64) -> DnsTestResult {
+        DnsTestResult {
+            dns_server: dns_server.to_string(),
+            status: true,
+            response_time: Some(response_time),
+            error_message: None,
+            error_kind: None,
+            session_id: 0,
+            http_status: HttpStatus::Success,
+            test_url: Some(format!("https://{}/", host)),
+            transport: DnsTransport::Udp,
+            provider: None,
+            poisoning: None,
+            dnssec: None,
+            block_reason: None,
+            block_page: None,
+            resolved_records: vec![],
+            resolution_time: None,
+            http_response_time: None,
+            connected_address: None,
+            address_family: None,
+            address_attempts: vec![],
+            dns_latency: LatencyStats::default(),
+            http_latency: LatencyStats::default(),
+        }
+    }
+
+    fn rule(suffix: &str, dns_server: &str) -> ForwardRule {
+        ForwardRule {
+            suffix: suffix.to_string(),
+            dns_server: dns_server.to_string(),
+        }
+    }
+
+    #[test]
+    fn each_host_gets_its_fastest_usable_server() {
+        let mut failed = result("9.9.9.9", "registry.npmjs.org", 5);
+        failed.status = false;
+        let results = [
+            result("8.8.8.8", "registry.npmjs.org", 80),
+            result("1.1.1.1", "registry.npmjs.org", 40),
+            failed,
+        ];
+        assert_eq!(rules_from_results(&results), vec![rule("registry.npmjs.org", "1.1.1.1")]);
+    }
+
+    #[test]
+    fn parent_needs_several_agreeing_hosts() {
+        // A single tested host doesn't speak for the rest of the domain
+        let single = [result("1.1.1.1", "registry-1.docker.io", 40)];
+        assert_eq!(rules_from_results(&single), vec![rule("registry-1.docker.io", "1.1.1.1")]);
+
+        let agreeing = [
+            result("1.1.1.1", "registry-1.docker.io", 40),
+            result("1.1.1.1", "auth.docker.io", 30),
+        ];
+        let rules = rules_from_results(&agreeing);
+        assert!(rules.contains(&rule("docker.io", "1.1.1.1")), "{:?}", rules);
+        assert_eq!(rule_for(&rules, "production.cloudflare.docker.io.").unwrap().dns_server, "1.1.1.1");
+
+        let split = [
+            result("1.1.1.1", "registry-1.docker.io", 40),
+            result("8.8.8.8", "auth.docker.io", 30),
+        ];
+        assert!(rules_from_results(&split).iter().all(|rule| rule.suffix != "docker.io"));
+    }
+
+    #[test]
+    fn longest_suffix_wins() {
+        let rules = [rule("docker.io", "1.1.1.1"), rule("registry-1.docker.io", "8.8.8.8")];
+        assert_eq!(rule_for(&rules, "Registry-1.Docker.IO").unwrap().dns_server, "8.8.8.8");
+        assert_eq!(rule_for(&rules, "docker.io").unwrap().dns_server, "1.1.1.1");
+        assert!(rule_for(&rules, "notdocker.io").is_none());
+        assert_eq!(parent_domain("www.bbc.co.uk").as_deref(), Some("bbc.co.uk"));
+    }
+}
@@ -1,6 +1,14 @@
 mod dns;
 mod docker;
+mod download;
 mod commands;
+mod errors;
+mod export;
+mod forwarder;
+mod system;
+#[cfg(test)]
+mod test_support;
+mod timing;
 mod utils;
 
 pub use dns::{DnsTestResult, DownloadSpeedResult};
@@ -11,7 +19,7 @@ pub use utils::*;
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![test_dns_servers, test_download_speed_all_dns, test_docker_registries, validate_docker_image, abort_all_tasks])
+        .invoke_handler(tauri::generate_handler![test_dns_servers, test_dns_servers_batch, list_domain_presets, probe_dns_servers, recommend_dns_servers, export_dns_config, detect_system_dns_backend, apply_system_dns, revert_system_dns, start_dns_forwarder, stop_dns_forwarder, dns_forwarder_status, test_download_speed_all_dns, download_via_best_dns, cancel_file_download, test_docker_registries, validate_docker_image, abort_all_tasks])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -0,0 +1,449 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use crate::dns::{DnsServer, DnsTransport};
+
+const RESOLV_CONF_HEADER: &str = "# Written by Bargozin, revert from the app to restore the previous file";
+
+/// How the system's DNS servers are configured.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsBackend {
+    NetworkManager,
+    SystemdResolved,
+    ResolvConf,
+}
+
+/// What the system used before Bargozin changed it, kept on disk until reverted.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum DnsRollback {
+    NetworkManager {
+        connection: String,
+        device: String,
+        ipv4_dns: String,
+        ipv4_ignore_auto_dns: String,
+        ipv6_dns: String,
+        ipv6_ignore_auto_dns: String,
+    },
+    SystemdResolved {
+        interface: String,
+        servers: Vec<String>,
+    },
+    ResolvConf {
+        content: String,
+    },
+}
+
+impl DnsRollback {
+    fn backend(&self) -> DnsBackend {
+        match self {
+            DnsRollback::NetworkManager { .. } => DnsBackend::NetworkManager,
+            DnsRollback::SystemdResolved { .. } => DnsBackend::SystemdResolved,
+            DnsRollback::ResolvConf { .. } => DnsBackend::ResolvConf,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AppliedDns {
+    pub backend: DnsBackend,
+    pub servers: Vec<IpAddr>,
+    /// The connection or interface that was changed, if not the whole system
+    pub target: Option<String>,
+}
+
+/// Reads and changes the system DNS configuration. The paths are fields so the
+/// resolv.conf backend can be pointed at a scratch directory.
+pub struct SystemDns {
+    pub resolv_conf: PathBuf,
+    pub rollback_path: PathBuf,
+}
+
+impl Default for SystemDns {
+    fn default() -> Self {
+        let config_dir = dirs::config_dir().unwrap_or_else(|| PathBuf::from("."));
+        Self {
+            resolv_conf: PathBuf::from("/etc/resolv.conf"),
+            rollback_path: config_dir.join("bargozin").join("dns-rollback.json"),
+        }
+    }
+}
+
+impl SystemDns {
+    /// Picks the backend that owns the DNS settings. NetworkManager comes first since
+    /// its settings persist and it forwards them to systemd-resolved when both run.
+    pub async fn detect_backend(&self) -> Result<DnsBackend> {
+        if !cfg!(target_os = "linux") {
+            return Err(anyhow::anyhow!("Applying DNS settings is only supported on Linux"));
+        }
+
+        if let Ok(state) = run("nmcli", &["-t", "-f", "RUNNING", "general"]).await {
+            if state.trim() == "running" {
+                return Ok(DnsBackend::NetworkManager);
+            }
+        }
+
+        let managed_by_resolved = tokio::fs::read_link(&self.resolv_conf)
+            .await
+            .is_ok_and(|target| target.to_string_lossy().contains("systemd/resolve"));
+        if managed_by_resolved && run("resolvectl", &["status"]).await.is_ok() {
+            return Ok(DnsBackend::SystemdResolved);
+        }
+
+        Ok(DnsBackend::ResolvConf)
+    }
+
+    /// Points the system at `servers` through `backend`, remembering the previous
+    /// settings. Applying again keeps the first recorded settings, so a revert
+    /// always goes back to how the system was before Bargozin touched it.
+    pub async fn apply(&self, backend: DnsBackend, servers: &[IpAddr]) -> Result<AppliedDns> {
+        if servers.is_empty() {
+            return Err(anyhow::anyhow!("No DNS servers to apply"));
+        }
+
+        let (rollback, target) = match backend {
+            DnsBackend::NetworkManager => {
+                let (connection, device) = active_connection().await?;
+                let rollback = network_manager_settings(&connection, &device).await?;
+                (rollback, Some(connection))
+            }
+            DnsBackend::SystemdResolved => {
+                let interface = default_interface().await?;
+                let servers = resolved_link_servers(&interface).await?;
+                (DnsRollback::SystemdResolved { interface: interface.clone(), servers }, Some(interface))
+            }
+            DnsBackend::ResolvConf => {
+                let content = tokio::fs::read_to_string(&self.resolv_conf).await.unwrap_or_default();
+                (DnsRollback::ResolvConf { content }, None)
+            }
+        };
+
+        let first_change = match self.read_rollback().await? {
+            Some(previous) if previous.backend() != backend => {
+                return Err(anyhow::anyhow!(
+                    "DNS was already changed through {:?}, revert that first",
+                    previous.backend()
+                ));
+            }
+            Some(_) => false,
+            None => {
+                self.save_rollback(&rollback).await?;
+                true
+            }
+        };
+
+        let result = self.write_servers(&rollback, servers).await;
+        if result.is_err() && first_change {
+            // Nothing changed, so there is nothing to revert
+            tokio::fs::remove_file(&self.rollback_path).await?;
+        }
+        result?;
+
+        println!("Applied DNS {:?} via {:?}", servers, backend);
+        Ok(AppliedDns {
+            backend,
+            servers: servers.to_vec(),
+            target,
+        })
+    }
+
+    // Sets the servers on whatever `rollback` was taken from
+    async fn write_servers(&self, rollback: &DnsRollback, servers: &[IpAddr]) -> Result<()> {
+        match rollback {
+            DnsRollback::NetworkManager { connection, device, .. } => {
+                let (ipv4, ipv6): (Vec<IpAddr>, Vec<IpAddr>) = servers.iter().partition(|ip| ip.is_ipv4());
+                let ipv4_dns = join_ips(&ipv4);
+                let ipv6_dns = join_ips(&ipv6);
+                run(
+                    "nmcli",
+                    &[
+                        "connection", "modify", connection,
+                        "ipv4.dns", &ipv4_dns,
+                        "ipv4.ignore-auto-dns", if ipv4.is_empty() { "no" } else { "yes" },
+                        "ipv6.dns", &ipv6_dns,
+                        "ipv6.ignore-auto-dns", if ipv6.is_empty() { "no" } else { "yes" },
+                    ],
+                )
+                .await?;
+                run("nmcli", &["device", "reapply", device]).await?;
+            }
+            DnsRollback::SystemdResolved { interface, .. } => {
+                let mut args = vec!["dns".to_string(), interface.clone()];
+                args.extend(servers.iter().map(|ip| ip.to_string()));
+                run_owned("resolvectl", &args).await?;
+                run("resolvectl", &["flush-caches"]).await?;
+            }
+            DnsRollback::ResolvConf { content } => {
+                write_resolv_conf(&self.resolv_conf, &render_resolv_conf(content, servers)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores the settings recorded by the first [`SystemDns::apply`].
+    pub async fn revert(&self) -> Result<DnsBackend> {
+        let rollback = self
+            .read_rollback()
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("There are no DNS changes to revert"))?;
+
+        match &rollback {
+            DnsRollback::NetworkManager {
+                connection,
+                device,
+                ipv4_dns,
+                ipv4_ignore_auto_dns,
+                ipv6_dns,
+                ipv6_ignore_auto_dns,
+            } => {
+                run(
+                    "nmcli",
+                    &[
+                        "connection", "modify", connection,
+                        "ipv4.dns", ipv4_dns,
+                        "ipv4.ignore-auto-dns", ipv4_ignore_auto_dns,
+                        "ipv6.dns", ipv6_dns,
+                        "ipv6.ignore-auto-dns", ipv6_ignore_auto_dns,
+                    ],
+                )
+                .await?;
+                run("nmcli", &["device", "reapply", device]).await?;
+            }
+            DnsRollback::SystemdResolved { interface, servers } => {
+                if servers.is_empty() {
+                    run("resolvectl", &["revert", interface]).await?;
+                } else {
+                    let mut args = vec!["dns".to_string(), interface.clone()];
+                    args.extend(servers.iter().cloned());
+                    run_owned("resolvectl", &args).await?;
+                }
+                run("resolvectl", &["flush-caches"]).await?;
+            }
+            DnsRollback::ResolvConf { content } => {
+                write_resolv_conf(&self.resolv_conf, content).await?;
+            }
+        }
+
+        tokio::fs::remove_file(&self.rollback_path).await?;
+        println!("Reverted DNS settings via {:?}", rollback.backend());
+        Ok(rollback.backend())
+    }
+
+    pub async fn read_rollback(&self) -> Result<Option<DnsRollback>> {
+        match tokio::fs::read_to_string(&self.rollback_path).await {
+            Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn save_rollback(&self, rollback: &DnsRollback) -> Result<()> {
+        if let Some(parent) = self.rollback_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&self.rollback_path, serde_json::to_string_pretty(rollback)?).await?;
+        Ok(())
+    }
+}
+
+// The system can only be pointed at plain DNS, so the entry must name an IP over UDP or TCP
+pub fn system_dns_address(entry: &str) -> Result<IpAddr> {
+    let server = DnsServer::parse(entry)?;
+    if !matches!(server.transport, DnsTransport::Udp | DnsTransport::Tcp) || server.port != 53 {
+        return Err(anyhow::anyhow!("{} can't be set as system DNS, only plain DNS on port 53 can", entry));
+    }
+    server
+        .address
+        .parse()
+        .with_context(|| format!("{} is not an IP address", server.address))
+}
+
+// New resolv.conf with our servers, keeping the search domains and options of the old one
+fn render_resolv_conf(previous: &str, servers: &[IpAddr]) -> String {
+    let mut content = format!("{}\n", RESOLV_CONF_HEADER);
+    for ip in servers {
+        content.push_str(&format!("nameserver {}\n", ip));
+    }
+    for line in previous.lines() {
+        let keyword = line.split_whitespace().next().unwrap_or_default();
+        if matches!(keyword, "search" | "domain" | "options") {
+            content.push_str(line);
+            content.push('\n');
+        }
+    }
+    content
+}
+
+async fn write_resolv_conf(path: &Path, content: &str) -> Result<()> {
+    tokio::fs::write(path, content).await.with_context(|| {
+        format!("Could not write {} (changing it needs root privileges)", path.display())
+    })
+}
+
+fn join_ips(ips: &[IpAddr]) -> String {
+    ips.iter().map(|ip| ip.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+// Interface of the default route, e.g. `wlan0`
+async fn default_interface() -> Result<String> {
+    let routes = run("ip", &["-o", "route", "show", "default"]).await?;
+    routes
+        .split_whitespace()
+        .skip_while(|word| *word != "dev")
+        .nth(1)
+        .map(String::from)
+        .ok_or_else(|| anyhow::anyhow!("No default route found"))
+}
+
+// The active NetworkManager connection carrying the default route, and its device
+async fn active_connection() -> Result<(String, String)> {
+    let interface = default_interface().await.ok();
+    let active = run("nmcli", &["-t", "-f", "NAME,DEVICE", "connection", "show", "--active"]).await?;
+
+    let connections: Vec<(String, String)> = active
+        .lines()
+        .filter_map(|line| line.rsplit_once(':'))
+        .map(|(name, device)| (name.replace("\\:", ":"), device.to_string()))
+        .filter(|(_, device)| !device.is_empty() && device != "lo")
+        .collect();
+
+    connections
+        .iter()
+        .find(|(_, device)| Some(device) == interface.as_ref())
+        .or_else(|| connections.first())
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("No active NetworkManager connection"))
+}
+
+async fn network_manager_settings(connection: &str, device: &str) -> Result<DnsRollback> {
+    let fields = "ipv4.dns,ipv4.ignore-auto-dns,ipv6.dns,ipv6.ignore-auto-dns";
+    let output = run("nmcli", &["-g", fields, "connection", "show", connection]).await?;
+    // One line per field, lists comma separated
+    let values: Vec<String> = output.lines().map(|line| line.replace(',', " ")).collect();
+    let value = |index: usize, default: &str| {
+        values
+            .get(index)
+            .filter(|value| !value.is_empty())
+            .cloned()
+            .unwrap_or_else(|| default.to_string())
+    };
+    Ok(DnsRollback::NetworkManager {
+        connection: connection.to_string(),
+        device: device.to_string(),
+        ipv4_dns: value(0, ""),
+        ipv4_ignore_auto_dns: value(1, "no"),
+        ipv6_dns: value(2, ""),
+        ipv6_ignore_auto_dns: value(3, "no"),
+    })
+}
+
+// Servers configured on the link itself, e.g. `Link 3 (wlan0): 1.1.1.1 8.8.8.8`
+async fn resolved_link_servers(interface: &str) -> Result<Vec<String>> {
+    let output = run("resolvectl", &["dns", interface]).await?;
+    Ok(output
+        .split_once("):")
+        .map(|(_, servers)| servers.split_whitespace().map(String::from).collect())
+        .unwrap_or_default())
+}
+
+async fn run(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .with_context(|| format!("Could not run {}", program))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+async fn run_owned(program: &str, args: &[String]) -> Result<String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    run(program, &args).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tabs, a comment and no final newline, so any normalising would show
+    const ORIGINAL: &str = "# Generated by dhcpcd\nsearch lan\tcorp.example\nnameserver 192.168.1.1\noptions edns0 trust-ad";
+
+    fn scratch_system(dir: &tempfile::TempDir) -> SystemDns {
+        SystemDns {
+            resolv_conf: dir.path().join("resolv.conf"),
+            rollback_path: dir.path().join("bargozin").join("dns-rollback.json"),
+        }
+    }
+
+    fn ips(addresses: &[&str]) -> Vec<IpAddr> {
+        addresses.iter().map(|address| address.parse().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn revert_restores_resolv_conf_byte_for_byte() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = scratch_system(&dir);
+        std::fs::write(&system.resolv_conf, ORIGINAL).unwrap();
+
+        system.apply(DnsBackend::ResolvConf, &ips(&["1.1.1.1", "2606:4700:4700::1111"])).await.unwrap();
+        let applied = std::fs::read_to_string(&system.resolv_conf).unwrap();
+        assert_eq!(
+            applied,
+            format!(
+                "{}\nnameserver 1.1.1.1\nnameserver 2606:4700:4700::1111\nsearch lan\tcorp.example\noptions edns0 trust-ad\n",
+                RESOLV_CONF_HEADER
+            )
+        );
+
+        // Applying again keeps the settings from before the first change
+        system.apply(DnsBackend::ResolvConf, &ips(&["8.8.8.8"])).await.unwrap();
+        assert!(std::fs::read_to_string(&system.resolv_conf).unwrap().contains("nameserver 8.8.8.8\n"));
+
+        assert_eq!(system.revert().await.unwrap(), DnsBackend::ResolvConf);
+        assert_eq!(std::fs::read(&system.resolv_conf).unwrap(), ORIGINAL.as_bytes());
+        assert!(!system.rollback_path.exists());
+        assert!(system.revert().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_apply_leaves_nothing_to_revert() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut system = scratch_system(&dir);
+        system.resolv_conf = dir.path().join("missing").join("resolv.conf");
+
+        assert!(system.apply(DnsBackend::ResolvConf, &ips(&["1.1.1.1"])).await.is_err());
+        assert!(system.read_rollback().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn apply_through_another_backend_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = scratch_system(&dir);
+        std::fs::write(&system.resolv_conf, ORIGINAL).unwrap();
+        let resolved = DnsRollback::SystemdResolved {
+            interface: "wlan0".to_string(),
+            servers: vec![],
+        };
+        system.save_rollback(&resolved).await.unwrap();
+
+        assert!(system.apply(DnsBackend::ResolvConf, &ips(&["1.1.1.1"])).await.is_err());
+        assert_eq!(std::fs::read(&system.resolv_conf).unwrap(), ORIGINAL.as_bytes());
+    }
+
+    #[test]
+    fn only_plain_dns_can_be_applied() {
+        assert_eq!(system_dns_address("tcp://9.9.9.9").unwrap(), ips(&["9.9.9.9"])[0]);
+        assert!(system_dns_address("tls://1.1.1.1#cloudflare-dns.com").is_err());
+        assert!(system_dns_address("https://dns.google/dns-query").is_err());
+    }
+}
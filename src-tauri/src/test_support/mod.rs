@@ -0,0 +1,349 @@
+// Local stand-ins for DNS servers, so tests don't depend on the network or on
+// what a real resolver answers today
+use std::net::Ipv4Addr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use trust_dns_resolver::proto::op::{Message, MessageType};
+use trust_dns_resolver::proto::rr::rdata::A;
+use trust_dns_resolver::proto::rr::{RData, Record, RecordType};
+use crate::dns::{DnsTestResult, DnsTransport, HttpStatus, LatencyStats};
+
+/// A DNS test of `host` through `dns_server`, reachable in `response_time` ms
+/// or unreachable without one.
+pub fn dns_result(dns_server: &str, host: &str, response_time: Option<u64>) -> DnsTestResult {
+    DnsTestResult {
+        dns_server: dns_server.to_string(),
+        status: response_time.is_some(),
+        response_time,
+        error_message: None,
+        error_kind: None,
+        session_id: 0,
+        http_status: if response_time.is_some() { HttpStatus::Success } else { HttpStatus::NotTested },
+        test_url: Some(format!("https://{}/", host)),
+        transport: DnsTransport::Udp,
+        provider: None,
+        poisoning: None,
+        dnssec: None,
+        block_reason: None,
+        block_page: None,
+        resolved_records: vec![],
+        resolution_time: None,
+        http_response_time: response_time,
+        connected_address: None,
+        address_family: None,
+        address_attempts: vec![],
+        dns_latency: LatencyStats::default(),
+        http_latency: LatencyStats::default(),
+    }
+}
+
+/// The answer to `query` with `address` for A questions and nothing for the rest.
+pub fn answer(query: &Message, address: Ipv4Addr) -> Message {
+    let mut response = query.clone();
+    response.set_message_type(MessageType::Response);
+    if let Some(question) = query.queries().first() {
+        if question.query_type() == RecordType::A {
+            let record = Record::from_rdata(question.name().clone(), 60, RData::A(A::from(address)));
+            response.add_answer(record);
+        }
+    }
+    response
+}
+
+/// A plain DNS server on a local UDP port answering every A query with
+/// `address`, returned as a server entry.
+pub async fn udp_dns_server(address: Ipv4Addr) -> String {
+    scripted_dns_server(move |query| answer(query, address)).await
+}
+
+/// A plain DNS server on a local UDP port replying to each query with
+/// `respond(query)`, returned as a server entry.
+pub async fn scripted_dns_server<F>(respond: F) -> String
+where
+    F: Fn(&Message) -> Message + Send + 'static,
+{
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let entry = socket.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 1500];
+        loop {
+            let Ok((len, peer)) = socket.recv_from(&mut buffer).await else {
+                return;
+            };
+            let Ok(query) = Message::from_vec(&buffer[..len]) else {
+                continue;
+            };
+            let _ = socket.send_to(&respond(&query).to_vec().unwrap(), peer).await;
+        }
+    });
+    entry
+}
+
+/// Also serves the UDP server at `entry` over TCP on the same port, answering
+/// every A query with `address`, like a server clients can fall back to.
+pub async fn tcp_dns_server(entry: &str, address: Ipv4Addr) {
+    let listener = TcpListener::bind(entry).await.unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                // Every message is preceded by its length (RFC 1035 section 4.2.2)
+                loop {
+                    let Ok(len) = stream.read_u16().await else {
+                        return;
+                    };
+                    let mut query = vec![0u8; len as usize];
+                    if stream.read_exact(&mut query).await.is_err() {
+                        return;
+                    }
+                    let Ok(query) = Message::from_vec(&query) else {
+                        return;
+                    };
+                    let response = answer(&query, address).to_vec().unwrap();
+                    let _ = stream.write_u16(response.len() as u16).await;
+                    let _ = stream.write_all(&response).await;
+                }
+            });
+        }
+    });
+}
+
+/// A DoH endpoint over plain HTTP, which only test builds accept, answering
+/// every A query with `address`, returned as a server entry.
+pub async fn doh_server(address: Ipv4Addr) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let entry = format!("http://{}/dns-query", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let Some(body) = read_request_body(&mut stream).await else {
+                    return;
+                };
+                let Ok(query) = Message::from_vec(&body) else {
+                    return;
+                };
+                let body = answer(&query, address).to_vec().unwrap();
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/dns-message\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            });
+        }
+    });
+    entry
+}
+
+/// An HTTP server on a local port serving `body` at every path, with byte range
+/// support and an ETag that If-Range is checked against. Returns the port.
+pub async fn file_server(body: Vec<u8>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let body = std::sync::Arc::new(body);
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let body = body.clone();
+            tokio::spawn(async move {
+                let Some(head) = read_request_head(&mut stream).await else {
+                    return;
+                };
+                // A changed file gets a different ETag, and If-Range with an old one gets it whole
+                let etag = format!("\"{}\"", body.len());
+                let stale = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("if-range: "))
+                    .is_some_and(|validator| validator.trim() != etag);
+                let range = head
+                    .lines()
+                    .filter(|_| !stale)
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .and_then(|range| {
+                        let (start, end) = range.trim().split_once('-')?;
+                        let start: usize = start.parse().ok()?;
+                        let end = end.parse().unwrap_or(body.len().saturating_sub(1));
+                        Some((start, end.min(body.len().saturating_sub(1))))
+                    });
+                let (status, extra, slice) = match range {
+                    Some((start, _)) if start >= body.len() => (
+                        "416 Range Not Satisfiable",
+                        format!("content-range: bytes */{}\r\n", body.len()),
+                        &body[..0],
+                    ),
+                    Some((start, end)) => (
+                        "206 Partial Content",
+                        format!("content-range: bytes {}-{}/{}\r\n", start, end, body.len()),
+                        &body[start..=end],
+                    ),
+                    None => ("200 OK", String::new(), &body[..]),
+                };
+                let head = format!(
+                    "HTTP/1.1 {}\r\naccept-ranges: bytes\r\netag: {}\r\n{}content-length: {}\r\nconnection: close\r\n\r\n",
+                    status,
+                    etag,
+                    extra,
+                    slice.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(slice).await;
+            });
+        }
+    });
+    port
+}
+
+/// An HTTPS server on a local port with a self-signed certificate for
+/// `localhost`, answering every request with `status` and `body` the way a
+/// blocker intercepting the connection would. Returns the port.
+pub async fn self_signed_https_server(status: &'static str, body: &'static str) -> u16 {
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio_rustls::rustls::ServerConfig;
+
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![CertificateDer::from(certified.cert.der().to_vec())], key)
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                // Clients validating the certificate give up during the handshake
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    return;
+                };
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 4096];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(read) => request.extend_from_slice(&buffer[..read]),
+                    }
+                }
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-type: text/html\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+    port
+}
+
+/// An HTTP server on a local port answering each request with the raw bytes of
+/// `respond(head)`, given the lowercased request head. Returns the port.
+pub async fn scripted_http_server<F>(respond: F) -> u16
+where
+    F: Fn(&str) -> Vec<u8> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let respond = std::sync::Arc::new(respond);
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let respond = respond.clone();
+            tokio::spawn(async move {
+                let Some(head) = read_request_head(&mut stream).await else {
+                    return;
+                };
+                let _ = stream.write_all(&respond(&head)).await;
+            });
+        }
+    });
+    port
+}
+
+/// A response with `status`, the extra `headers` (each ending in CRLF) and `body`.
+pub fn http_response(status: &str, headers: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\n{}content-length: {}\r\nconnection: close\r\n\r\n",
+        status,
+        headers,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// Redirects `/start` to `/file` after `redirect_delay`, then answers `/file`
+/// with "body" after `delay`. Each response closes its connection.
+pub async fn redirecting_server(redirect_delay: std::time::Duration, delay: std::time::Duration) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let Some(head) = read_request_head(&mut stream).await else {
+                    return;
+                };
+                let response = if head.contains(" /start ") {
+                    tokio::time::sleep(redirect_delay).await;
+                    format!(
+                        "HTTP/1.1 302 Found\r\nlocation: http://127.0.0.1:{}/file\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                        port
+                    )
+                } else {
+                    tokio::time::sleep(delay).await;
+                    "HTTP/1.1 200 OK\r\ncontent-length: 4\r\nconnection: close\r\n\r\nbody".to_string()
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    port
+}
+
+// The lowercased head of one HTTP/1.1 request
+async fn read_request_head(stream: &mut tokio::net::TcpStream) -> Option<String> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 4096];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    Some(String::from_utf8_lossy(&request).to_ascii_lowercase())
+}
+
+// The body of one HTTP/1.1 request with a Content-Length
+async fn read_request_body(stream: &mut tokio::net::TcpStream) -> Option<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let read = stream.read(&mut buffer).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        request.extend_from_slice(&buffer[..read]);
+        let Some(head_end) = request.windows(4).position(|window| window == b"\r\n\r\n") else {
+            continue;
+        };
+        let head = String::from_utf8_lossy(&request[..head_end]).to_ascii_lowercase();
+        let length: usize = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))?
+            .trim()
+            .parse()
+            .ok()?;
+        let body_start = head_end + 4;
+        while request.len() < body_start + length {
+            let read = stream.read(&mut buffer).await.ok()?;
+            if read == 0 {
+                return None;
+            }
+            request.extend_from_slice(&buffer[..read]);
+        }
+        return Some(request[body_start..body_start + length].to_vec());
+    }
+}
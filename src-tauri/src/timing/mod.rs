@@ -0,0 +1,393 @@
+use std::fmt;
+use std::future::Future;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use reqwest::redirect::Policy;
+use reqwest::ClientBuilder;
+use serde::{Deserialize, Serialize};
+use tokio_rustls::rustls::client::{
+    ClientSessionMemoryCache, ClientSessionStore, Resumption, Tls12ClientSessionValue, Tls13ClientSessionValue,
+};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, NamedGroup, RootCertStore};
+use tower_layer::Layer;
+use tower_service::Service;
+
+// What reqwest's own redirect policy allows
+const MAX_REDIRECTS: usize = 10;
+
+/// Where the time of a download went, all of it measured on the connection
+/// the response came over. Speeds are worked out over `transfer_ms` alone, so
+/// a slow handshake doesn't make a fast mirror look slow.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PhaseTimings {
+    pub dns_ms: Option<u64>,
+    pub tcp_connect_ms: Option<u64>,
+    pub tls_handshake_ms: Option<u64>,
+    /// From sending the request that got the final response to its headers,
+    /// once the connection is up; earlier redirect hops are left out
+    pub ttfb_ms: Option<u64>,
+    /// From the response headers to the last byte read
+    pub transfer_ms: Option<u64>,
+}
+
+impl PhaseTimings {
+    /// `dns_ms` is for a connection whose address came from a lookup made
+    /// before the request; one the client looked up itself is timed by the clock.
+    pub fn measured(clock: &ConnectionClock, dns_ms: Option<u64>, transfer: Duration) -> Self {
+        let mut timings = clock.readings().timings();
+        timings.dns_ms = timings.dns_ms.or(dns_ms);
+        timings.transfer_ms = Some(transfer.as_millis() as u64);
+        timings
+    }
+}
+
+pub fn transfer_speed_mbps(downloaded_bytes: u64, transfer: Duration) -> f64 {
+    let seconds = transfer.as_secs_f64();
+    if seconds > 0.0 {
+        (downloaded_bytes as f64 * 8.0) / (seconds * 1_000_000.0)
+    } else {
+        0.0
+    }
+}
+
+/// Marks what happens on one HTTP client's connections as it happens. A new
+/// connection, e.g. to the host a redirect points at, starts over, so the
+/// readings are for the connection the final response came over.
+#[derive(Clone, Default)]
+pub struct ConnectionClock(Arc<Mutex<Readings>>);
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Readings {
+    connect_started: Option<Instant>,
+    resolved: Option<Instant>,
+    // None when the address was known before the client asked for it
+    lookup: Option<Duration>,
+    tls_started: Option<Instant>,
+    connected: Option<Instant>,
+    request_sent: Option<Instant>,
+    headers: Option<Instant>,
+}
+
+impl ConnectionClock {
+    fn mark(&self, update: impl FnOnce(&mut Readings)) {
+        update(&mut self.0.lock().unwrap());
+    }
+
+    fn readings(&self) -> Readings {
+        *self.0.lock().unwrap()
+    }
+
+    fn connect_started(&self) {
+        self.mark(|readings| {
+            *readings = Readings {
+                connect_started: Some(Instant::now()),
+                request_sent: readings.request_sent,
+                ..Readings::default()
+            }
+        });
+    }
+
+    pub fn resolved(&self, lookup: Option<Duration>) {
+        self.mark(|readings| {
+            readings.resolved = Some(Instant::now());
+            readings.lookup = lookup;
+        });
+    }
+
+    fn tls_started(&self) {
+        self.mark(|readings| readings.tls_started = Some(Instant::now()));
+    }
+
+    fn connected(&self) {
+        self.mark(|readings| readings.connected = Some(Instant::now()));
+    }
+
+    /// Called as each request, the first or a redirect hop, goes out.
+    pub fn request_sent(&self) {
+        self.mark(|readings| readings.request_sent = Some(Instant::now()));
+    }
+
+    pub fn headers_received(&self) {
+        self.mark(|readings| readings.headers = Some(Instant::now()));
+    }
+
+    /// Has reqwest report its connections, TLS handshakes and redirect hops
+    /// here. The TLS settings are the ones reqwest's rustls-tls would pick.
+    pub fn instrument(&self, builder: ClientBuilder) -> ClientBuilder {
+        let clock = self.clone();
+        let redirects = Policy::custom(move |attempt| {
+            if attempt.previous().len() > MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+            clock.request_sent();
+            attempt.follow()
+        });
+        let mut tls = self.tls_config();
+        tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+        builder
+            .connector_layer(ClockLayer(self.clone()))
+            .use_preconfigured_tls(tls)
+            .redirect(redirects)
+    }
+
+    /// ureq's resolver and TLS connector that report here, for an agent that
+    /// follows redirects itself so each hop can be marked.
+    pub fn instrument_ureq(&self, builder: ureq::AgentBuilder) -> ureq::AgentBuilder {
+        builder
+            .resolver(ClockedResolver(self.clone()))
+            .tls_connector(Arc::new(ClockedTls {
+                clock: self.clone(),
+                config: Arc::new(self.tls_config()),
+            }))
+            .redirects(0)
+    }
+
+    // Same roots as reqwest's rustls-tls. The session store is asked for a
+    // ticket as the handshake starts, which is what marks its start.
+    fn tls_config(&self) -> ClientConfig {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let mut config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.resumption = Resumption::store(Arc::new(HandshakeStart {
+            clock: self.clone(),
+            sessions: ClientSessionMemoryCache::new(32),
+        }));
+        config
+    }
+}
+
+impl Readings {
+    fn timings(&self) -> PhaseTimings {
+        let ms = |from: Option<Instant>, to: Option<Instant>| {
+            Some(to?.saturating_duration_since(from?).as_millis() as u64)
+        };
+        let tcp_start = self.resolved.or(self.connect_started);
+        // Without TLS the connection is ready when TCP is; ureq doesn't say when that is
+        let tcp_done = self.tls_started.or(self.connected);
+        // Opened for the request that got the final response, rather than
+        // kept from an earlier hop
+        let fresh = match (self.connect_started, self.request_sent) {
+            (Some(connect), Some(request)) => connect >= request,
+            _ => false,
+        };
+        let ttfb_from = if fresh { self.connected } else { self.request_sent };
+        PhaseTimings {
+            dns_ms: self.lookup.map(|lookup| lookup.as_millis() as u64),
+            tcp_connect_ms: ms(tcp_start, tcp_done),
+            tls_handshake_ms: ms(self.tls_started, self.connected),
+            ttfb_ms: ms(ttfb_from, self.headers),
+            transfer_ms: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ClockLayer(ConnectionClock);
+
+impl<S> Layer<S> for ClockLayer {
+    type Service = ClockedConnector<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ClockedConnector {
+            inner,
+            clock: self.0.clone(),
+        }
+    }
+}
+
+// Wraps reqwest's connector, which looks the host up, connects and does the
+// TLS handshake; it is only called for a new connection
+#[derive(Clone)]
+struct ClockedConnector<S> {
+    inner: S,
+    clock: ConnectionClock,
+}
+
+impl<S, R> Service<R> for ClockedConnector<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        self.clock.connect_started();
+        let clock = self.clock.clone();
+        let connecting = self.inner.call(request);
+        Box::pin(async move {
+            let connection = connecting.await?;
+            clock.connected();
+            Ok(connection)
+        })
+    }
+}
+
+struct HandshakeStart {
+    clock: ConnectionClock,
+    sessions: ClientSessionMemoryCache,
+}
+
+impl fmt::Debug for HandshakeStart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HandshakeStart")
+    }
+}
+
+impl ClientSessionStore for HandshakeStart {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        self.sessions.set_kx_hint(server_name, group)
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        self.sessions.kx_hint(server_name)
+    }
+
+    fn set_tls12_session(&self, server_name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.sessions.set_tls12_session(server_name, value)
+    }
+
+    fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        self.sessions.tls12_session(server_name)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.sessions.remove_tls12_session(server_name)
+    }
+
+    fn insert_tls13_ticket(&self, server_name: ServerName<'static>, value: Tls13ClientSessionValue) {
+        self.sessions.insert_tls13_ticket(server_name, value)
+    }
+
+    // The first thing rustls does for a new handshake, before the ClientHello
+    fn take_tls13_ticket(&self, server_name: &ServerName<'static>) -> Option<Tls13ClientSessionValue> {
+        self.clock.tls_started();
+        self.sessions.take_tls13_ticket(server_name)
+    }
+}
+
+// The system resolver, as ureq uses by default; it is asked once per new connection
+struct ClockedResolver(ConnectionClock);
+
+impl ureq::Resolver for ClockedResolver {
+    fn resolve(&self, netloc: &str) -> std::io::Result<Vec<SocketAddr>> {
+        self.0.connect_started();
+        let lookup_start = Instant::now();
+        let addresses = netloc.to_socket_addrs()?.collect();
+        self.0.resolved(Some(lookup_start.elapsed()));
+        Ok(addresses)
+    }
+}
+
+// ureq hands over the connected socket for the handshake and waits for it to finish
+struct ClockedTls {
+    clock: ConnectionClock,
+    config: Arc<ClientConfig>,
+}
+
+impl ureq::TlsConnector for ClockedTls {
+    fn connect(&self, dns_name: &str, io: Box<dyn ureq::ReadWrite>) -> Result<Box<dyn ureq::ReadWrite>, ureq::Error> {
+        self.clock.tls_started();
+        let stream = ureq::TlsConnector::connect(&self.config, dns_name, io)?;
+        self.clock.connected();
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::redirecting_server;
+
+    fn at(start: Instant, ms: u64) -> Option<Instant> {
+        Some(start + Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn phases_come_from_one_connection() {
+        let start = Instant::now();
+        let readings = Readings {
+            request_sent: at(start, 0),
+            connect_started: at(start, 0),
+            resolved: at(start, 20),
+            lookup: Some(Duration::from_millis(20)),
+            tls_started: at(start, 50),
+            connected: at(start, 110),
+            headers: at(start, 190),
+        };
+        let timings = readings.timings();
+        assert_eq!(timings.dns_ms, Some(20));
+        assert_eq!(timings.tcp_connect_ms, Some(30));
+        assert_eq!(timings.tls_handshake_ms, Some(60));
+        assert_eq!(timings.ttfb_ms, Some(80));
+    }
+
+    #[test]
+    fn a_reused_connection_counts_from_the_last_request() {
+        // The redirect at 300ms went back over the connection opened for the first request
+        let start = Instant::now();
+        let readings = Readings {
+            connect_started: at(start, 0),
+            resolved: at(start, 0),
+            connected: at(start, 40),
+            request_sent: at(start, 300),
+            headers: at(start, 350),
+            ..Readings::default()
+        };
+        let timings = readings.timings();
+        assert_eq!(timings.dns_ms, None);
+        assert_eq!(timings.tcp_connect_ms, Some(40));
+        assert_eq!(timings.tls_handshake_ms, None);
+        assert_eq!(timings.ttfb_ms, Some(50));
+    }
+
+    #[test]
+    fn nothing_is_made_up_for_unseen_phases() {
+        // A plain HTTP connection through ureq, which doesn't say when TCP is up
+        let start = Instant::now();
+        let readings = Readings {
+            request_sent: at(start, 0),
+            connect_started: at(start, 0),
+            resolved: at(start, 5),
+            lookup: Some(Duration::from_millis(5)),
+            headers: at(start, 100),
+            ..Readings::default()
+        };
+        let timings = readings.timings();
+        assert_eq!(timings.dns_ms, Some(5));
+        assert_eq!(timings.tcp_connect_ms, None);
+        assert_eq!(timings.ttfb_ms, None);
+    }
+
+    #[tokio::test]
+    async fn reqwest_ttfb_leaves_out_the_redirect_hop() {
+        let port = redirecting_server(Duration::from_millis(400), Duration::from_millis(100)).await;
+        let clock = ConnectionClock::default();
+        let client = clock.instrument(reqwest::Client::builder()).build().unwrap();
+
+        clock.request_sent();
+        let response = client.get(format!("http://127.0.0.1:{}/start", port)).send().await.unwrap();
+        clock.headers_received();
+        assert_eq!(response.text().await.unwrap(), "body");
+
+        let timings = PhaseTimings::measured(&clock, Some(7), Duration::from_millis(1));
+        // Addresses given in the URL aren't looked up
+        assert_eq!(timings.dns_ms, Some(7));
+        assert!(timings.tcp_connect_ms.is_some());
+        assert_eq!(timings.tls_handshake_ms, None);
+        let ttfb = timings.ttfb_ms.unwrap();
+        assert!((100..400).contains(&ttfb), "ttfb {}ms", ttfb);
+    }
+}
@@ -1,38 +1,58 @@
-use reqwest::Client;
+use reqwest::{Client, ClientBuilder};
 use std::fs;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::sync::Arc;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
-use trust_dns_resolver::TokioAsyncResolver;
-use trust_dns_resolver::config::*;
 use url::Url;
+use crate::timing::ConnectionClock;
+use crate::dns::{lookup_records, BlockPages, CdnNetworks, DnsConfig, DnsServer, LookupStrategy};
 use reqwest::dns::{Resolve, Resolving, Name, Addrs};
 
 pub const DNS_CONFIG_URL: &str =
     "https://raw.githubusercontent.com/403unlocker/403Unlocker-cli/refs/heads/main/config/dns.yml";
 
 pub fn dns_config_path() -> PathBuf {
-    let home = std::env::var("HOME").expect("HOME not set");
-    PathBuf::from(home).join(".config/403unlocker/dns.yml")
+    let home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
+    home.join(".config/403unlocker/dns.yml")
 }
 
-pub async fn read_dns_file(path: &PathBuf) -> anyhow::Result<Vec<String>> {
+pub async fn read_dns_file(path: &PathBuf) -> anyhow::Result<DnsConfig> {
     let content = fs::read_to_string(path)?;
-    let yaml: serde_yaml::Value = serde_yaml::from_str(&content)?;
-    let list = yaml["dnsServers"]
-        .as_sequence()
-        .ok_or_else(|| anyhow::anyhow!("dnsServers key missing"))?
-        .iter()
-        .filter_map(|v| v.as_str().map(String::from))
-        .collect();
-    Ok(list)
+    DnsConfig::from_yaml(&content)
+}
+
+pub fn block_pages_config_path() -> PathBuf {
+    let config_dir = dirs::config_dir().unwrap_or_else(|| PathBuf::from("."));
+    config_dir.join("bargozin").join("blockPages.yml")
+}
+
+pub fn cdn_networks_config_path() -> PathBuf {
+    let config_dir = dirs::config_dir().unwrap_or_else(|| PathBuf::from("."));
+    config_dir.join("bargozin").join("cdnNetworks.yml")
+}
+
+pub fn dns_export_dir() -> PathBuf {
+    let config_dir = dirs::config_dir().unwrap_or_else(|| PathBuf::from("."));
+    config_dir.join("bargozin").join("export")
+}
+
+pub async fn read_block_pages_file(path: &PathBuf) -> anyhow::Result<BlockPages> {
+    let content = fs::read_to_string(path)?;
+    BlockPages::from_yaml(&content)
+}
+
+pub async fn read_cdn_networks_file(path: &PathBuf) -> anyhow::Result<CdnNetworks> {
+    let content = fs::read_to_string(path)?;
+    CdnNetworks::from_yaml(&content)
 }
 
 pub async fn download_config_file(url: &str, path: &PathBuf) -> anyhow::Result<()> {
-    let response = reqwest::get(url).await?;
+    // Bounded so an unreachable GitHub doesn't stall the tests that fall back to built-in lists
+    let client = Client::builder().timeout(Duration::from_secs(10)).build()?;
+    let response = client.get(url).send().await?.error_for_status()?;
     let content = response.bytes().await?;
     let parent = path.parent().unwrap();
     tokio::fs::create_dir_all(parent).await?;
@@ -46,40 +66,81 @@ pub fn ensure_https(input: &str) -> Option<Url> {
     Url::parse(&format!("https://{}/", clean)).ok()
 }
 
-// Custom DNS resolver that uses a specific DNS server
-struct CustomDnsResolver {
-    resolver: TokioAsyncResolver,
+/// reqwest resolver that looks names up through one DNS server entry, over
+/// whichever transport it names, so any HTTP client can be pointed at that server.
+///
+/// Addresses are handed back with port 0, which tells the connector to use the
+/// URL's port: the explicit one (`example.com:8443`) or the scheme's default.
+#[derive(Clone)]
+pub struct CustomDnsResolver {
+    dns_server: String,
+    lookup_strategy: LookupStrategy,
+    // Host whose addresses were already looked up, answered without asking the server again
+    pinned: Option<(String, Vec<IpAddr>)>,
+    clock: Option<ConnectionClock>,
 }
 
 impl CustomDnsResolver {
-    fn new(dns_ip: &str) -> Option<Self> {
-        let resolver_config = ResolverConfig::from_parts(
-            None,
-            vec![],
-            vec![NameServerConfig {
-                socket_addr: format!("{}:53", dns_ip).parse::<SocketAddr>().ok()?,
-                protocol: Protocol::Udp,
-                tls_dns_name: None,
-                trust_negative_responses: true,
-                bind_addr: None,
-            }],
-        );
-
-        let resolver = TokioAsyncResolver::tokio(resolver_config, ResolverOpts::default());
-        Some(Self { resolver })
+    pub fn new(dns_server: &str, lookup_strategy: LookupStrategy) -> anyhow::Result<Self> {
+        DnsServer::parse(dns_server)?;
+        Ok(Self {
+            dns_server: dns_server.to_string(),
+            lookup_strategy,
+            pinned: None,
+            clock: None,
+        })
+    }
+
+    /// Answers `host` with `ips` only, e.g. to try one resolved address at a time.
+    /// Other hosts (redirect targets) are still looked up through the DNS server.
+    pub fn pin(mut self, host: &str, ips: Vec<IpAddr>) -> Self {
+        self.pinned = Some((host.to_string(), ips));
+        self
+    }
+
+    /// Times the lookups, connections and requests of the client it builds on `clock`.
+    pub fn timed(mut self, clock: ConnectionClock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    // Client builder that resolves every request through this resolver
+    pub fn client_builder(self) -> ClientBuilder {
+        let clock = self.clock.clone();
+        let builder = Client::builder()
+            .dns_resolver(Arc::new(self))
+            .user_agent("Mozilla/5.0 (compatible; Bargozin-DNS-Tester)");
+        match clock {
+            Some(clock) => clock.instrument(builder),
+            None => builder,
+        }
     }
 }
 
 impl Resolve for CustomDnsResolver {
     fn resolve(&self, name: Name) -> Resolving {
-        let resolver = self.resolver.clone();
+        let resolver = self.clone();
         Box::pin(async move {
-            let response = resolver.lookup_ip(name.as_str()).await
-                .map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send + Sync>)?;
-            
-            let addrs: Vec<SocketAddr> = response
-                .iter()
-                .map(|ip| SocketAddr::new(ip, 443))  // Always use 443 for HTTPS URLs
+            let lookup_start = Instant::now();
+            let (ips, lookup) = match &resolver.pinned {
+                Some((host, ips)) if host.eq_ignore_ascii_case(name.as_str()) => (ips.clone(), None),
+                _ => {
+                    let ips = lookup_records(name.as_str(), &resolver.dns_server, resolver.lookup_strategy)
+                        .await
+                        .map_err(|e| -> Box<dyn std::error::Error + Send + Sync> { e.into() })?
+                        .into_iter()
+                        .map(|record| record.ip)
+                        .collect();
+                    (ips, Some(lookup_start.elapsed()))
+                }
+            };
+            if let Some(clock) = &resolver.clock {
+                clock.resolved(lookup);
+            }
+
+            let addrs: Vec<SocketAddr> = ips
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, 0))  // Port comes from the URL
                 .collect();
             
             let addrs: Addrs = Box::new(addrs.into_iter());
@@ -89,13 +150,11 @@ impl Resolve for CustomDnsResolver {
 }
 
 pub async fn check_url_with_dns(url: &Url, dns_ip: &str) -> Option<(u16, String)> {
-    let resolver = CustomDnsResolver::new(dns_ip)?;
+    let resolver = CustomDnsResolver::new(dns_ip, LookupStrategy::default()).ok()?;
     
-    let client = Client::builder()
-        .dns_resolver(Arc::new(resolver))
-        .danger_accept_invalid_certs(true)
+    let client = resolver
+        .client_builder()
         .timeout(Duration::from_secs(10))
-        .user_agent("Mozilla/5.0 (compatible; 403Unlocker)")
         .build()
         .ok()?;
 